@@ -1,5 +1,6 @@
 use crate::unit;
 use crate::utils;
+use crate::weapondef;
 use serde::{Deserialize, Serialize};
 use typename::TypeName;
 use utils::Id;
@@ -27,6 +28,252 @@ pub struct BotDef {
     pub build_dist: f32,
     ///metal
     pub metal_cost: i32,
+    ///Builds the units of its production queue at its exit
+    #[serde(default)]
+    pub is_factory: bool,
+    ///energy
+    #[serde(default)]
+    pub energy_cost: i32,
+    //Economy
+    ///metal/frame
+    #[serde(default)]
+    pub metal_production: f32,
+    ///energy/frame
+    #[serde(default)]
+    pub energy_production: f32,
+    ///m, metal spots in this radius are extracted
+    #[serde(default)]
+    pub extraction_radius: f32,
+    ///metal/frame
+    #[serde(default)]
+    pub metal_upkeep: f32,
+    ///energy/frame
+    #[serde(default)]
+    pub energy_upkeep: f32,
+    ///metal, added to the owner storage once built
+    #[serde(default)]
+    pub metal_storage: f32,
+    ///energy, added to the owner storage once built
+    #[serde(default)]
+    pub energy_storage: f32,
+    ///m, terrain in this radius is seen by the team once built
+    #[serde(default = "default_sight_radius")]
+    pub sight_radius: f32,
+    ///m, enemy units in this radius are known by position once built
+    #[serde(default)]
+    pub radar_radius: f32,
+    ///m, units of the team in this radius are hidden from enemy radars once built
+    #[serde(default)]
+    pub jammer_radius: f32,
+    ///Never seen by radars
+    #[serde(default)]
+    pub stealth: bool,
+    ///Used by weapons to prioritize targets
+    #[serde(default)]
+    pub category: String,
+    ///Key of WeaponDef::armor_multipliers
+    #[serde(default)]
+    pub armor_class: String,
+    #[serde(default)]
+    pub move_class: MoveClass,
+    ///Flies when set, move_class then only tells where it can land
+    #[serde(default)]
+    pub flight: Option<FlightDef>,
+    ///Aimed by the Joint::AimWeapon0 part
+    #[serde(default)]
+    pub weapon0: Option<weapondef::WeaponDef>,
 
     pub part_tree: unit::PartTree,
 }
+
+impl BotDef {
+    pub fn is_air(&self) -> bool {
+        self.flight.is_some()
+    }
+
+    ///Botdefs the frame server can't simulate: energy is spent along the metal of a build, so
+    ///a unit costing energy must cost metal too
+    pub fn check(&self) -> Result<(), String> {
+        if self.metal_cost == 0 && self.energy_cost > 0 {
+            return Err(format!(
+                "energy_cost {} needs a metal_cost",
+                self.energy_cost
+            ));
+        }
+        match &self.weapon0 {
+            Some(weapon) => weapon.check(),
+            None => Ok(()),
+        }
+    }
+
+    ///Energy spent along each metal of the build, 0 for units costing no metal
+    pub fn energy_per_metal(&self) -> f64 {
+        if self.metal_cost == 0 {
+            0.0
+        } else {
+            self.energy_cost as f64 / self.metal_cost as f64
+        }
+    }
+}
+
+///Botdefs made before vision see as far as unit_example
+fn default_sight_radius() -> f32 {
+    30.0
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MoveType {
+    ///Drive on the ground, within the water depth limits
+    Ground,
+    ///Drive on the ground, at any water depth
+    Amphibious,
+    ///Float above the ground and over the water
+    Hover,
+    ///Float on the water, where it's deeper than min_water_depth
+    Naval,
+    ///Dive min_water_depth under the water level, where it's deeper than that
+    Submarine,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MoveClass {
+    pub move_type: MoveType,
+    ///dz/dx
+    pub max_slope: f32,
+    ///m under the water level
+    pub min_water_depth: f32,
+    ///m under the water level
+    pub max_water_depth: f32,
+}
+
+impl Default for MoveClass {
+    fn default() -> Self {
+        MoveClass {
+            move_type: MoveType::Ground,
+            max_slope: 1.0,
+            min_water_depth: 0.0,
+            max_water_depth: 4.0,
+        }
+    }
+}
+
+impl MoveClass {
+    ///Height of a unit of this class standing over ground_z
+    pub fn stand_z(&self, ground_z: f32, water_level: f32) -> f32 {
+        match self.move_type {
+            MoveType::Hover | MoveType::Naval => ground_z.max(water_level),
+            MoveType::Submarine => ground_z.max(water_level - self.min_water_depth),
+            _ => ground_z,
+        }
+    }
+
+    ///Can a unit of this class stand on a texel of this slope and height
+    pub fn can_cross(&self, slope: f32, ground_z: f32, water_level: f32) -> bool {
+        let water_depth = (water_level - ground_z).max(0.0);
+        match self.move_type {
+            MoveType::Ground => {
+                slope <= self.max_slope
+                    && water_depth >= self.min_water_depth
+                    && water_depth <= self.max_water_depth
+            }
+            MoveType::Amphibious => slope <= self.max_slope,
+            //Water surface is flat
+            MoveType::Hover => water_depth > 0.0 || slope <= self.max_slope,
+            MoveType::Naval | MoveType::Submarine => water_depth >= self.min_water_depth,
+        }
+    }
+
+    ///Speed multiplier on a texel of this slope, 1 on flat ground down to 0.5 at max_slope,
+    ///driving through the water halves it again at max_water_depth
+    pub fn speed_factor(&self, slope: f32, ground_z: f32, water_level: f32) -> f32 {
+        let water_depth = (water_level - ground_z).max(0.0);
+        match self.move_type {
+            MoveType::Hover if water_depth > 0.0 => 1.0,
+            MoveType::Naval | MoveType::Submarine => 1.0,
+            _ => {
+                (1.0 - 0.5 * (slope / self.max_slope).min(1.0))
+                    * (1.0 - 0.5 * (water_depth / self.max_water_depth.max(1.0)).min(1.0))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum FlightType {
+    ///Keeps flying forward at min_speed or more, circles while waiting
+    FixedWing,
+    ///Can stand still in the air
+    Gunship,
+}
+
+///Flight model of an air unit, accel, max_speed and turn rates come from the BotDef
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FlightDef {
+    pub flight_type: FlightType,
+    ///m above the ground or the water
+    pub cruise_altitude: f32,
+    ///m/frame, only for FixedWing
+    pub min_speed: f32,
+    ///m/frame, when taking off, landing and following the terrain
+    pub climb_rate: f32,
+    ///rad, roll when turning at max_turn_rate and max_speed
+    pub max_bank: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_botdef(name: &str) -> BotDef {
+        let path = format!("src/asset/botdef/{}.json", name);
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn units_costing_energy_cost_metal() {
+        assert!(crate::moddef::ModDef::load_on_disk("example").is_ok());
+
+        let mut botdef = load_botdef("unit_example");
+        botdef.metal_cost = 0;
+        assert!(botdef.check().is_err());
+        botdef.energy_cost = 0;
+        assert!(botdef.check().is_ok());
+        assert_eq!(botdef.energy_per_metal(), 0.0);
+    }
+
+    #[test]
+    fn move_classes_stand_and_cross_by_water_depth() {
+        let water_level = 40.0;
+        let class = |move_type, min_water_depth| MoveClass {
+            move_type,
+            min_water_depth,
+            ..MoveClass::default()
+        };
+
+        let ground = MoveClass::default();
+        assert_eq!(ground.stand_z(30.0, water_level), 30.0);
+        assert!(ground.can_cross(0.5, 38.0, water_level));
+        assert!(!ground.can_cross(0.5, 30.0, water_level), "too deep");
+        assert!(!ground.can_cross(1.5, 50.0, water_level), "too steep");
+
+        let amphibious = class(MoveType::Amphibious, 0.0);
+        assert!(amphibious.can_cross(0.5, 0.0, water_level));
+        assert!(!amphibious.can_cross(1.5, 50.0, water_level));
+
+        let hover = class(MoveType::Hover, 0.0);
+        assert_eq!(hover.stand_z(30.0, water_level), water_level);
+        assert_eq!(hover.stand_z(50.0, water_level), 50.0);
+        assert!(hover.can_cross(2.0, 30.0, water_level), "the water is flat");
+        assert!(!hover.can_cross(2.0, 50.0, water_level));
+
+        let naval = class(MoveType::Naval, 2.0);
+        assert_eq!(naval.stand_z(30.0, water_level), water_level);
+        assert!(naval.can_cross(2.0, 30.0, water_level));
+        assert!(!naval.can_cross(0.0, 39.0, water_level), "too shallow");
+
+        let submarine = class(MoveType::Submarine, 5.0);
+        assert_eq!(submarine.stand_z(30.0, water_level), 35.0);
+        assert_eq!(submarine.stand_z(38.0, water_level), 38.0);
+        assert!(!submarine.can_cross(0.0, 50.0, water_level));
+    }
+}
@@ -1,7 +1,8 @@
 extern crate nalgebra as na;
 use super::heightmap_editor;
 use crate::botdef;
-use crate::frame::Frame;
+use crate::frame::{EventRejection, Frame, MessageTarget, PlayerMessage, PlayerMessageKind};
+use crate::frame_server::vision;
 use crate::mobile;
 use crate::utils;
 use fnv::{FnvHashMap, FnvHashSet};
@@ -22,6 +23,22 @@ pub struct Explosion {
     pub seed: f32,
 }
 
+///Seconds a ping stays on the map
+pub const PING_DURATION: f32 = 5.0;
+///Seconds a drawing takes to fade out
+pub const DRAWING_DURATION: f32 = 10.0;
+///Chat messages kept for the chat window
+const CHAT_HISTORY: usize = 100;
+
+///Ping or drawing of a player, shown until death_sec
+#[derive(Clone, Debug)]
+pub struct MapMark {
+    pub player_id: Id<Player>,
+    pub kind: PlayerMessageKind,
+    pub born_sec: f32,
+    pub death_sec: f32,
+}
+
 pub struct State {
     pub position: Point3<f32>,
     pub dir: Vector3<f32>,
@@ -46,6 +63,11 @@ pub struct State {
     pub explosions: Vec<Explosion>,
     pub kinematic_projectiles_cache: FnvHashMap<Id<KinematicProjectile>, KinematicProjectile>,
     pub kinematic_projectiles: Vec<Point3<f32>>,
+    pub map_marks: Vec<MapMark>,
+    ///Last known state of enemy buildings out of sight
+    pub ghosts: FnvHashMap<Id<KBot>, KBot>,
+    ///Enemies out of sight known by radar, around their position
+    pub radar_blips: Vec<Point3<f32>>,
 
     pub selected: FnvHashSet<Id<KBot>>,
     pub under_mouse: Option<Id<KBot>>,
@@ -55,6 +77,19 @@ pub struct State {
     pub last_frame: Instant,
 
     pub my_player_id: Option<Id<Player>>,
+    ///Last of my events dropped by the server, with the frame number it was reported at
+    pub last_rejection: Option<(i32, EventRejection)>,
+    ///Tick shown by the replay slider while it is dragged
+    pub replay_seek_tick: Option<i32>,
+
+    ///Chat messages seen, oldest first
+    pub chat: Vec<PlayerMessage>,
+    ///Text being typed in the chat window
+    pub chat_input: imgui::ImString,
+    ///Who sees our chat messages, pings and drawings
+    pub message_target: MessageTarget,
+    ///Points of the drawing in progress, sent when the mouse is released
+    pub drawing: Vec<Vector3<f32>>,
 
     pub players: FnvHashMap<Id<Player>, Player>,
 
@@ -86,6 +121,9 @@ impl State {
             kinematic_projectiles: Vec::new(),
 
             explosions: Vec::new(),
+            map_marks: Vec::new(),
+            ghosts: FnvHashMap::default(),
+            radar_blips: Vec::new(),
             server_sec: 0.0,
 
             selected: FnvHashSet::default(),
@@ -94,6 +132,12 @@ impl State {
 
             players: FnvHashMap::default(),
             my_player_id: None,
+            last_rejection: None,
+            replay_seek_tick: None,
+            chat: Vec::new(),
+            chat_input: imgui::ImString::with_capacity(crate::frame::MAX_CHAT_LEN),
+            message_target: MessageTarget::All,
+            drawing: Vec::new(),
 
             start_time: Instant::now(),
             last_frame: Instant::now(),
@@ -102,7 +146,8 @@ impl State {
         }
     }
 
-    pub fn handle_new_frame(&mut self, frame: Frame) {
+    pub fn handle_new_frame(&mut self, mut frame: Frame) {
+        self.hide_out_of_sight(&mut frame);
         let time_between = self.frame_zero_time_received.elapsed();
         log::trace!("receive: NewFrame after {:?}", time_between);
         self.frame_zero_time_received = Instant::now();
@@ -122,6 +167,35 @@ impl State {
             });
         }
 
+        for rejection in self.frame_zero.event_rejections.iter() {
+            if Some(rejection.player_id) == self.my_player_id {
+                log::warn!("{} rejected: {:?}", rejection.event, rejection.reason);
+                self.last_rejection = Some((self.frame_zero.number, rejection.clone()));
+            }
+        }
+
+        for message in self.frame_zero.player_messages.iter() {
+            if message.target == MessageTarget::Allies && !self.is_ally(message.player_id) {
+                continue;
+            }
+            let duration = match &message.kind {
+                PlayerMessageKind::Chat(_) => {
+                    self.chat.push(message.clone());
+                    let extra = self.chat.len().saturating_sub(CHAT_HISTORY);
+                    self.chat.drain(..extra);
+                    continue;
+                }
+                PlayerMessageKind::Ping(_) => PING_DURATION,
+                PlayerMessageKind::Drawing(_) => DRAWING_DURATION,
+            };
+            self.map_marks.push(MapMark {
+                player_id: message.player_id,
+                kind: message.kind.clone(),
+                born_sec: sec,
+                death_sec: sec + duration,
+            });
+        }
+
         for proj_b in self.frame_zero.kinematic_projectiles_birth.iter() {
             self.kinematic_projectiles_cache
                 .insert(proj_b.id, proj_b.clone());
@@ -141,10 +215,53 @@ impl State {
             .frame_zero
             .kbots
             .values()
+            .chain(self.ghosts.values())
             .map(|kbot| (kbot.clone(), ClientKbot::new(kbot.position)))
             .collect();
     }
 
+    ///Strips the enemies out of sight of my team, buildings seen before stay as ghosts and
+    ///units on radar as blips
+    fn hide_out_of_sight(&mut self, frame: &mut Frame) {
+        self.radar_blips.clear();
+        let team = match self.my_player_id.and_then(|id| frame.players.get(&id)) {
+            Some(me) => me.team,
+            //Spectators and replays see everything
+            None => {
+                self.ghosts.clear();
+                return;
+            }
+        };
+        for kbot in vision::strip_hidden(frame, team) {
+            if vision::on_radar(frame, team, &kbot) {
+                self.radar_blips
+                    .push(vision::radar_blip(&kbot, frame.number));
+            }
+        }
+        //Units the server kept out of our frame
+        self.radar_blips.extend(frame.radar_blips.iter().cloned());
+        //Gone out of sight, whether stripped here or by the server
+        for kbot in self.frame_zero.kbots.values().filter(|kbot| {
+            kbot.team != team
+                && !frame.kbots.contains_key(&kbot.id)
+                && !frame.kbots_dead.contains(&kbot.id)
+        }) {
+            let building = frame
+                .bot_defs
+                .get(&kbot.botdef_id)
+                .map(|botdef| botdef.max_speed == 0.0)
+                .unwrap_or(false);
+            if building {
+                self.ghosts.insert(kbot.id, kbot.clone());
+            }
+        }
+        //Seeing the place again shows what is really there
+        if let Some(grid) = frame.vision.get(&team) {
+            self.ghosts
+                .retain(|_, ghost| !grid.is_visible(ghost.position.x, ghost.position.y));
+        }
+    }
+
     pub fn interpolate(&mut self, threadpool: &rayon::ThreadPool, view_proj: &Matrix4<f32>) {
         let elapsed = self.frame_zero_time_received.elapsed().as_secs_f64();
         //elapsed normalize between 0 and 1 if frame arrives every 100ms (0.1s)
@@ -188,6 +305,8 @@ impl State {
             .copied()
             .filter(|e| e.death_sec > self.server_sec)
             .collect();
+        let server_sec = self.server_sec;
+        self.map_marks.retain(|mark| mark.death_sec > server_sec);
 
         let mut kbots = std::mem::replace(&mut self.kbots, Vec::new());
 
@@ -248,6 +367,34 @@ impl State {
             .unwrap_or(None)
     }
 
+    ///Closest wreck covering position
+    pub fn wreck_under(&self, position: Vector3<f32>) -> Option<Id<Wreck>> {
+        let frame = &self.frame_zero;
+        frame
+            .wrecks
+            .values()
+            .filter_map(|wreck| {
+                let radius = frame.bot_defs.get(&wreck.botdef_id)?.radius;
+                let distance = (wreck.position.coords - position).magnitude();
+                if distance <= radius + 1.0 {
+                    Some((wreck.id, distance))
+                } else {
+                    None
+                }
+            })
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(id, _)| id)
+    }
+
+    ///Same team as my player, spectators have no allies
+    fn is_ally(&self, player_id: Id<Player>) -> bool {
+        let team = |id| self.frame_zero.players.get(&id).map(|player| player.team);
+        match self.my_player_id.and_then(team) {
+            Some(my_team) => team(player_id) == Some(my_team),
+            None => false,
+        }
+    }
+
     pub fn near(&self) -> f32 {
         if self.position_smooth.z > 515.0
             || self.position_smooth.coords.x < -500.0
@@ -2,6 +2,7 @@ use imgui::*;
 use na::Vector3;
 use std::collections::HashSet;
 
+use crate::frame_delta;
 use crate::gpu_obj::heightmap_gpu;
 use noise::{NoiseFn, Seedable};
 
@@ -83,6 +84,8 @@ impl State {
                     .build(&ui, min_z);
                 imgui::Slider::new(im_str!("max height"), 0.0..=heightmap_gpu::MAX_Z)
                     .build(&ui, max_z);
+                imgui::Slider::new(im_str!("water level"), 0.0..=heightmap_gpu::MAX_Z)
+                    .build(&ui, &mut heightmap_gpu.phy.data.water_level);
 
                 if ui.small_button(im_str!("Save")) {
                     Self::save(heightmap_gpu, "src/asset/map/map_example");
@@ -345,6 +348,20 @@ impl State {
         let mut decoder = png::Decoder::new(File::open(&height_path).unwrap());
         decoder.set_transformations(png::Transformations::IDENTITY);
         let (info, mut reader) = decoder.read_info().unwrap();
+        //Positions of bigger maps don't fit in frame deltas
+        if info.width as usize > frame_delta::MAX_MAP_SIZE
+            || info.height as usize > frame_delta::MAX_MAP_SIZE
+        {
+            log::error!(
+                "{} is {}x{}, maps are {}x{} at most",
+                height_path,
+                info.width,
+                info.height,
+                frame_delta::MAX_MAP_SIZE,
+                frame_delta::MAX_MAP_SIZE
+            );
+            return;
+        }
         log::debug!("info: {:?}", info.width);
         log::debug!("height: {:?}", info.height);
         log::debug!("bit depth: {:?}", info.bit_depth);
@@ -368,5 +385,13 @@ impl State {
             heightmap_gpu.phy.width as u32,
             heightmap_gpu.phy.height as u32,
         );
+
+        let json_path = format!("{}/data.json", path);
+        if let Ok(file) = File::open(&json_path) {
+            match serde_json::from_reader(std::io::BufReader::new(file)) {
+                Ok(data) => heightmap_gpu.phy.data = data,
+                Err(e) => log::warn!("Could not parse {}: {}", json_path, e),
+            }
+        }
     }
 }
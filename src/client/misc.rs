@@ -3,13 +3,53 @@ use crate::*;
 use unit_part_gpu::*;
 
 use super::uitool::UiTool;
+
+///Parts of unfinished units are hatched with a green of this intensity, black for wrecks
+const WRECK_CON_COMPLETED: f32 = 0.0;
+///Team value the part shader draws without team color
+const NEUTRAL_TEAM: f32 = 99.0;
+
+///0.0 is move line, 1.0 is build line, 2.0 is repair line
+fn command_line_type(command: &mobile::Command) -> f32 {
+    match command {
+        mobile::Command::Build(_) => 1.0,
+        mobile::Command::Repair(_)
+        | mobile::Command::Guard(_)
+        | mobile::Command::Reclaim { .. } => 2.0,
+        _ => 0.0,
+    }
+}
+
+///Segments from the end of the current command through each queued command
+fn queued_path(
+    kbot: &mobile::KBot,
+    start: Point3<f32>,
+    kbots: &fnv::FnvHashMap<utils::Id<mobile::KBot>, mobile::KBot>,
+) -> Vec<(Point3<f32>, Point3<f32>, f32)> {
+    let mut from = kbot.current_command.position(kbots).unwrap_or(start);
+    let mut segments = Vec::new();
+    for command in kbot.command_queue.iter() {
+        if let Some(to) = command.position(kbots) {
+            segments.push((from, to, command_line_type(command)));
+            from = to;
+        }
+    }
+    segments
+}
+
 impl App {
     pub fn clear_gpu_instance_and_game_state(&mut self) {
         self.game_state.players.clear();
         self.game_state.my_player_id = None;
+        self.game_state.last_rejection = None;
         self.game_state.kbots.clear();
         self.game_state.selected.clear();
         self.game_state.explosions.clear();
+        self.game_state.map_marks.clear();
+        self.game_state.ghosts.clear();
+        self.game_state.radar_blips.clear();
+        self.game_state.chat.clear();
+        self.game_state.drawing.clear();
         self.game_state.kinematic_projectiles_cache.clear();
         // self.unit_editor.root.children.clear();
 
@@ -198,6 +238,33 @@ impl App {
                     }
                 }
 
+                //Wreck
+                for wreck in self.game_state.frame_zero.wrecks.values() {
+                    let distance = (wreck.position.coords - self.game_state.position_smooth.coords)
+                        .magnitude();
+                    if distance >= unit_icon_distance {
+                        continue;
+                    }
+                    if let Some(botdef) = self.game_state.frame_zero.bot_defs.get(&wreck.botdef_id)
+                    {
+                        let mat = utils::face_towards_dir(
+                            &wreck.position.coords,
+                            &wreck.dir.normalize(),
+                            &wreck.up,
+                        );
+                        Self::visit_part_tree(
+                            &botdef.part_tree,
+                            &mat,
+                            &mut self.unit_part_gpu,
+                            0.0,
+                            NEUTRAL_TEAM,
+                            WRECK_CON_COMPLETED,
+                            wreck.dir,
+                            0.0,
+                        );
+                    }
+                }
+
                 for model_gpu in self.unit_part_gpu.states.iter_mut() {
                     match model_gpu {
                         ModelGpuState::Ready(model_gpu) => {
@@ -263,7 +330,62 @@ impl App {
 
             //Arrow
             self.vertex_attr_buffer_f32.clear();
-            for arrow in self.game_state.frame_zero.arrows.iter() {
+            let see_all_order = self
+                .input_state
+                .key_pressed
+                .contains(&winit::event::VirtualKeyCode::LShift);
+            let mut queue_arrows = Vec::new();
+            for (kbot, client_kbot) in self.game_state.kbots.iter() {
+                if see_all_order || self.game_state.selected.contains(&kbot.id) {
+                    for (from, to, type_) in queued_path(
+                        kbot,
+                        client_kbot.position,
+                        &self.game_state.frame_zero.kbots,
+                    ) {
+                        let color = match type_ as i32 {
+                            1 => [0.0, 0.5, 1.0, 1.0],
+                            2 => [0.0, 1.0, 1.0, 1.0],
+                            _ => [0.0, 1.0, 0.5, 1.0],
+                        };
+                        //Short arrow at the end of the segment
+                        let dir = to.coords - from.coords;
+                        let length = dir.magnitude().min(1.5);
+                        if length > 0.0 {
+                            let start = to - dir.normalize() * length;
+                            queue_arrows.push(mobile::Arrow::new(start, to, color));
+                        }
+                    }
+                }
+            }
+            //Pings bounce above the pinged position, in the color of their player
+            for mark in self.game_state.map_marks.iter() {
+                if let frame::PlayerMessageKind::Ping(position) = mark.kind {
+                    let color = self
+                        .game_state
+                        .players
+                        .get(&mark.player_id)
+                        .map(|player| player.color)
+                        .unwrap_or([1.0; 3]);
+                    let bounce = ((self.game_state.server_sec - mark.born_sec) * 6.0)
+                        .sin()
+                        .abs()
+                        * 2.0;
+                    let end = Point3::from(position) + Vector3::new(0.0, 0.0, 0.5 + bounce);
+                    let start = end + Vector3::new(0.0, 1.0, 6.0);
+                    queue_arrows.push(mobile::Arrow::new(
+                        start,
+                        end,
+                        [color[0], color[1], color[2], 1.0],
+                    ));
+                }
+            }
+            for arrow in self
+                .game_state
+                .frame_zero
+                .arrows
+                .iter()
+                .chain(queue_arrows.iter())
+            {
                 let mat = Matrix4::face_towards(
                     &arrow.position,
                     &arrow.end,
@@ -388,6 +510,16 @@ impl App {
                 let team = if is_selected { -1.0 } else { kbot.team as f32 };
                 self.vertex_attr_buffer_f32.push(team);
             }
+            //Radar blips at any distance, white as their team isn't known
+            for blip in self.game_state.radar_blips.iter() {
+                let r = view_proj * blip.to_homogeneous();
+                if r.z > 0.0 && r.x.abs() < r.w && r.y.abs() < r.w {
+                    self.vertex_attr_buffer_f32.push(r.x / r.w);
+                    self.vertex_attr_buffer_f32.push(r.y / r.w);
+                    self.vertex_attr_buffer_f32.push(10.0);
+                    self.vertex_attr_buffer_f32.push(2.0);
+                }
+            }
             self.unit_icon
                 .update_instance(&self.vertex_attr_buffer_f32[..], &self.gpu.device);
 
@@ -418,7 +550,7 @@ impl App {
                 };
 
                 match self.game_state.uitool {
-                    UiTool::Repair => {
+                    UiTool::Repair | UiTool::Reclaim => {
                         index_to_vector(0, 0);
                     }
                     UiTool::Spawn(..) => {
@@ -448,6 +580,39 @@ impl App {
             self.vertex_attr_buffer_f32.clear();
             {
                 let mut count = 0;
+                fn add_line(
+                    view_proj: &Matrix4<f32>,
+                    buffer: &mut Vec<f32>,
+                    start: &Point3<f32>,
+                    end: &Point3<f32>,
+                    type_: f32,
+                    count: &mut i32,
+                ) {
+                    let min = view_proj * start.to_homogeneous();
+                    let max = view_proj * end.to_homogeneous();
+                    if (min.z > 0.0
+                        && min.x > -min.w
+                        && min.x < min.w
+                        && min.y > -min.w
+                        && min.y < min.w)
+                        || (max.z > 0.0
+                            && max.x > -max.w
+                            && max.x < max.w
+                            && max.y > -max.w
+                            && max.y < max.w)
+                    {
+                        *count += 1;
+                        buffer.push(min.x / min.w);
+                        buffer.push(min.y / min.w);
+                        buffer.push(max.x / max.w);
+                        buffer.push(max.y / max.w);
+                        //0.0 is move line
+                        //1.0 is build line
+                        buffer.push(type_);
+                        buffer.push(0.0);
+                    }
+                }
+
                 let see_all_order = self
                     .input_state
                     .key_pressed
@@ -455,39 +620,6 @@ impl App {
                 {
                     for (kbot, client_kbot) in self.game_state.kbots.iter() {
                         if see_all_order || self.game_state.selected.contains(&kbot.id) {
-                            fn add_line(
-                                view_proj: &Matrix4<f32>,
-                                buffer: &mut Vec<f32>,
-                                start: &Point3<f32>,
-                                end: &Point3<f32>,
-                                type_: f32,
-                                count: &mut i32,
-                            ) {
-                                let min = view_proj * start.to_homogeneous();
-                                let max = view_proj * end.to_homogeneous();
-                                if (min.z > 0.0
-                                    && min.x > -min.w
-                                    && min.x < min.w
-                                    && min.y > -min.w
-                                    && min.y < min.w)
-                                    || (max.z > 0.0
-                                        && max.x > -max.w
-                                        && max.x < max.w
-                                        && max.y > -max.w
-                                        && max.y < max.w)
-                                {
-                                    *count += 1;
-                                    buffer.push(min.x / min.w);
-                                    buffer.push(min.y / min.w);
-                                    buffer.push(max.x / max.w);
-                                    buffer.push(max.y / max.w);
-                                    //0.0 is move line
-                                    //1.0 is build line
-                                    buffer.push(type_);
-                                    buffer.push(0.0);
-                                }
-                            }
-
                             if let Some(target) = kbot.move_target {
                                 add_line(
                                     view_proj,
@@ -498,7 +630,7 @@ impl App {
                                     &mut count,
                                 );
                             }
-                            match kbot.current_command {
+                            match &kbot.current_command {
                                 mobile::Command::Build(id_builded) => {
                                     for target_kbot in
                                         self.game_state.frame_zero.kbots.get(&id_builded)
@@ -527,14 +659,128 @@ impl App {
                                         );
                                     }
                                 }
+                                mobile::Command::Attack(id_attacked) => {
+                                    if let Some(target_kbot) =
+                                        self.game_state.frame_zero.kbots.get(&id_attacked)
+                                    {
+                                        add_line(
+                                            view_proj,
+                                            &mut self.vertex_attr_buffer_f32,
+                                            &client_kbot.position,
+                                            &target_kbot.position,
+                                            0.0,
+                                            &mut count,
+                                        );
+                                    }
+                                }
+                                mobile::Command::AttackGround(position) => {
+                                    add_line(
+                                        view_proj,
+                                        &mut self.vertex_attr_buffer_f32,
+                                        &client_kbot.position,
+                                        position,
+                                        0.0,
+                                        &mut count,
+                                    );
+                                }
+                                mobile::Command::Guard(id_guarded) => {
+                                    if let Some(target_kbot) =
+                                        self.game_state.frame_zero.kbots.get(&id_guarded)
+                                    {
+                                        add_line(
+                                            view_proj,
+                                            &mut self.vertex_attr_buffer_f32,
+                                            &client_kbot.position,
+                                            &target_kbot.position,
+                                            2.0,
+                                            &mut count,
+                                        );
+                                    }
+                                }
+                                mobile::Command::Patrol { waypoints, .. } => {
+                                    for (i, waypoint) in waypoints.iter().enumerate() {
+                                        let next = &waypoints[(i + 1) % waypoints.len()];
+                                        add_line(
+                                            view_proj,
+                                            &mut self.vertex_attr_buffer_f32,
+                                            waypoint,
+                                            next,
+                                            0.0,
+                                            &mut count,
+                                        );
+                                    }
+                                }
                                 _ => {}
                             }
+
+                            for (from, to, type_) in queued_path(
+                                kbot,
+                                client_kbot.position,
+                                &self.game_state.frame_zero.kbots,
+                            ) {
+                                add_line(
+                                    view_proj,
+                                    &mut self.vertex_attr_buffer_f32,
+                                    &from,
+                                    &to,
+                                    type_,
+                                    &mut count,
+                                );
+                            }
+
+                            if let Some(rally_point) = kbot
+                                .factory
+                                .as_ref()
+                                .and_then(|factory| factory.rally_point)
+                            {
+                                add_line(
+                                    view_proj,
+                                    &mut self.vertex_attr_buffer_f32,
+                                    &client_kbot.position,
+                                    &rally_point,
+                                    0.0,
+                                    &mut count,
+                                );
+                            }
                         }
                     }
                     for i in (0..self.vertex_attr_buffer_f32.len()).step_by(6) {
                         self.vertex_attr_buffer_f32[i + 5] = count as f32;
                     }
                 }
+
+                //The line shader dims lines of a high count, drawings fade out through it
+                let mut drawings: Vec<(&[Vector3<f32>], f32)> = self
+                    .game_state
+                    .map_marks
+                    .iter()
+                    .filter_map(|mark| match &mark.kind {
+                        frame::PlayerMessageKind::Drawing(points) => {
+                            let age = (self.game_state.server_sec - mark.born_sec)
+                                / (mark.death_sec - mark.born_sec);
+                            Some((&points[..], (1.0 - age).max(0.05)))
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                drawings.push((&self.game_state.drawing[..], 1.0));
+                for (points, alpha) in drawings {
+                    let start = self.vertex_attr_buffer_f32.len();
+                    let mut drawing_count = 0;
+                    for segment in points.windows(2) {
+                        add_line(
+                            view_proj,
+                            &mut self.vertex_attr_buffer_f32,
+                            &Point3::from(segment[0]),
+                            &Point3::from(segment[1]),
+                            1.0,
+                            &mut drawing_count,
+                        );
+                    }
+                    for i in (start..self.vertex_attr_buffer_f32.len()).step_by(6) {
+                        self.vertex_attr_buffer_f32[i + 5] = 50.0 + alpha.powi(-4);
+                    }
+                }
             }
             self.line_gpu
                 .update_instance(&self.vertex_attr_buffer_f32[..], &self.gpu.device);
@@ -40,10 +40,12 @@ use winit::event::WindowEvent;
 
 pub struct StartClient {
     pub bind: String,
+    pub name: String,
 }
 
 pub struct StartServer {
     pub bind: String,
+    pub name: String,
 }
 
 pub enum FromClient {
@@ -52,6 +54,16 @@ pub enum FromClient {
     StartClient(StartClient),
     DisconnectServer,
     DisconnectClient,
+    LobbyChange(lobby::LobbyChange),
+    ///Asked by the host once everyone is ready
+    StartGame,
+    StartReplay {
+        path: String,
+    },
+    StopReplay,
+    ReplayPause(bool),
+    ReplaySeek(usize),
+    ReplaySpeed(f64),
 }
 
 struct ImguiWrap {
@@ -72,6 +84,7 @@ pub enum MainMode {
     UnitEditor,
     MapEditor,
     MultiplayerLobby,
+    Replay,
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -138,6 +151,10 @@ pub struct App {
     loop_helper: LoopHelper,
     profiler: frame::ProfilerMap,
     global_info: Option<manager::GlobalInfo>,
+    ///Multiplayer game being set up, with the player we are in it
+    lobby: Option<(lobby::Lobby, utils::Id<frame::Player>)>,
+    ///Name shown to the other players
+    player_name: ImString,
     threadpool: rayon::ThreadPool,
 
     frame_count: i32,
@@ -264,7 +281,7 @@ impl App {
         filler.extend_from_slice(mx_ref);
         filler.extend_from_slice(mx_normal_ref);
         filler.extend_from_slice(&[
-            0.0_f32, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+            0.0_f32, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
         ]);
         // mat4 cor_proj_view;
         // mat4 u_View;
@@ -277,11 +294,12 @@ impl App {
         // float radius
         // float pen_strength
         // vec2 mapSize;
+        // float water_level;
 
         let ub_camera_mat = gpu
             .device
             .create_buffer_mapped(
-                16 * 4 + 12,
+                16 * 4 + 13,
                 wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
             )
             .fill_from_slice(&filler[..]);
@@ -294,7 +312,7 @@ impl App {
                     binding: 0,
                     resource: wgpu::BindingResource::Buffer {
                         buffer: &ub_camera_mat,
-                        range: 0..(4 * 16 + 12) * 4,
+                        range: 0..(4 * 16 + 13) * 4,
                     },
                 },
                 wgpu::Binding {
@@ -598,6 +616,8 @@ impl App {
             loop_helper: LoopHelper::builder().build_with_target_rate(144.0),
             profiler: frame::ProfilerMap::new(),
             global_info: None,
+            lobby: None,
+            player_name: ImString::new("Player"),
             threadpool: rayon::ThreadPoolBuilder::new()
                 // .num_threads(8)
                 .build()
@@ -763,8 +783,11 @@ impl App {
                         },
                     ..
                 } => {
-                    self.input_state.key_pressed.insert(vkc.clone());
-                    self.input_state.key_trigger.insert(vkc.clone());
+                    //Typing in the chat doesn't move the camera
+                    if !self.imgui_wrap.imgui.io().want_capture_keyboard {
+                        self.input_state.key_pressed.insert(vkc.clone());
+                        self.input_state.key_trigger.insert(vkc.clone());
+                    }
                 }
                 WindowEvent::KeyboardInput {
                     input:
@@ -1047,6 +1070,20 @@ impl App {
                         self.game_state.handle_new_frame(frame);
                     }
                     ToClient::GlobalInfo(global_info) => self.global_info = Some(global_info),
+                    ToClient::Lobby {
+                        lobby,
+                        my_player_id,
+                    } => {
+                        //We were elected host when the previous one left
+                        let host = lobby
+                            .slots
+                            .iter()
+                            .any(|slot| slot.host && slot.player_id == my_player_id);
+                        if host && self.net_mode == NetMode::Client {
+                            self.net_mode = NetMode::Server;
+                        }
+                        self.lobby = Some((lobby, my_player_id));
+                    }
                 }
             }
         }
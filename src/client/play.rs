@@ -10,76 +10,95 @@ use na::{Isometry3, Matrix4, Point3, Vector2, Vector3, Vector4};
 use std::time::{Duration, Instant};
 use utils::*;
 
+///World distance between two points of a drawing
+const DRAWING_STEP: f32 = 2.0;
+
 impl App {
     pub fn init_play(&mut self) {
+        self.clear_gpu_instance_and_game_state();
         match self.net_mode {
             NetMode::Offline | NetMode::Server => {
-                self.clear_gpu_instance_and_game_state();
-                self.game_state.position =
-                    Point3::new(300.0, 100.0, self.heightmap_gpu.phy.z(300.0, 100.0) + 50.0);
-                self.game_state.dir = Vector3::new(0.0, 0.3, -1.0);
+                //Offline, a lobby of two on the map already loaded
+                let (lobby, my_player_id) = match (&self.lobby, self.net_mode) {
+                    (Some((lobby, my_player_id)), NetMode::Server) => {
+                        if lobby::has_heightmap(&lobby.map) {
+                            heightmap_editor::State::load(&mut self.heightmap_gpu, &lobby.map);
+                        }
+                        (lobby.clone(), *my_player_id)
+                    }
+                    _ => {
+                        let mut lobby = lobby::Lobby::new(
+                            lobby::DEFAULT_MAP.to_owned(),
+                            moddef::DEFAULT_MOD.to_owned(),
+                        );
+                        let my_player_id =
+                            lobby.join("Me".to_owned(), true, String::new()).unwrap();
+                        lobby.join("Ennemy".to_owned(), false, String::new());
+                        (lobby, my_player_id)
+                    }
+                };
+                self.look_at_start(&lobby, my_player_id);
 
-                let mut player_me = Player::new();
-                let mut player_ennemy = Player::new();
-                player_ennemy.team = 1;
+                let (moddef, bot_defs) = match moddef::ModDef::load_on_disk(&lobby.mod_name) {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        log::error!("Could not load mod {}: {}", lobby.mod_name, e);
+                        return;
+                    }
+                };
+                let start_unit = match moddef.units_id.first() {
+                    Some(id) => &bot_defs[id],
+                    None => {
+                        log::error!("Mod {} has no unit", lobby.mod_name);
+                        return;
+                    }
+                };
 
                 let mut kbots = FnvHashMap::default();
+                let players: Vec<_> = lobby.slots.iter().filter(|slot| !slot.spectator).collect();
+                let (map_width, map_height) =
+                    (self.heightmap_gpu.phy.width, self.heightmap_gpu.phy.height);
+                for (index, slot) in players.iter().enumerate() {
+                    let mut player = Player::new();
+                    player.id = slot.player_id;
+                    player.name = slot.name.clone();
+                    player.team = slot.team;
+                    player.color = slot.color;
 
-                let tank_example =
-                    Self::load_botdef_on_disk("src/asset/botdef/unit_example.json").unwrap();
-                let building_example =
-                    Self::load_botdef_on_disk("src/asset/botdef/building_example.json").unwrap();
-
-                for i in (100..300).step_by(4) {
-                    for j in (100..500).step_by(4) {
-                        let m = mobile::KBot::new(
-                            Point3::new(i as f32, j as f32, 100.0),
-                            &tank_example,
-                            player_me.id,
-                        );
-                        player_me.kbots.insert(m.id);
-                        kbots.insert(m.id, m);
-                    }
-                }
-
-                for i in (320..520).step_by(4) {
-                    for j in (100..500).step_by(4) {
-                        let mut m = mobile::KBot::new(
-                            Point3::new(i as f32, j as f32, 100.0),
-                            &tank_example,
-                            player_ennemy.id,
-                        );
-                        m.team = 1;
-                        player_ennemy.kbots.insert(m.id);
-                        kbots.insert(m.id, m);
+                    let (xs, ys) = start_area(index, players.len(), map_width, map_height);
+                    for i in xs.step_by(4) {
+                        for j in ys.clone().step_by(4) {
+                            let mut m = mobile::KBot::new(
+                                Point3::new(i as f32, j as f32, 100.0),
+                                start_unit,
+                                player.id,
+                            );
+                            m.team = player.team;
+                            player.kbots.insert(m.id);
+                            kbots.insert(m.id, m);
+                        }
                     }
+                    self.game_state.players.insert(player.id, player);
                 }
 
                 log::info!("Starting a game with {} bots", kbots.len());
 
-                self.game_state.my_player_id = Some(player_me.id);
-                self.game_state.players.insert(player_me.id, player_me);
-                self.game_state
-                    .players
-                    .insert(player_ennemy.id, player_ennemy);
-
-                let mut bot_defs = FnvHashMap::default();
-                bot_defs.insert(tank_example.id, tank_example);
-                bot_defs.insert(building_example.id, building_example);
-
-                let mut moddef = crate::moddef::ModDef {
-                    units_id: bot_defs.keys().copied().collect(),
-                    con_map: FnvHashMap::default(),
-                };
+                self.game_state.my_player_id = Some(my_player_id);
 
                 let replacer = FrameEventFromPlayer::ReplaceFrame(frame::Frame {
                     number: 0,
                     players: self.game_state.players.clone(),
                     moddef,
                     kbots,
+                    wrecks: FnvHashMap::default(),
+                    vision: FnvHashMap::default(),
                     kbots_dead: HashSet::default(),
                     kinematic_projectiles_dead: Vec::new(),
                     kinematic_projectiles_birth: Vec::new(),
+                    event_rejections: Vec::new(),
+                    radar_blips: Vec::new(),
+                    player_messages: Vec::new(),
+                    id_generator: utils::IdGenerator::new(),
                     kinematic_projectiles: self.game_state.kinematic_projectiles_cache.clone(),
                     arrows: Vec::new(),
                     explosions: Vec::new(),
@@ -93,22 +112,40 @@ impl App {
             }
 
             NetMode::Client => {
-                self.clear_gpu_instance_and_game_state();
-                self.game_state.position =
-                    Point3::new(300.0, 100.0, self.heightmap_gpu.phy.z(300.0, 100.0) + 50.0);
-                self.game_state.dir = Vector3::new(0.0, 0.3, -1.0);
-                self.game_state.my_player_id = self
-                    .game_state
-                    .frame_zero
-                    .players
-                    .values()
-                    .filter(|p| p.team == 1)
-                    .map(|p| p.id.clone())
-                    .next();
+                //The server assigned us a player when we joined its lobby
+                if let Some((lobby, my_player_id)) = &self.lobby {
+                    if lobby::has_heightmap(&lobby.map) {
+                        heightmap_editor::State::load(&mut self.heightmap_gpu, &lobby.map);
+                    }
+                    self.game_state.my_player_id = Some(*my_player_id);
+                    let (lobby, my_player_id) = (lobby.clone(), *my_player_id);
+                    self.look_at_start(&lobby, my_player_id);
+                }
             }
         }
     }
 
+    ///Camera over the start area of the player, or over the middle of the map for spectators
+    fn look_at_start(&mut self, lobby: &lobby::Lobby, my_player_id: utils::Id<Player>) {
+        let phy = &self.heightmap_gpu.phy;
+        let players: Vec<_> = lobby.slots.iter().filter(|slot| !slot.spectator).collect();
+        let (x, y) = match players
+            .iter()
+            .position(|slot| slot.player_id == my_player_id)
+        {
+            Some(index) => {
+                let (xs, ys) = start_area(index, players.len(), phy.width, phy.height);
+                (
+                    (xs.start + xs.end) as f32 / 2.0,
+                    (ys.start + ys.end) as f32 / 2.0,
+                )
+            }
+            None => (phy.width as f32 / 2.0, phy.height as f32 / 2.0),
+        };
+        self.game_state.position = Point3::new(x, y, phy.safe_z(x, y) + 50.0);
+        self.game_state.dir = Vector3::new(0.0, 0.3, -1.0);
+    }
+
     pub fn handle_play(
         &mut self,
         delta_sim_sec: f32,
@@ -120,6 +157,8 @@ impl App {
             self.game_state.interpolate(&self.threadpool, &view_proj);
         });
 
+        let marking = self.handle_map_marks();
+
         // Selection on screen
         let selection_screen = time(|| {
             //Under_cursor
@@ -174,7 +213,7 @@ impl App {
                 }
             }
 
-            if let Some(me) = self.game_state.my_player() {
+            if let (Some(me), false) = (self.game_state.my_player(), marking) {
                 //Selection square
                 if let input_state::Drag::End { x0, y0, x1, y1 } = self.input_state.drag {
                     let start_sel = std::time::Instant::now();
@@ -224,4 +263,66 @@ impl App {
         self.profiler.mix("interp", interp_duration, 20);
         self.profiler.mix("selection_screen", selection_screen, 20);
     }
+
+    ///Control click pings the map, control drag draws on it, true while doing so
+    fn handle_map_marks(&mut self) -> bool {
+        let id = match self.game_state.my_player() {
+            Some(me) => me.id,
+            None => return false,
+        };
+        let marking = self
+            .input_state
+            .key_pressed
+            .contains(&winit::event::VirtualKeyCode::LControl);
+        let left = winit::event::MouseButton::Left;
+
+        if let (true, true, Some(position)) = (
+            marking,
+            self.input_state.mouse_pressed.contains(&left),
+            self.game_state.mouse_world_pos,
+        ) {
+            let drawing = &mut self.game_state.drawing;
+            let far_enough = drawing
+                .last()
+                .map(|last| (last - position).magnitude() > DRAWING_STEP)
+                .unwrap_or(true);
+            if far_enough && drawing.len() < frame::MAX_DRAWING_POINTS {
+                drawing.push(position);
+            }
+        }
+
+        let drawing = !self.game_state.drawing.is_empty();
+        if drawing && self.input_state.mouse_release.contains(&left) {
+            let target = self.game_state.message_target;
+            let mut points = std::mem::take(&mut self.game_state.drawing);
+            let event = if points.len() > 1 {
+                FrameEventFromPlayer::MapDrawing { id, target, points }
+            } else {
+                FrameEventFromPlayer::MapPing {
+                    id,
+                    target,
+                    position: points.remove(0),
+                }
+            };
+            let _ = self
+                .sender_from_client_to_manager
+                .try_send(client::FromClient::PlayerInput(event));
+        }
+        marking || drawing
+    }
+}
+
+///m, start area of the index-th of count players, side by side across the map
+fn start_area(
+    index: usize,
+    count: usize,
+    map_width: usize,
+    map_height: usize,
+) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+    let column = map_width / count.max(1);
+    let width = (column * 4 / 5).min(200);
+    let height = (map_height * 4 / 5).min(400);
+    let x0 = column * index + (column - width) / 2;
+    let y0 = (map_height - height) / 2;
+    (x0..x0 + width, y0..y0 + height)
 }
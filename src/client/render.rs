@@ -61,6 +61,11 @@ impl App {
                     from,
                     to: MainMode::Home,
                 } => {
+                    if from == MainMode::Replay {
+                        let _ = self
+                            .sender_from_client_to_manager
+                            .try_send(client::FromClient::StopReplay);
+                    }
                     self.clear_gpu_instance_and_game_state();
                     self.game_state.position = Point3::new(200.0, 100.0, 50.0);
                     self.game_state.dir = Vector3::new(0.0, 0.3, -1.0);
@@ -78,6 +83,14 @@ impl App {
                     from,
                     to: MainMode::MultiplayerLobby,
                 } => {}
+                RenderEvent::ChangeMode {
+                    from,
+                    to: MainMode::Replay,
+                } => {
+                    self.clear_gpu_instance_and_game_state();
+                    self.game_state.position = Point3::new(300.0, 100.0, 150.0);
+                    self.game_state.dir = Vector3::new(0.0, 0.3, -1.0);
+                }
                 RenderEvent::ChangeMode {
                     from,
                     to: MainMode::UnitEditor,
@@ -105,7 +118,7 @@ impl App {
             }
         }
 
-        let mode_with_camera = [MainMode::Play, MainMode::MapEditor];
+        let mode_with_camera = [MainMode::Play, MainMode::MapEditor, MainMode::Replay];
         // Camera Movements
         if mode_with_camera.contains(&self.main_menu) {
             self.rts_camera(sim_sec);
@@ -157,7 +170,7 @@ impl App {
             &self.game_state.position_smooth,
             &self.game_state.dir_smooth,
         );
-        if self.main_menu == MainMode::Play {
+        if self.main_menu == MainMode::Play || self.main_menu == MainMode::Replay {
             self.handle_play(sim_sec, &mut encoder_render, &view_proj);
         }
 
@@ -208,12 +221,13 @@ impl App {
             self.game_state.heightmap_editor.pen_strength as f32,
             self.heightmap_gpu.phy.width as f32,
             self.heightmap_gpu.phy.height as f32,
+            self.heightmap_gpu.phy.data.water_level,
         ]);
 
         let ub_camera_temp = self
             .gpu
             .device
-            .create_buffer_mapped(4 * 16 + 12, wgpu::BufferUsage::COPY_SRC)
+            .create_buffer_mapped(4 * 16 + 13, wgpu::BufferUsage::COPY_SRC)
             .fill_from_slice(&filler[..]);
 
         encoder_render.copy_buffer_to_buffer(
@@ -221,7 +235,7 @@ impl App {
             0,
             &self.ub_camera_mat,
             0,
-            (4 * 16 + 12) * 4,
+            (4 * 16 + 13) * 4,
         );
 
         self.heightmap_gpu.update_uniform(
@@ -244,6 +258,11 @@ impl App {
         {
             let main_menu = &mut self.main_menu;
 
+            //First, to stay under every window
+            if *main_menu == MainMode::Play {
+                draw_fog(&ui, &self.game_state, &view_proj, &self.heightmap_gpu.phy);
+            }
+
             {
                 //Stat
                 let fps_before = self.game_state.fps.clone();
@@ -370,6 +389,9 @@ impl App {
                                 if ui.button(im_str!("Multiplayer"), [200.0_f32, 100.0]) {
                                     next_mode = MainMode::MultiplayerLobby;
                                 }
+                                if ui.button(im_str!("Replay"), [200.0_f32, 100.0]) {
+                                    next_mode = MainMode::Replay;
+                                }
                                 if ui.button(im_str!("Exit"), [200.0_f32, 100.0]) {
                                     exit = true;
                                 }
@@ -387,19 +409,53 @@ impl App {
                         }
                     }
                     MainMode::Play => {
+                        let mut share_overflow = None;
                         if let Some(me) = self.game_state.my_player() {
                             let resource_window = imgui::Window::new(im_str!("Resources"));
                             resource_window
-                                .size([400.0, 120.0], imgui::Condition::FirstUseEver)
+                                .size([400.0, 220.0], imgui::Condition::FirstUseEver)
                                 .position([500.0, 3.0], imgui::Condition::FirstUseEver)
                                 .collapsed(false, imgui::Condition::FirstUseEver)
                                 .build(&ui, || {
-                                    ui.text(im_str!("metal: {:.1}", me.metal));
-                                    ProgressBar::new((me.metal / 500.0) as f32).build(&ui);
-                                    ui.text(im_str!("energy: {:.1}", me.energy));
-                                    ProgressBar::new((me.energy / 500.0) as f32).build(&ui);
+                                    Self::resource_ui(
+                                        &ui,
+                                        "metal",
+                                        me.metal,
+                                        me.metal_storage,
+                                        &me.metal_stats,
+                                    );
+                                    ui.separator();
+                                    Self::resource_ui(
+                                        &ui,
+                                        "energy",
+                                        me.energy,
+                                        me.energy_storage,
+                                        &me.energy_stats,
+                                    );
+                                    ui.separator();
+                                    let mut share = me.share_overflow;
+                                    if ui
+                                        .checkbox(im_str!("share overflow with allies"), &mut share)
+                                    {
+                                        share_overflow = Some(share);
+                                    }
                                 });
                         }
+                        if let (Some(share), Some(id)) =
+                            (share_overflow, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::ShareOverflow { id, share },
+                                ),
+                            );
+                        }
+
+                        if let Some(chat) = draw_chat(&ui, &mut self.game_state) {
+                            let _ = self
+                                .sender_from_client_to_manager
+                                .try_send(client::FromClient::PlayerInput(chat));
+                        }
 
                         let mut uitool = self.game_state.uitool;
                         let can_be_built: Vec<_> = self
@@ -412,6 +468,56 @@ impl App {
                             .collect();
 
                         let can_be_built = &self.game_state.frame_zero.bot_defs;
+                        let mut stop = false;
+
+                        let frame_zero = &self.game_state.frame_zero;
+                        let factories: Vec<&mobile::KBot> = self
+                            .game_state
+                            .selected
+                            .iter()
+                            .filter_map(|id| frame_zero.kbots.get(id))
+                            .filter(|kbot| kbot.factory.is_some())
+                            .collect();
+                        //What selected builders can build, and selected factories produce
+                        let mut buildable = Vec::new();
+                        let mut producible = Vec::new();
+                        for kbot in self
+                            .game_state
+                            .selected
+                            .iter()
+                            .filter_map(|id| frame_zero.kbots.get(id))
+                        {
+                            let list = if kbot.factory.is_some() {
+                                &mut producible
+                            } else {
+                                &mut buildable
+                            };
+                            if let Some(con) = frame_zero.moddef.con_map.get(&kbot.botdef_id) {
+                                for botdef_id in con {
+                                    if !list.contains(botdef_id) {
+                                        list.push(*botdef_id);
+                                    }
+                                }
+                            }
+                        }
+                        let last_rejection = self
+                            .game_state
+                            .last_rejection
+                            .as_ref()
+                            .filter(|(number, _)| frame_zero.number - number < 30)
+                            .map(|(_, rejection)| rejection);
+                        //Shift changes units 5 at a time
+                        let production_step = if self
+                            .input_state
+                            .key_pressed
+                            .contains(&winit::event::VirtualKeyCode::LShift)
+                        {
+                            5
+                        } else {
+                            1
+                        };
+                        let mut production_order = None;
+                        let mut factory_repeat = None;
 
                         let command_window = imgui::Window::new(im_str!("Command"));
                         command_window
@@ -419,18 +525,124 @@ impl App {
                             .position([3.0, 415.0], imgui::Condition::FirstUseEver)
                             .collapsed(false, imgui::Condition::FirstUseEver)
                             .build(&ui, || {
-                                for can_be_built in can_be_built {
-                                    let txt = format!("Build {:?}", can_be_built.1.file_path);
-                                    if ui.small_button(&im_str!("{}", txt)) {
-                                        uitool = UiTool::Spawn(can_be_built.0.clone());
+                                for botdef_id in buildable.iter() {
+                                    if let Some(botdef) = can_be_built.get(botdef_id) {
+                                        let txt = format!("Build {:?}", botdef.file_path);
+                                        if ui.small_button(&im_str!("{}", txt)) {
+                                            uitool = UiTool::Spawn(*botdef_id);
+                                        }
                                     }
                                 }
 
                                 if ui.small_button(im_str!("Repair")) {
                                     uitool = UiTool::Repair;
                                 }
+                                if ui.small_button(im_str!("Reclaim")) {
+                                    uitool = UiTool::Reclaim;
+                                }
+                                if ui.small_button(im_str!("Attack")) {
+                                    uitool = UiTool::Attack;
+                                }
+                                if ui.small_button(im_str!("Guard")) {
+                                    uitool = UiTool::Guard;
+                                }
+                                if ui.small_button(im_str!("Patrol")) {
+                                    uitool = UiTool::Patrol;
+                                }
+                                if ui.small_button(im_str!("Stop")) {
+                                    stop = true;
+                                }
+
+                                if !factories.is_empty() {
+                                    ui.separator();
+                                    for botdef_id in producible.iter() {
+                                        let queued: usize = factories
+                                            .iter()
+                                            .filter_map(|kbot| kbot.factory.as_ref())
+                                            .map(|factory| {
+                                                factory
+                                                    .queue
+                                                    .iter()
+                                                    .filter(|e| *e == botdef_id)
+                                                    .count()
+                                            })
+                                            .sum();
+                                        let name = can_be_built
+                                            .get(botdef_id)
+                                            .map(|botdef| botdef.file_path.clone())
+                                            .unwrap_or_default();
+                                        //Left click adds to the queue, right click removes
+                                        if ui.small_button(&im_str!(
+                                            "Produce {:?} ({})",
+                                            name,
+                                            queued
+                                        )) {
+                                            production_order = Some((*botdef_id, production_step));
+                                        }
+                                        if ui.is_item_clicked(MouseButton::Right) {
+                                            production_order = Some((*botdef_id, -production_step));
+                                        }
+                                    }
+                                    let mut repeat = factories
+                                        .iter()
+                                        .filter_map(|kbot| kbot.factory.as_ref())
+                                        .all(|factory| factory.repeat);
+                                    if ui.checkbox(im_str!("repeat"), &mut repeat) {
+                                        factory_repeat = Some(repeat);
+                                    }
+                                }
+
+                                if let Some(rejection) = last_rejection {
+                                    ui.separator();
+                                    ui.text_colored(
+                                        [1.0, 0.3, 0.3, 1.0],
+                                        format!(
+                                            "{} rejected: {:?}",
+                                            rejection.event, rejection.reason
+                                        ),
+                                    );
+                                }
                             });
 
+                        if let (Some((botdef_id, amount)), Some(id)) =
+                            (production_order, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::ProductionOrder {
+                                        id,
+                                        selected: self.game_state.selected.clone(),
+                                        botdef_id,
+                                        amount,
+                                    },
+                                ),
+                            );
+                        }
+
+                        if let (Some(repeat), Some(id)) =
+                            (factory_repeat, self.game_state.my_player_id)
+                        {
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(
+                                    FrameEventFromPlayer::FactoryRepeat {
+                                        id,
+                                        selected: self.game_state.selected.clone(),
+                                        repeat,
+                                    },
+                                ),
+                            );
+                        }
+
+                        if let (true, Some(id)) = (stop, self.game_state.my_player_id) {
+                            uitool = UiTool::None;
+                            let _ = self.sender_from_client_to_manager.try_send(
+                                client::FromClient::PlayerInput(FrameEventFromPlayer::StopOrder {
+                                    id,
+                                    selected: self.game_state.selected.clone(),
+                                }),
+                            );
+                        }
+
                         if self.game_state.uitool != uitool {
                             log::debug!(
                                 "UiTool state from {:?} to {:?}",
@@ -452,9 +664,82 @@ impl App {
                             &mut self.unit_part_gpu,
                         );
                     }
+                    MainMode::Replay => {
+                        let mut to_send = Vec::new();
+                        let replay_info = self.global_info.and_then(|info| info.replay);
+                        let replay_seek_tick = &mut self.game_state.replay_seek_tick;
+                        let replay_window = imgui::Window::new(im_str!("Replay"));
+                        replay_window
+                            .size([400.0, 200.0], imgui::Condition::FirstUseEver)
+                            .position([500.0, 3.0], imgui::Condition::FirstUseEver)
+                            .collapsed(false, imgui::Condition::FirstUseEver)
+                            .build(&ui, || match replay_info {
+                                None => {
+                                    let mut paths: Vec<String> =
+                                        std::fs::read_dir(replay::REPLAY_DIR)
+                                            .map(|dir| {
+                                                dir.filter_map(|entry| entry.ok())
+                                                    .map(|entry| {
+                                                        entry.path().to_string_lossy().into_owned()
+                                                    })
+                                                    .collect()
+                                            })
+                                            .unwrap_or_default();
+                                    paths.sort();
+                                    if paths.is_empty() {
+                                        ui.text(im_str!("No replay in {}", replay::REPLAY_DIR));
+                                    }
+                                    //Most recent first
+                                    for path in paths.iter().rev() {
+                                        if ui.small_button(&im_str!("{}", path)) {
+                                            to_send.push(client::FromClient::StartReplay {
+                                                path: path.clone(),
+                                            });
+                                        }
+                                    }
+                                }
+                                Some(info) => {
+                                    ui.text(im_str!("tick {} / {}", info.tick, info.tick_count));
+                                    let pause_label = if info.paused { "Play" } else { "Pause" };
+                                    if ui.small_button(&im_str!("{}", pause_label)) {
+                                        to_send.push(client::FromClient::ReplayPause(!info.paused));
+                                    }
+                                    for speed in &[0.5, 1.0, 2.0, 4.0, 8.0] {
+                                        ui.same_line(0.0);
+                                        if ui.radio_button_bool(
+                                            &im_str!("x{}", speed),
+                                            info.speed == *speed,
+                                        ) {
+                                            to_send.push(client::FromClient::ReplaySpeed(*speed));
+                                        }
+                                    }
+
+                                    //Seek once the slider is released, seeking can take a while
+                                    let mut tick = replay_seek_tick.unwrap_or(info.tick as i32);
+                                    imgui::Slider::new(
+                                        im_str!("seek"),
+                                        0..=(info.tick_count as i32 - 1).max(0),
+                                    )
+                                    .build(&ui, &mut tick);
+                                    if ui.is_item_active() {
+                                        *replay_seek_tick = Some(tick);
+                                    } else if ui.is_item_deactivated_after_edit() {
+                                        *replay_seek_tick = None;
+                                        to_send.push(client::FromClient::ReplaySeek(tick as usize));
+                                    }
+
+                                    if ui.small_button(im_str!("Close")) {
+                                        to_send.push(client::FromClient::StopReplay);
+                                    }
+                                }
+                            });
+                        for e in to_send {
+                            let _ = self.sender_from_client_to_manager.try_send(e);
+                        }
+                    }
                     MainMode::MultiplayerLobby => {
-                        let w = 216.0;
-                        let h = 324.0;
+                        let w = 420.0;
+                        let h = 480.0;
                         let home_window = imgui::Window::new(im_str!("Multiplayer Lobby"));
 
                         let mut create_server = false;
@@ -462,6 +747,9 @@ impl App {
                         let mut disconnect_server = false;
                         let mut disconnect_client = false;
                         let mut next_mode = MainMode::MultiplayerLobby;
+                        let mut to_send = Vec::new();
+                        let lobby = &self.lobby;
+                        let player_name = &mut self.player_name;
                         if let Some(global_info) = self.global_info {
                             home_window
                                 .size([w, h], imgui::Condition::Always)
@@ -480,32 +768,53 @@ impl App {
                                     if global_info.net_server.is_none()
                                         && global_info.net_client.is_none()
                                     {
+                                        ui.input_text(im_str!("name"), player_name)
+                                            .resize_buffer(true)
+                                            .build();
                                         create_server =
                                             ui.button(im_str!("Start server"), [200.0_f32, 100.0]);
                                         create_client =
                                             ui.button(im_str!("Start client"), [200.0_f32, 100.0]);
-                                    } else if global_info.net_server.is_some() {
-                                        disconnect_server = ui.button(
-                                            im_str!("Disconnect server"),
-                                            [200.0_f32, 100.0],
-                                        );
-                                    } else if global_info.net_client.is_some() {
-                                        disconnect_client = ui.button(
-                                            im_str!("Disconnect client"),
-                                            [200.0_f32, 100.0],
-                                        );
+                                    } else {
+                                        match lobby {
+                                            None => ui.text(im_str!("Joining...")),
+                                            Some((lobby, my_player_id)) => {
+                                                draw_lobby(&ui, lobby, *my_player_id, &mut to_send)
+                                            }
+                                        }
+                                        ui.separator();
+                                        if global_info.net_server.is_some() {
+                                            disconnect_server = ui.button(
+                                                im_str!("Disconnect server"),
+                                                [200.0_f32, 40.0],
+                                            );
+                                        } else {
+                                            disconnect_client = ui.button(
+                                                im_str!("Disconnect client"),
+                                                [200.0_f32, 40.0],
+                                            );
+                                        }
                                     }
 
-                                    if ui.button(im_str!("Back"), [200.0_f32, 100.0]) {
+                                    if ui.button(im_str!("Back"), [200.0_f32, 40.0]) {
                                         next_mode = MainMode::Home;
                                     }
                                 });
                         }
+                        for e in to_send {
+                            let _ = self.sender_from_client_to_manager.try_send(e);
+                        }
+                        if let Some((lobby, _)) = &self.lobby {
+                            if lobby.started {
+                                next_mode = MainMode::Play;
+                            }
+                        }
 
                         if create_server {
                             self.net_mode = NetMode::Server;
                             let e = client::FromClient::StartServer(client::StartServer {
                                 bind: "127.0.0.1:4567".to_owned(),
+                                name: self.player_name.to_str().to_owned(),
                             });
                             let _ = self.sender_from_client_to_manager.try_send(e);
                         }
@@ -514,16 +823,19 @@ impl App {
                             self.net_mode = NetMode::Client;
                             let e = client::FromClient::StartClient(client::StartClient {
                                 bind: "127.0.0.1:4567".to_owned(),
+                                name: self.player_name.to_str().to_owned(),
                             });
                             let _ = self.sender_from_client_to_manager.try_send(e);
                         }
                         if disconnect_server {
                             self.net_mode = NetMode::Offline;
+                            self.lobby = None;
                             let e = client::FromClient::DisconnectServer;
                             let _ = self.sender_from_client_to_manager.try_send(e);
                         }
                         if disconnect_client {
                             self.net_mode = NetMode::Offline;
+                            self.lobby = None;
                             let e = client::FromClient::DisconnectClient;
                             let _ = self.sender_from_client_to_manager.try_send(e);
                         }
@@ -840,38 +1152,91 @@ impl App {
             self.game_state.my_player_id,
             self.game_state.mouse_world_pos,
         ) {
-            let orders = match self.game_state.uitool {
+            //Shift appends to the command queue and keeps the tool
+            let queued = self
+                .input_state
+                .key_pressed
+                .contains(&winit::event::VirtualKeyCode::LShift);
+            let uitool = self.game_state.uitool;
+            if !queued {
+                self.game_state.uitool = UiTool::None;
+            }
+
+            let orders = match uitool {
                 UiTool::Move | UiTool::None => vec![FrameEventFromPlayer::MoveOrder {
                     id,
                     selected: self.game_state.selected.clone(),
+                    queued,
                     mouse_world_pos,
                 }],
 
                 UiTool::Spawn(id_to_con) => {
-                    self.game_state.uitool = UiTool::None;
-
                     vec![FrameEventFromPlayer::ConOrder {
                         id,
                         selected: self.game_state.selected.clone(),
+                        queued,
                         mouse_world_pos,
                         botdef_id: id_to_con,
                     }]
                 }
 
                 UiTool::Repair => {
-                    self.game_state.uitool = UiTool::None;
-
                     if let Some(under) = self.game_state.under_mouse {
                         vec![FrameEventFromPlayer::RepairOrder {
                             id,
                             selected: self.game_state.selected.clone(),
+                            queued,
                             to_repair: under,
                         }]
                     } else {
                         vec![]
                     }
                 }
-                _ => vec![],
+
+                UiTool::Reclaim => {
+                    if let Some(wreck) = self.game_state.wreck_under(mouse_world_pos) {
+                        vec![FrameEventFromPlayer::ReclaimOrder {
+                            id,
+                            selected: self.game_state.selected.clone(),
+                            queued,
+                            to_reclaim: wreck,
+                        }]
+                    } else {
+                        vec![]
+                    }
+                }
+
+                UiTool::Attack => {
+                    vec![FrameEventFromPlayer::AttackOrder {
+                        id,
+                        selected: self.game_state.selected.clone(),
+                        queued,
+                        target: self.game_state.under_mouse,
+                        mouse_world_pos,
+                    }]
+                }
+
+                UiTool::Guard => {
+                    if let Some(under) = self.game_state.under_mouse {
+                        vec![FrameEventFromPlayer::GuardOrder {
+                            id,
+                            selected: self.game_state.selected.clone(),
+                            queued,
+                            to_guard: under,
+                        }]
+                    } else {
+                        vec![]
+                    }
+                }
+
+                UiTool::Patrol => {
+                    vec![FrameEventFromPlayer::PatrolOrder {
+                        id,
+                        selected: self.game_state.selected.clone(),
+                        queued,
+                        mouse_world_pos,
+                    }]
+                }
             };
 
             for order in orders {
@@ -916,4 +1281,328 @@ impl App {
             },
         );
     }
+
+    ///Stock against storage, then income against expense per second
+    fn resource_ui(
+        ui: &imgui::Ui,
+        name: &str,
+        stock: f64,
+        storage: f64,
+        stats: &frame::ResourceStats,
+    ) {
+        let to_sec = 10.0;
+        let income = stats.income() * to_sec;
+        let expense = stats.expense() * to_sec;
+        let scale = income.max(expense).max(0.001);
+
+        ui.text(im_str!("{}", name));
+        ProgressBar::new((stock / storage.max(1.0)) as f32)
+            .overlay_text(&im_str!("{:.0} / {:.0}", stock, storage))
+            .build(&ui);
+
+        ProgressBar::new((income / scale) as f32)
+            .overlay_text(&im_str!("income +{:.1}/s", income))
+            .build(&ui);
+        if ui.is_item_hovered() {
+            ui.tooltip_text(format!(
+                "production {:.1}/s\nreclaim {:.1}/s\nfrom allies {:.1}/s",
+                stats.production * to_sec,
+                stats.reclaim * to_sec,
+                stats.shared_in * to_sec,
+            ));
+        }
+
+        ProgressBar::new((expense / scale) as f32)
+            .overlay_text(&im_str!("expense -{:.1}/s", expense))
+            .build(&ui);
+        if ui.is_item_hovered() {
+            ui.tooltip_text(format!(
+                "build {:.1}/s\nupkeep {:.1}/s\nto allies {:.1}/s\nwasted {:.1}/s",
+                stats.build * to_sec,
+                stats.upkeep * to_sec,
+                stats.shared_out * to_sec,
+                stats.wasted * to_sec,
+            ));
+        }
+    }
+}
+
+///Map, mod and slots of the lobby, only our own slot can be edited
+fn draw_lobby(
+    ui: &Ui,
+    lobby: &lobby::Lobby,
+    my_player_id: utils::Id<Player>,
+    to_send: &mut Vec<client::FromClient>,
+) {
+    let is_host = lobby
+        .slots
+        .iter()
+        .any(|slot| slot.host && slot.player_id == my_player_id);
+
+    if is_host {
+        let maps: Vec<ImString> = lobby::list_maps().into_iter().map(ImString::new).collect();
+        let mut current = maps
+            .iter()
+            .position(|map| map.to_str() == lobby.map)
+            .unwrap_or(0);
+        let items: Vec<&ImStr> = maps.iter().map(|map| map.as_ref()).collect();
+        if imgui::ComboBox::new(im_str!("map")).build_simple_string(ui, &mut current, &items) {
+            to_send.push(client::FromClient::LobbyChange(lobby::LobbyChange::Map(
+                maps[current].to_str().to_owned(),
+            )));
+        }
+
+        let mods: Vec<ImString> = moddef::ModDef::list_on_disk()
+            .into_iter()
+            .map(ImString::new)
+            .collect();
+        let mut current = mods
+            .iter()
+            .position(|mod_name| mod_name.to_str() == lobby.mod_name)
+            .unwrap_or(0);
+        let items: Vec<&ImStr> = mods.iter().map(|mod_name| mod_name.as_ref()).collect();
+        if imgui::ComboBox::new(im_str!("mod")).build_simple_string(ui, &mut current, &items) {
+            to_send.push(client::FromClient::LobbyChange(lobby::LobbyChange::Mod(
+                mods[current].to_str().to_owned(),
+            )));
+        }
+    } else {
+        ui.text(im_str!("map: {}", lobby.map));
+        ui.text(im_str!("mod: {}", lobby.mod_name));
+    }
+    ui.separator();
+
+    for (i, slot) in lobby.slots.iter().enumerate() {
+        let [r, g, b] = slot.color;
+        imgui::ColorButton::new(&im_str!("##color{}", i), [r, g, b, 1.0]).build(ui);
+        ui.same_line(0.0);
+        if slot.player_id == my_player_id {
+            let mut name = ImString::new(slot.name.clone());
+            name.reserve(32);
+            if ui
+                .input_text(&im_str!("##name{}", i), &mut name)
+                .enter_returns_true(true)
+                .build()
+            {
+                to_send.push(client::FromClient::LobbyChange(lobby::LobbyChange::Name(
+                    name.to_str().to_owned(),
+                )));
+            }
+            ui.text(im_str!("team"));
+            for team in 0..lobby::MAX_TEAMS {
+                ui.same_line(0.0);
+                if ui.radio_button_bool(&im_str!("{}##team{}", team, i), slot.team == team) {
+                    to_send.push(client::FromClient::LobbyChange(lobby::LobbyChange::Team(
+                        team,
+                    )));
+                }
+            }
+            let mut color = slot.color;
+            if imgui::ColorEdit::new(&im_str!("color##{}", i), &mut color).build(ui) {
+                to_send.push(client::FromClient::LobbyChange(lobby::LobbyChange::Color(
+                    color,
+                )));
+            }
+            if !slot.host {
+                let mut ready = slot.ready;
+                if ui.checkbox(&im_str!("ready##{}", i), &mut ready) {
+                    to_send.push(client::FromClient::LobbyChange(lobby::LobbyChange::Ready(
+                        ready,
+                    )));
+                }
+            }
+        } else if slot.spectator {
+            ui.text(im_str!("{} (spectator)", slot.name));
+        } else {
+            ui.text(im_str!(
+                "{} (team {}){}",
+                slot.name,
+                slot.team,
+                if !slot.connected {
+                    " away"
+                } else if slot.host {
+                    " host"
+                } else if slot.ready {
+                    " ready"
+                } else {
+                    ""
+                }
+            ));
+        }
+    }
+    ui.separator();
+
+    if is_host {
+        if lobby.can_start() {
+            if ui.button(im_str!("Start game"), [200.0_f32, 40.0]) {
+                to_send.push(client::FromClient::StartGame);
+            }
+        } else {
+            ui.text(im_str!("Waiting for every player to be ready"));
+        }
+    } else {
+        ui.text(im_str!("Waiting for the host to start"));
+    }
+}
+
+///Messages seen so far, and what we type for all or our allies
+fn draw_chat(ui: &Ui, game_state: &mut game_state::State) -> Option<FrameEventFromPlayer> {
+    let mut to_send = None;
+    let chat_window = imgui::Window::new(im_str!("Chat"));
+    chat_window
+        .size([400.0, 200.0], imgui::Condition::FirstUseEver)
+        .position([3.0, 600.0], imgui::Condition::FirstUseEver)
+        .collapsed(false, imgui::Condition::FirstUseEver)
+        .build(&ui, || {
+            let players = &game_state.players;
+            ChildWindow::new("chat log")
+                .size([0.0, -50.0])
+                .build(&ui, || {
+                    let at_bottom = ui.scroll_y() >= ui.scroll_max_y();
+                    for message in game_state.chat.iter() {
+                        if let frame::PlayerMessageKind::Chat(text) = &message.kind {
+                            let (name, color) = players
+                                .get(&message.player_id)
+                                .map(|player| (&player.name[..], player.color))
+                                .unwrap_or(("?", [1.0; 3]));
+                            let allies = match message.target {
+                                frame::MessageTarget::All => "",
+                                frame::MessageTarget::Allies => " (allies)",
+                            };
+                            ui.text_colored(
+                                [color[0], color[1], color[2], 1.0],
+                                format!("{}{}:", name, allies),
+                            );
+                            ui.same_line(0.0);
+                            ui.text(text);
+                        }
+                    }
+                    //Follow new messages unless scrolled up
+                    if at_bottom {
+                        ui.set_scroll_here_y();
+                    }
+                });
+
+            if let Some(id) = game_state
+                .my_player_id
+                .filter(|id| players.contains_key(id))
+            {
+                if ui
+                    .input_text(im_str!("##chat input"), &mut game_state.chat_input)
+                    .enter_returns_true(true)
+                    .build()
+                {
+                    let text = game_state.chat_input.to_str().trim().to_owned();
+                    game_state.chat_input.clear();
+                    if !text.is_empty() {
+                        to_send = Some(FrameEventFromPlayer::Chat {
+                            id,
+                            target: game_state.message_target,
+                            text,
+                        });
+                    }
+                }
+                let mut allies_only = game_state.message_target == frame::MessageTarget::Allies;
+                if ui.checkbox(im_str!("allies only"), &mut allies_only) {
+                    game_state.message_target = if allies_only {
+                        frame::MessageTarget::Allies
+                    } else {
+                        frame::MessageTarget::All
+                    };
+                }
+                ui.same_line(0.0);
+                ui.text_disabled("ctrl click to ping, ctrl drag to draw");
+            }
+        });
+    to_send
+}
+
+///Most blocks of vision cells darkened at once, imgui draw lists hold 65536 vertices
+const MAX_FOG_BLOCKS: usize = 4000;
+
+///Darkens the cells my team doesn't see, more the ones it never explored
+fn draw_fog(
+    ui: &Ui,
+    game_state: &game_state::State,
+    view_proj: &Matrix4<f32>,
+    heightmap: &heightmap_phy::HeightmapPhy,
+) {
+    use frame_server::vision::CELL_SIZE;
+    let grid = match game_state
+        .my_player()
+        .and_then(|me| game_state.frame_zero.vision.get(&me.team))
+    {
+        Some(grid) => grid,
+        None => return,
+    };
+    let [width, height] = ui.io().display_size;
+    let to_screen = |i: usize, j: usize| {
+        let (x, y) = (i as f32 * CELL_SIZE, j as f32 * CELL_SIZE);
+        let p = view_proj * Point3::new(x, y, heightmap.safe_z(x, y)).to_homogeneous();
+        if p.z > 0.0 {
+            Some([
+                (p.x / p.w * 0.5 + 0.5) * width,
+                (p.y / p.w * 0.5 + 0.5) * height,
+            ])
+        } else {
+            None
+        }
+    };
+
+    //Blocks of step x step cells, as small as the budget allows
+    let mut step = 1;
+    let blocks = loop {
+        let mut blocks = Vec::new();
+        for j in (0..grid.height).step_by(step) {
+            for i in (0..grid.width).step_by(step) {
+                let center = (i + step / 2).min(grid.width - 1)
+                    + (j + step / 2).min(grid.height - 1) * grid.width;
+                if grid.visible[center] {
+                    continue;
+                }
+                let (i1, j1) = ((i + step).min(grid.width), (j + step).min(grid.height));
+                let corners = match (
+                    to_screen(i, j),
+                    to_screen(i1, j),
+                    to_screen(i1, j1),
+                    to_screen(i, j1),
+                ) {
+                    (Some(a), Some(b), Some(c), Some(d)) => [a, b, c, d],
+                    _ => continue,
+                };
+                let off_screen = corners.iter().all(|p| p[0] < 0.0)
+                    || corners.iter().all(|p| p[0] > width)
+                    || corners.iter().all(|p| p[1] < 0.0)
+                    || corners.iter().all(|p| p[1] > height);
+                if !off_screen {
+                    let alpha = if grid.explored[center] { 0.35 } else { 0.6 };
+                    blocks.push((corners, alpha));
+                }
+            }
+        }
+        if blocks.len() <= MAX_FOG_BLOCKS {
+            break blocks;
+        }
+        step *= 2;
+    };
+
+    imgui::Window::new(im_str!("fog"))
+        .position([0.0, 0.0], imgui::Condition::Always)
+        .size([width, height], imgui::Condition::Always)
+        .flags(
+            WindowFlags::NO_DECORATION
+                | WindowFlags::NO_INPUTS
+                | WindowFlags::NO_BACKGROUND
+                | WindowFlags::NO_SAVED_SETTINGS
+                | WindowFlags::NO_FOCUS_ON_APPEARING
+                | WindowFlags::NO_BRING_TO_FRONT_ON_FOCUS,
+        )
+        .build(&ui, || {
+            let draw_list = ui.get_window_draw_list();
+            for ([a, b, c, d], alpha) in blocks {
+                let color = [0.0, 0.0, 0.0, alpha];
+                draw_list.add_triangle(a, b, c, color).filled(true).build();
+                draw_list.add_triangle(a, c, d, color).filled(true).build();
+            }
+        });
 }
@@ -6,7 +6,9 @@ pub enum UiTool {
     None,
     Move,
     Repair,
+    Reclaim,
     Guard,
     Attack,
+    Patrol,
     Spawn(Id<botdef::BotDef>),
 }
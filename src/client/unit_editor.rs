@@ -13,6 +13,7 @@ pub struct UnitEditor {
     pub orbit: Point3<f32>,
     pub botdef: BotDef,
     pub asset_dir_cached: FileTree,
+    pub new_armor_class: String,
 }
 impl UnitEditor {
     pub fn new() -> Self {
@@ -38,12 +39,36 @@ impl UnitEditor {
             build_power: 10.0,
             build_dist: 5.0,
             metal_cost: 100,
+            is_factory: false,
+            energy_cost: 100,
+            metal_production: 0.0,
+            energy_production: 0.0,
+            extraction_radius: 0.0,
+            metal_upkeep: 0.0,
+            energy_upkeep: 0.0,
+            metal_storage: 0.0,
+            energy_storage: 0.0,
+            sight_radius: 30.0,
+            radar_radius: 0.0,
+            jammer_radius: 0.0,
+            stealth: false,
+            category: "unit".to_owned(),
+            armor_class: "light".to_owned(),
+            move_class: botdef::MoveClass {
+                move_type: botdef::MoveType::Ground,
+                max_slope: 1.0,
+                min_water_depth: 0.0,
+                max_water_depth: 4.0,
+            },
+            flight: None,
+            weapon0: None,
             part_tree: root,
         };
 
         UnitEditor {
             orbit: Point3::new(300.0, 100.0, 0.5),
             asset_dir_cached: FileTree::Unknown,
+            new_armor_class: String::new(),
             botdef,
         }
     }
@@ -113,6 +138,24 @@ impl App {
                     build_power,
                     build_dist,
                     metal_cost,
+                    is_factory,
+                    energy_cost,
+                    metal_production,
+                    energy_production,
+                    extraction_radius,
+                    metal_upkeep,
+                    energy_upkeep,
+                    metal_storage,
+                    energy_storage,
+                    sight_radius,
+                    radar_radius,
+                    jammer_radius,
+                    stealth,
+                    category,
+                    armor_class,
+                    move_class,
+                    flight,
+                    weapon0,
                     part_tree,
                 } = &unit_editor.botdef;
 
@@ -181,6 +224,398 @@ impl App {
                     .max(100.0)
                     .build();
 
+                ui.separator();
+                let mut metal_cost_ = metal_cost.clone();
+                ui.drag_int(im_str!("metal cost"), &mut metal_cost_).build();
+
+                let mut energy_cost_ = energy_cost.clone();
+                ui.drag_int(im_str!("energy cost"), &mut energy_cost_)
+                    .build();
+
+                let mut is_factory_ = *is_factory;
+                ui.checkbox(im_str!("factory"), &mut is_factory_);
+
+                let mut metal_production_human = metal_production * to_sec;
+                ui.drag_float(
+                    im_str!("metal production (metal/sec)"),
+                    &mut metal_production_human,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(1000.0)
+                .build();
+
+                let mut energy_production_human = energy_production * to_sec;
+                ui.drag_float(
+                    im_str!("energy production (energy/sec)"),
+                    &mut energy_production_human,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(1000.0)
+                .build();
+
+                let mut extraction_radius_ = extraction_radius.clone();
+                ui.drag_float(
+                    im_str!("metal extraction radius (m)"),
+                    &mut extraction_radius_,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(16.0)
+                .build();
+
+                let mut metal_upkeep_human = metal_upkeep * to_sec;
+                ui.drag_float(im_str!("metal upkeep (metal/sec)"), &mut metal_upkeep_human)
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(1000.0)
+                    .build();
+
+                let mut energy_upkeep_human = energy_upkeep * to_sec;
+                ui.drag_float(
+                    im_str!("energy upkeep (energy/sec)"),
+                    &mut energy_upkeep_human,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(1000.0)
+                .build();
+
+                let mut metal_storage_ = *metal_storage;
+                ui.drag_float(im_str!("metal storage (metal)"), &mut metal_storage_)
+                    .speed(1.0)
+                    .min(0.0)
+                    .max(100000.0)
+                    .build();
+
+                let mut energy_storage_ = *energy_storage;
+                ui.drag_float(im_str!("energy storage (energy)"), &mut energy_storage_)
+                    .speed(1.0)
+                    .min(0.0)
+                    .max(100000.0)
+                    .build();
+
+                let mut sight_radius_ = *sight_radius;
+                ui.drag_float(im_str!("sight radius (m)"), &mut sight_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(1000.0)
+                    .build();
+
+                let mut radar_radius_ = *radar_radius;
+                ui.drag_float(im_str!("radar radius (m)"), &mut radar_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(1000.0)
+                    .build();
+
+                let mut jammer_radius_ = *jammer_radius;
+                ui.drag_float(im_str!("jammer radius (m)"), &mut jammer_radius_)
+                    .speed(0.1)
+                    .min(0.0)
+                    .max(1000.0)
+                    .build();
+
+                let mut stealth_ = *stealth;
+                ui.checkbox(im_str!("stealth"), &mut stealth_);
+
+                let mut category_buf = ImString::new(category.clone());
+                category_buf.reserve(32);
+                ui.input_text(im_str!("category"), &mut category_buf)
+                    .build();
+                let category = category_buf.to_str().to_owned();
+
+                let mut armor_class_buf = ImString::new(armor_class.clone());
+                armor_class_buf.reserve(32);
+                ui.input_text(im_str!("armor class"), &mut armor_class_buf)
+                    .build();
+                let armor_class = armor_class_buf.to_str().to_owned();
+
+                ui.text(im_str!("move class"));
+                let mut move_class = move_class.clone();
+                ui.radio_button(
+                    im_str!("Ground"),
+                    &mut move_class.move_type,
+                    botdef::MoveType::Ground,
+                );
+                ui.radio_button(
+                    im_str!("Amphibious"),
+                    &mut move_class.move_type,
+                    botdef::MoveType::Amphibious,
+                );
+                ui.radio_button(
+                    im_str!("Hover"),
+                    &mut move_class.move_type,
+                    botdef::MoveType::Hover,
+                );
+                ui.radio_button(
+                    im_str!("Naval"),
+                    &mut move_class.move_type,
+                    botdef::MoveType::Naval,
+                );
+                ui.radio_button(
+                    im_str!("Submarine"),
+                    &mut move_class.move_type,
+                    botdef::MoveType::Submarine,
+                );
+
+                ui.drag_float(im_str!("max slope (dz/dx)"), &mut move_class.max_slope)
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(100.0)
+                    .build();
+
+                //Draft of boats, dive depth of submarines
+                let min_water_depth_label = match move_class.move_type {
+                    botdef::MoveType::Naval => im_str!("draft (m)"),
+                    botdef::MoveType::Submarine => im_str!("dive depth (m)"),
+                    _ => im_str!("min water depth (m)"),
+                };
+                ui.drag_float(min_water_depth_label, &mut move_class.min_water_depth)
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(100.0)
+                    .build();
+
+                ui.drag_float(
+                    im_str!("max water depth (m)"),
+                    &mut move_class.max_water_depth,
+                )
+                .speed(0.01)
+                .min(0.0)
+                .max(100.0)
+                .build();
+                move_class.max_water_depth =
+                    move_class.max_water_depth.max(move_class.min_water_depth);
+
+                ui.separator();
+                let mut flight = flight.clone();
+                let mut has_flight = flight.is_some();
+                ui.checkbox(im_str!("flight"), &mut has_flight);
+                if has_flight && flight.is_none() {
+                    flight = Some(botdef::FlightDef {
+                        flight_type: botdef::FlightType::Gunship,
+                        cruise_altitude: 20.0,
+                        min_speed: 0.0,
+                        climb_rate: 0.4,
+                        max_bank: 0.5,
+                    });
+                } else if !has_flight {
+                    flight = None;
+                }
+
+                if let Some(flight) = &mut flight {
+                    ui.radio_button(
+                        im_str!("Fixed wing"),
+                        &mut flight.flight_type,
+                        botdef::FlightType::FixedWing,
+                    );
+                    ui.radio_button(
+                        im_str!("Gunship"),
+                        &mut flight.flight_type,
+                        botdef::FlightType::Gunship,
+                    );
+
+                    ui.drag_float(im_str!("cruise altitude (m)"), &mut flight.cruise_altitude)
+                        .speed(0.1)
+                        .min(0.0)
+                        .max(1000.0)
+                        .build();
+
+                    if flight.flight_type == botdef::FlightType::FixedWing {
+                        let mut min_speed_human = flight.min_speed * to_sec;
+                        ui.drag_float(im_str!("minimum speed (m/sec)"), &mut min_speed_human)
+                            .speed(0.01)
+                            .min(0.0)
+                            .max(1000.0)
+                            .build();
+                        flight.min_speed = min_speed_human * to_frame;
+                    }
+
+                    let mut climb_rate_human = flight.climb_rate * to_sec;
+                    ui.drag_float(im_str!("climb rate (m/sec)"), &mut climb_rate_human)
+                        .speed(0.01)
+                        .min(0.01)
+                        .max(1000.0)
+                        .build();
+                    flight.climb_rate = climb_rate_human * to_frame;
+
+                    let mut max_bank_human = flight.max_bank * to_rev * 360.0;
+                    ui.drag_float(im_str!("max bank (deg)"), &mut max_bank_human)
+                        .speed(0.1)
+                        .min(0.0)
+                        .max(90.0)
+                        .build();
+                    flight.max_bank = max_bank_human / 360.0 * to_rad;
+                }
+
+                ui.separator();
+                let mut weapon0 = weapon0.clone();
+                let mut has_weapon0 = weapon0.is_some();
+                ui.checkbox(im_str!("weapon0 (AimWeapon0 part)"), &mut has_weapon0);
+                if has_weapon0 && weapon0.is_none() {
+                    weapon0 = Some(weapondef::WeaponDef {
+                        range: 6.0,
+                        reload_frame_count: 3,
+                        damage: 10,
+                        projectile_speed: 2.0,
+                        projectile_gravity: 0.08,
+                        projectile_radius: 0.25,
+                        area_of_effect: 0.0,
+                        armor_multipliers: fnv::FnvHashMap::default(),
+                        friendly_fire: false,
+                        turret_turn_rate: 0.3,
+                        firing_arc: std::f32::consts::PI,
+                        target_priority: weapondef::TargetPriority::Closest,
+                        can_target_ground: true,
+                        can_target_air: false,
+                        can_target_underwater: false,
+                    });
+                } else if !has_weapon0 {
+                    weapon0 = None;
+                }
+
+                if let Some(weapon) = &mut weapon0 {
+                    ui.drag_float(im_str!("range (m)"), &mut weapon.range)
+                        .speed(0.01)
+                        .min(0.01)
+                        .max(1000.0)
+                        .build();
+
+                    let mut reload_human = weapon.reload_frame_count as f32 / to_sec;
+                    ui.drag_float(im_str!("reload time (sec)"), &mut reload_human)
+                        .speed(0.01)
+                        .min(0.1)
+                        .max(100.0)
+                        .build();
+                    weapon.reload_frame_count = (reload_human * to_sec).round() as i32;
+
+                    ui.drag_int(im_str!("damage"), &mut weapon.damage).build();
+                    weapon.damage = weapon.damage.max(0);
+
+                    let mut projectile_speed_human = weapon.projectile_speed * to_sec;
+                    ui.drag_float(
+                        im_str!("projectile speed (m/sec)"),
+                        &mut projectile_speed_human,
+                    )
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(1000.0)
+                    .build();
+                    weapon.projectile_speed = projectile_speed_human.max(0.01) * to_frame;
+
+                    let mut projectile_gravity_human = weapon.projectile_gravity * to_sec * to_sec;
+                    ui.drag_float(
+                        im_str!("projectile gravity (m/sec²)"),
+                        &mut projectile_gravity_human,
+                    )
+                    .speed(0.01)
+                    .min(0.0)
+                    .max(100.0)
+                    .build();
+                    weapon.projectile_gravity = projectile_gravity_human * to_frame * to_frame;
+
+                    ui.drag_float(
+                        im_str!("projectile radius (m)"),
+                        &mut weapon.projectile_radius,
+                    )
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(10.0)
+                    .build();
+
+                    ui.drag_float(im_str!("area of effect (m)"), &mut weapon.area_of_effect)
+                        .speed(0.01)
+                        .min(0.0)
+                        .max(100.0)
+                        .build();
+
+                    ui.checkbox(im_str!("friendly fire"), &mut weapon.friendly_fire);
+                    ui.checkbox(im_str!("targets ground"), &mut weapon.can_target_ground);
+                    ui.checkbox(im_str!("targets air"), &mut weapon.can_target_air);
+                    ui.checkbox(
+                        im_str!("targets underwater"),
+                        &mut weapon.can_target_underwater,
+                    );
+
+                    let mut armor_classes: Vec<String> =
+                        weapon.armor_multipliers.keys().cloned().collect();
+                    armor_classes.sort();
+                    for armor_class in armor_classes {
+                        let multiplier = weapon.armor_multipliers.get_mut(&armor_class).unwrap();
+                        ui.drag_float(&im_str!("damage x vs {}", armor_class), multiplier)
+                            .speed(0.01)
+                            .min(0.0)
+                            .max(100.0)
+                            .build();
+                        ui.same_line(0.0);
+                        if ui.small_button(&im_str!("remove##{}", armor_class)) {
+                            weapon.armor_multipliers.remove(&armor_class);
+                        }
+                    }
+                    let mut new_armor_class_buf =
+                        ImString::new(unit_editor.new_armor_class.clone());
+                    new_armor_class_buf.reserve(32);
+                    ui.input_text(im_str!("new armor class"), &mut new_armor_class_buf)
+                        .build();
+                    unit_editor.new_armor_class = new_armor_class_buf.to_str().to_owned();
+                    if ui.small_button(im_str!("add multiplier")) {
+                        weapon
+                            .armor_multipliers
+                            .insert(unit_editor.new_armor_class.clone(), 1.0);
+                    }
+
+                    let mut turret_turn_rate_human = weapon.turret_turn_rate * to_sec * to_rev;
+                    ui.drag_float(
+                        im_str!("turret turn rate (rev/sec)"),
+                        &mut turret_turn_rate_human,
+                    )
+                    .speed(0.01)
+                    .min(0.01)
+                    .max(100.0)
+                    .build();
+                    weapon.turret_turn_rate = turret_turn_rate_human * to_frame * to_rad;
+
+                    let mut firing_arc_human = weapon.firing_arc * to_rev * 360.0;
+                    ui.drag_float(im_str!("firing arc (±deg)"), &mut firing_arc_human)
+                        .speed(0.1)
+                        .min(0.0)
+                        .max(180.0)
+                        .build();
+                    weapon.firing_arc = firing_arc_human / 360.0 * to_rad;
+
+                    ui.text(im_str!("target priority"));
+                    use weapondef::TargetPriority;
+                    let mut priority_category = match &weapon.target_priority {
+                        TargetPriority::Category(category) => Some(category.clone()),
+                        _ => None,
+                    };
+                    for (label, target_priority) in &[
+                        (im_str!("Closest"), TargetPriority::Closest),
+                        (im_str!("Lowest health"), TargetPriority::LowestHealth),
+                        (
+                            im_str!("Highest metal cost"),
+                            TargetPriority::HighestMetalCost,
+                        ),
+                        (im_str!("Keep current"), TargetPriority::KeepCurrent),
+                    ] {
+                        if ui.radio_button_bool(label, &weapon.target_priority == target_priority) {
+                            weapon.target_priority = target_priority.clone();
+                            priority_category = None;
+                        }
+                    }
+                    if ui.radio_button_bool(im_str!("Category"), priority_category.is_some()) {
+                        priority_category = Some(priority_category.unwrap_or_default());
+                    }
+                    if let Some(priority_category) = priority_category {
+                        let mut buf = ImString::new(priority_category);
+                        buf.reserve(32);
+                        ui.input_text(im_str!("target category"), &mut buf).build();
+                        weapon.target_priority = TargetPriority::Category(buf.to_str().to_owned());
+                    }
+                }
+
                 unit_editor.botdef.max_turn_rate = max_turn_rate_human * to_frame * to_rad;
                 unit_editor.botdef.turn_accel = turn_accel_human * to_frame * to_frame * to_rad;
                 unit_editor.botdef.max_speed = max_speed_human * to_frame;
@@ -189,6 +624,25 @@ impl App {
                 unit_editor.botdef.max_life = life.max(0);
                 unit_editor.botdef.build_power = build_power_human * to_frame;
                 unit_editor.botdef.build_dist = build_dist_;
+                unit_editor.botdef.metal_cost = metal_cost_.max(1);
+                unit_editor.botdef.is_factory = is_factory_;
+                unit_editor.botdef.energy_cost = energy_cost_.max(0);
+                unit_editor.botdef.metal_production = metal_production_human * to_frame;
+                unit_editor.botdef.energy_production = energy_production_human * to_frame;
+                unit_editor.botdef.extraction_radius = extraction_radius_;
+                unit_editor.botdef.metal_upkeep = metal_upkeep_human * to_frame;
+                unit_editor.botdef.energy_upkeep = energy_upkeep_human * to_frame;
+                unit_editor.botdef.metal_storage = metal_storage_;
+                unit_editor.botdef.energy_storage = energy_storage_;
+                unit_editor.botdef.sight_radius = sight_radius_;
+                unit_editor.botdef.radar_radius = radar_radius_;
+                unit_editor.botdef.jammer_radius = jammer_radius_;
+                unit_editor.botdef.stealth = stealth_;
+                unit_editor.botdef.category = category;
+                unit_editor.botdef.armor_class = armor_class;
+                unit_editor.botdef.move_class = move_class;
+                unit_editor.botdef.flight = flight;
+                unit_editor.botdef.weapon0 = weapon0;
                 ui.separator();
                 Self::ui_part_tree(
                     ui,
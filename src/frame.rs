@@ -11,6 +11,7 @@ use na::{Point3, Vector3};
 use std::collections::HashMap;
 use std::time::Duration;
 
+use crate::frame_server::vision::VisionGrid;
 use crate::unit;
 use mobile::*;
 use serde::{Deserialize, Serialize};
@@ -19,10 +20,18 @@ use utils::*;
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Player {
     pub id: Id<Player>,
+    pub name: String,
+    pub color: [f32; 3],
     pub kbots: FnvHashSet<Id<KBot>>,
     pub team: u8,
     pub metal: f64,
     pub energy: f64,
+    pub metal_storage: f64,
+    pub energy_storage: f64,
+    ///Give what exceeds storage to allies of the same team instead of wasting it
+    pub share_overflow: bool,
+    pub metal_stats: ResourceStats,
+    pub energy_stats: ResourceStats,
 }
 
 impl Player {
@@ -30,35 +39,268 @@ impl Player {
         let id = utils::rand_id();
         Player {
             id,
+            name: "Player".to_owned(),
+            color: [0.1, 0.4, 1.0],
             kbots: FnvHashSet::default(),
             team: 0,
             metal: 500.0,
             energy: 500.0,
+            metal_storage: BASE_STORAGE,
+            energy_storage: BASE_STORAGE,
+            share_overflow: true,
+            metal_stats: ResourceStats::default(),
+            energy_stats: ResourceStats::default(),
         }
     }
 }
 
+///Storage every player has without any building
+pub const BASE_STORAGE: f64 = 1000.0;
+///Frames over which resource stats are averaged
+pub const STATS_WINDOW: f64 = 10.0;
+
+///Flow of one resource, per frame
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+pub struct ResourceStats {
+    //Income
+    pub production: f64,
+    pub reclaim: f64,
+    pub shared_in: f64,
+    //Expense
+    pub upkeep: f64,
+    pub build: f64,
+    pub shared_out: f64,
+    ///Overflow that nobody could store
+    pub wasted: f64,
+}
+
+impl ResourceStats {
+    pub fn income(&self) -> f64 {
+        self.production + self.reclaim + self.shared_in
+    }
+
+    pub fn expense(&self) -> f64 {
+        self.upkeep + self.build + self.shared_out + self.wasted
+    }
+
+    ///Moving average of the last STATS_WINDOW frames
+    pub fn roll(&mut self, frame: &ResourceStats) {
+        let roll = |avg: &mut f64, v: f64| *avg += (v - *avg) / STATS_WINDOW;
+        roll(&mut self.production, frame.production);
+        roll(&mut self.reclaim, frame.reclaim);
+        roll(&mut self.shared_in, frame.shared_in);
+        roll(&mut self.upkeep, frame.upkeep);
+        roll(&mut self.build, frame.build);
+        roll(&mut self.shared_out, frame.shared_out);
+        roll(&mut self.wasted, frame.wasted);
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum FrameEventFromPlayer {
     RepairOrder {
         id: Id<Player>,
         selected: FnvHashSet<Id<KBot>>,
         to_repair: Id<KBot>,
+        queued: bool,
+    },
+    ReclaimOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        to_reclaim: Id<Wreck>,
+        queued: bool,
     },
     ConOrder {
         id: Id<Player>,
         selected: FnvHashSet<Id<KBot>>,
         mouse_world_pos: Vector3<f32>,
         botdef_id: Id<botdef::BotDef>,
+        queued: bool,
     },
     MoveOrder {
         id: Id<Player>,
         selected: FnvHashSet<Id<KBot>>,
         mouse_world_pos: Vector3<f32>,
+        queued: bool,
+    },
+    ///Attack target, or the ground at mouse_world_pos when None
+    AttackOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        target: Option<Id<KBot>>,
+        mouse_world_pos: Vector3<f32>,
+        queued: bool,
+    },
+    GuardOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        to_guard: Id<KBot>,
+        queued: bool,
+    },
+    PatrolOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        mouse_world_pos: Vector3<f32>,
+        queued: bool,
+    },
+    StopOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+    },
+    ///Positive amount adds to the back of the queue, negative removes from it
+    ProductionOrder {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        botdef_id: Id<botdef::BotDef>,
+        amount: i32,
+    },
+    FactoryRepeat {
+        id: Id<Player>,
+        selected: FnvHashSet<Id<KBot>>,
+        repeat: bool,
+    },
+    ShareOverflow {
+        id: Id<Player>,
+        share: bool,
+    },
+    Chat {
+        id: Id<Player>,
+        target: MessageTarget,
+        text: String,
+    },
+    MapPing {
+        id: Id<Player>,
+        target: MessageTarget,
+        position: Vector3<f32>,
+    },
+    ///Freehand line through points, on the ground
+    MapDrawing {
+        id: Id<Player>,
+        target: MessageTarget,
+        points: Vec<Vector3<f32>>,
     },
     ReplaceFrame(Frame),
 }
 
+///Longest chat message, in characters
+pub const MAX_CHAT_LEN: usize = 200;
+pub const MAX_DRAWING_POINTS: usize = 500;
+///Units a ProductionOrder adds to or removes from a queue at most
+pub const MAX_PRODUCTION_AMOUNT: i32 = 100;
+
+///Who sees a chat message, ping or drawing
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum MessageTarget {
+    All,
+    ///Players of the same team as the sender
+    Allies,
+}
+
+impl MessageTarget {
+    ///Teams of the sender and of the receiver, None for spectators who only get messages to all
+    pub fn reaches(self, sender_team: Option<u8>, team: Option<u8>) -> bool {
+        match self {
+            MessageTarget::All => true,
+            MessageTarget::Allies => sender_team.is_some() && sender_team == team,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PlayerMessageKind {
+    Chat(String),
+    Ping(Vector3<f32>),
+    Drawing(Vec<Vector3<f32>>),
+}
+
+///Relayed by the frame server to the clients, it doesn't change the simulation
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PlayerMessage {
+    pub player_id: Id<Player>,
+    pub target: MessageTarget,
+    pub kind: PlayerMessageKind,
+}
+
+///Why the frame server dropped an event
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RejectionReason {
+    UnknownPlayer,
+    ///A selected unit belongs to another player
+    NotOwned(Id<KBot>),
+    UnknownBotDef,
+    ///No selected builder has the BotDef in its ModDef::con_map entry
+    NotBuildable,
+    OffMap,
+    Unaffordable,
+    ///Chat message or drawing over the size limit
+    TooLong,
+    ///ProductionOrder amount over MAX_PRODUCTION_AMOUNT either way
+    BadAmount,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct EventRejection {
+    pub player_id: Id<Player>,
+    pub event: String,
+    pub reason: RejectionReason,
+}
+
+impl FrameEventFromPlayer {
+    pub fn player_id(&self) -> Option<Id<Player>> {
+        use FrameEventFromPlayer::*;
+        match self {
+            RepairOrder { id, .. }
+            | ReclaimOrder { id, .. }
+            | ConOrder { id, .. }
+            | MoveOrder { id, .. }
+            | AttackOrder { id, .. }
+            | GuardOrder { id, .. }
+            | PatrolOrder { id, .. }
+            | StopOrder { id, .. }
+            | ProductionOrder { id, .. }
+            | FactoryRepeat { id, .. }
+            | ShareOverflow { id, .. }
+            | Chat { id, .. }
+            | MapPing { id, .. }
+            | MapDrawing { id, .. } => Some(*id),
+            ReplaceFrame(_) => None,
+        }
+    }
+
+    ///Chat, pings and drawings, relayed to their target only
+    pub fn message_target(&self) -> Option<MessageTarget> {
+        use FrameEventFromPlayer::*;
+        match self {
+            Chat { target, .. } | MapPing { target, .. } | MapDrawing { target, .. } => {
+                Some(*target)
+            }
+            _ => None,
+        }
+    }
+
+    ///Events of a frame are applied by player, then in this order
+    pub fn rank(&self) -> u8 {
+        use FrameEventFromPlayer::*;
+        match self {
+            ReplaceFrame(_) => 0,
+            ShareOverflow { .. } => 1,
+            StopOrder { .. } => 2,
+            MoveOrder { .. } => 3,
+            PatrolOrder { .. } => 4,
+            AttackOrder { .. } => 5,
+            GuardOrder { .. } => 6,
+            RepairOrder { .. } => 7,
+            ReclaimOrder { .. } => 8,
+            ConOrder { .. } => 9,
+            ProductionOrder { .. } => 10,
+            FactoryRepeat { .. } => 11,
+            Chat { .. } => 12,
+            MapPing { .. } => 13,
+            MapDrawing { .. } => 14,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ProfilerMap {
     pub hm: HashMap<String, std::time::Duration>,
@@ -111,15 +353,25 @@ pub struct Frame {
     pub heightmap_phy: Option<heightmap_phy::HeightmapPhy>,
     pub players: FnvHashMap<Id<Player>, Player>,
     pub kbots: FnvHashMap<Id<KBot>, KBot>,
+    pub wrecks: FnvHashMap<Id<Wreck>, Wreck>,
+    ///By team
+    pub vision: FnvHashMap<u8, VisionGrid>,
+    ///Where the server shows units of other teams known by radar only, it keeps them out of the
+    ///frames of the clients
+    pub radar_blips: Vec<Point3<f32>>,
     pub moddef: moddef::ModDef,
     // relevant to send to client once
     pub bot_defs: FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
     // relevant to send to client always
     pub number: i32,
+    ///Ids of what the frame server creates
+    pub id_generator: IdGenerator,
     pub explosions: Vec<ExplosionEvent>,
     pub kbots_dead: FnvHashSet<Id<KBot>>,
     pub kinematic_projectiles_dead: Vec<Id<KinematicProjectile>>,
     pub kinematic_projectiles_birth: Vec<KinematicProjectile>,
+    pub event_rejections: Vec<EventRejection>,
+    pub player_messages: Vec<PlayerMessage>,
     pub frame_profiler: ProfilerMap,
 }
 
@@ -130,6 +382,8 @@ impl Frame {
             players: FnvHashMap::default(),
             moddef: moddef::ModDef::new(),
             kbots: FnvHashMap::default(),
+            wrecks: FnvHashMap::default(),
+            vision: FnvHashMap::default(),
             kinematic_projectiles: FnvHashMap::default(),
             arrows: Vec::new(),
             explosions: Vec::new(),
@@ -138,7 +392,51 @@ impl Frame {
             kbots_dead: FnvHashSet::default(),
             kinematic_projectiles_dead: Vec::new(),
             kinematic_projectiles_birth: Vec::new(),
+            event_rejections: Vec::new(),
+            radar_blips: Vec::new(),
+            player_messages: Vec::new(),
             bot_defs: FnvHashMap::default(),
+            id_generator: IdGenerator::new(),
+        }
+    }
+
+    ///Hash of the simulated state, independent of the maps iteration order
+    pub fn canonical_hash(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = fnv::FnvHasher::default();
+        self.number.hash(&mut hasher);
+        for id in sorted_ids(&self.kbots) {
+            bincode::serialize(&self.kbots[&id])
+                .unwrap()
+                .hash(&mut hasher);
+        }
+        for id in sorted_ids(&self.players) {
+            let player = &self.players[&id];
+            let mut kbots: Vec<IdValue> = player.kbots.iter().map(|id| id.value).collect();
+            kbots.sort();
+            bincode::serialize(&(
+                player.id.value,
+                player.team,
+                player.metal,
+                player.energy,
+                kbots,
+            ))
+            .unwrap()
+            .hash(&mut hasher);
+        }
+        for id in sorted_ids(&self.wrecks) {
+            bincode::serialize(&self.wrecks[&id])
+                .unwrap()
+                .hash(&mut hasher);
+        }
+        for id in sorted_ids(&self.kinematic_projectiles) {
+            bincode::serialize(&self.kinematic_projectiles[&id])
+                .unwrap()
+                .hash(&mut hasher);
         }
+        bincode::serialize(&self.explosions)
+            .unwrap()
+            .hash(&mut hasher);
+        hasher.finish()
     }
 }
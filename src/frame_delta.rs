@@ -0,0 +1,830 @@
+extern crate nalgebra as na;
+
+use crate::botdef;
+use crate::frame::*;
+use crate::frame_server::vision;
+use crate::heightmap_phy::HeightmapPhy;
+use crate::mobile::*;
+use crate::moddef::ModDef;
+use crate::utils::*;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use fnv::{FnvHashMap, FnvHashSet};
+use na::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::prelude::*;
+
+///Ticks between two authoritative frames, clients compute the ticks in between themselves
+const SNAPSHOT_INTERVAL: usize = 10;
+///Ticks between two full frames, so that a lost delta is never fatal for long
+const KEYFRAME_INTERVAL: usize = 50;
+///Drift a client's KBot may have from the snapshot before being corrected, in m
+const POSITION_TOLERANCE: f32 = 0.25;
+///Positions are sent in 1/32 m as u16, enough for maps up to MAX_MAP_SIZE
+const POSITION_SCALE: f32 = 32.0;
+///m, side of the biggest map whose positions fit the u16 of quantize_position
+pub const MAX_MAP_SIZE: usize = 2048;
+///Speeds are sent in 1/1024 m per frame
+const SPEED_SCALE: f32 = 1024.0;
+///Angular velocities are sent in 1/4096 rad per frame
+const ANGULAR_VELOCITY_SCALE: f32 = 4096.0;
+///Unit vectors are sent as i16 components
+const UNIT_SCALE: f32 = 32767.0;
+const ANGLE_SCALE: f32 = 32767.0 / std::f32::consts::PI;
+
+///What the server streams every tick instead of the whole DataToComputeNextFrame
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FrameMessage {
+    ///Sent only when they change
+    moddef: Option<ModDef>,
+    bot_defs: Option<FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>>,
+    ///Taken out of the ReplaceFrame events, sent once per game
+    heightmap_phy: Option<HeightmapPhy>,
+    body: FrameBody,
+    events: Vec<FrameEventFromPlayer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum FrameBody {
+    ///Whole frame without moddef, bot_defs and heightmap
+    Keyframe(Frame),
+    Delta(FrameDelta),
+    ///Only the events, the receiver already computed the frame of that number itself
+    Events {
+        number: i32,
+    },
+}
+
+///One tick of the server, for the client to compute on its own frame
+pub struct ServerTick {
+    ///Frame the events apply to
+    pub number: i32,
+    ///Authoritative frame of that number, every few ticks
+    pub snapshot: Option<Frame>,
+    pub events: Vec<FrameEventFromPlayer>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FrameDelta {
+    ///Number of the frame this delta applies to
+    base: i32,
+    number: i32,
+    id_generator: IdGenerator,
+    ///Without their kbots, the receiver rebuilds them from player_id
+    players: Vec<Player>,
+    kbots: Vec<KBotDelta>,
+    kbots_removed: Vec<Id<KBot>>,
+    ///New or being reclaimed since the base
+    wrecks: Vec<Wreck>,
+    wrecks_removed: Vec<Id<Wreck>>,
+    ///The client computes the vision grids itself
+    radar_blips: Vec<Point3<f32>>,
+    ///Since the base, which can be several ticks old
+    projectiles_added: Vec<KinematicProjectile>,
+    projectiles_removed: Vec<Id<KinematicProjectile>>,
+    arrows: Vec<Arrow>,
+    explosions: Vec<ExplosionEvent>,
+    kbots_dead: FnvHashSet<Id<KBot>>,
+    kinematic_projectiles_dead: Vec<Id<KinematicProjectile>>,
+    kinematic_projectiles_birth: Vec<KinematicProjectile>,
+    event_rejections: Vec<EventRejection>,
+    player_messages: Vec<PlayerMessage>,
+    frame_profiler: ProfilerMap,
+}
+
+///Quantized fields of a KBot that changed, all of them for a new KBot
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct KBotDelta {
+    id: Id<KBot>,
+    ///Move since the last quantized position, small values compress well
+    position: Option<[i16; 3]>,
+    speed: Option<[i16; 3]>,
+    dir: Option<[i16; 3]>,
+    angle: Option<i16>,
+    angular_velocity: Option<i16>,
+    up: Option<[i16; 3]>,
+    weapon0_dir: Option<[i16; 3]>,
+    wheel0_angle: Option<u16>,
+    life: Option<i32>,
+    con_completed: Option<f32>,
+    state: Option<KBotState>,
+}
+
+///Fields of a KBot that rarely change, sent as they are
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct KBotState {
+    move_target: Option<Point3<f32>>,
+    current_command: Command,
+    command_queue: VecDeque<Command>,
+    player_id: Id<Player>,
+    team: u8,
+    grounded: bool,
+    frame_last_shot: i32,
+    weapon0_target: Option<Id<KBot>>,
+    botdef_id: Id<botdef::BotDef>,
+    factory: Option<Factory>,
+}
+
+impl KBotState {
+    fn of(kbot: &KBot) -> Self {
+        KBotState {
+            move_target: kbot.move_target,
+            current_command: kbot.current_command.clone(),
+            command_queue: kbot.command_queue.clone(),
+            player_id: kbot.player_id,
+            team: kbot.team,
+            grounded: kbot.grounded,
+            frame_last_shot: kbot.frame_last_shot,
+            weapon0_target: kbot.weapon0_target,
+            botdef_id: kbot.botdef_id,
+            factory: kbot.factory.clone(),
+        }
+    }
+}
+
+impl KBotDelta {
+    ///None when nothing visible to the receiver changed
+    fn between(old: Option<&KBot>, new: &KBot) -> Option<Self> {
+        let delta = KBotDelta {
+            id: new.id,
+            position: {
+                let old = old.map_or([0; 3], |k| quantize_position(&k.position));
+                let new = quantize_position(&new.position);
+                let moved = [
+                    new[0].wrapping_sub(old[0]) as i16,
+                    new[1].wrapping_sub(old[1]) as i16,
+                    new[2].wrapping_sub(old[2]) as i16,
+                ];
+                changed(Some([0; 3]), moved)
+            },
+            speed: changed(
+                old.map(|k| quantize_vector(&k.speed, SPEED_SCALE)),
+                quantize_vector(&new.speed, SPEED_SCALE),
+            ),
+            dir: changed(
+                old.map(|k| quantize_vector(&k.dir, UNIT_SCALE)),
+                quantize_vector(&new.dir, UNIT_SCALE),
+            ),
+            angle: changed(
+                old.map(|k| quantize(k.angle.rad, ANGLE_SCALE)),
+                quantize(new.angle.rad, ANGLE_SCALE),
+            ),
+            angular_velocity: changed(
+                old.map(|k| quantize(k.angular_velocity, ANGULAR_VELOCITY_SCALE)),
+                quantize(new.angular_velocity, ANGULAR_VELOCITY_SCALE),
+            ),
+            up: changed(
+                old.map(|k| quantize_vector(&k.up, UNIT_SCALE)),
+                quantize_vector(&new.up, UNIT_SCALE),
+            ),
+            weapon0_dir: changed(
+                old.map(|k| quantize_vector(&k.weapon0_dir, UNIT_SCALE)),
+                quantize_vector(&new.weapon0_dir, UNIT_SCALE),
+            ),
+            wheel0_angle: changed(
+                old.map(|k| quantize_turn(k.wheel0_angle)),
+                quantize_turn(new.wheel0_angle),
+            ),
+            life: changed(old.map(|k| k.life), new.life),
+            con_completed: changed(old.map(|k| k.con_completed), new.con_completed),
+            state: changed(old.map(KBotState::of), KBotState::of(new)),
+        };
+
+        let unchanged = delta.position.is_none()
+            && delta.speed.is_none()
+            && delta.dir.is_none()
+            && delta.angle.is_none()
+            && delta.angular_velocity.is_none()
+            && delta.up.is_none()
+            && delta.weapon0_dir.is_none()
+            && delta.wheel0_angle.is_none()
+            && delta.life.is_none()
+            && delta.con_completed.is_none()
+            && delta.state.is_none();
+        if unchanged {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+
+    fn apply(&self, kbot: &mut KBot) {
+        if let Some(moved) = self.position {
+            let q = quantize_position(&kbot.position);
+            kbot.position = Point3::new(
+                q[0].wrapping_add(moved[0] as u16) as f32 / POSITION_SCALE,
+                q[1].wrapping_add(moved[1] as u16) as f32 / POSITION_SCALE,
+                q[2].wrapping_add(moved[2] as u16) as f32 / POSITION_SCALE,
+            );
+        }
+        if let Some(q) = self.speed {
+            kbot.speed = dequantize_vector(q, SPEED_SCALE);
+        }
+        if let Some(q) = self.dir {
+            kbot.dir = dequantize_vector(q, UNIT_SCALE);
+        }
+        if let Some(q) = self.angle {
+            kbot.angle = Angle::new(q as f32 / ANGLE_SCALE);
+        }
+        if let Some(q) = self.angular_velocity {
+            kbot.angular_velocity = q as f32 / ANGULAR_VELOCITY_SCALE;
+        }
+        if let Some(q) = self.up {
+            kbot.up = dequantize_vector(q, UNIT_SCALE);
+        }
+        if let Some(q) = self.weapon0_dir {
+            kbot.weapon0_dir = dequantize_vector(q, UNIT_SCALE);
+        }
+        if let Some(q) = self.wheel0_angle {
+            kbot.wheel0_angle = q as f32 / 65536.0 * 2.0 * std::f32::consts::PI;
+        }
+        if let Some(life) = self.life {
+            kbot.life = life;
+        }
+        if let Some(con_completed) = self.con_completed {
+            kbot.con_completed = con_completed;
+        }
+        if let Some(state) = &self.state {
+            kbot.move_target = state.move_target;
+            kbot.current_command = state.current_command.clone();
+            kbot.command_queue = state.command_queue.clone();
+            kbot.player_id = state.player_id;
+            kbot.team = state.team;
+            kbot.grounded = state.grounded;
+            kbot.frame_last_shot = state.frame_last_shot;
+            kbot.weapon0_target = state.weapon0_target;
+            kbot.botdef_id = state.botdef_id;
+            kbot.factory = state.factory.clone();
+        }
+    }
+
+    ///A KBot the receiver doesn't know yet, the delta must hold every field
+    fn to_kbot(&self) -> Option<KBot> {
+        let state = self.state.as_ref()?;
+        let mut kbot = KBot {
+            id: self.id,
+            position: Point3::new(0.0, 0.0, 0.0),
+            speed: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(1.0, 0.0, 0.0),
+            angle: Angle::new(0.0),
+            angular_velocity: 0.0,
+            up: Vector3::new(0.0, 0.0, 1.0),
+            move_target: None,
+            current_command: Command::None,
+            command_queue: VecDeque::new(),
+            life: 0,
+            con_completed: 1.0,
+            player_id: state.player_id,
+            team: state.team,
+            grounded: false,
+            frame_last_shot: 0,
+            weapon0_dir: Vector3::new(1.0, 0.0, 0.0),
+            weapon0_target: None,
+            wheel0_angle: 0.0,
+            botdef_id: state.botdef_id,
+            factory: None,
+        };
+        self.apply(&mut kbot);
+        Some(kbot)
+    }
+}
+
+fn changed<T: PartialEq>(old: Option<T>, new: T) -> Option<T> {
+    if old.as_ref() == Some(&new) {
+        None
+    } else {
+        Some(new)
+    }
+}
+
+fn quantize(x: f32, scale: f32) -> i16 {
+    (x * scale).round() as i16
+}
+
+fn quantize_vector(v: &Vector3<f32>, scale: f32) -> [i16; 3] {
+    [
+        quantize(v.x, scale),
+        quantize(v.y, scale),
+        quantize(v.z, scale),
+    ]
+}
+
+fn dequantize_vector(q: [i16; 3], scale: f32) -> Vector3<f32> {
+    Vector3::new(
+        q[0] as f32 / scale,
+        q[1] as f32 / scale,
+        q[2] as f32 / scale,
+    )
+}
+
+///Units are kept on the map, so coordinates are within 0 and MAX_MAP_SIZE, anything else is
+///clamped
+fn quantize_position(p: &Point3<f32>) -> [u16; 3] {
+    [
+        (p.x * POSITION_SCALE).round() as u16,
+        (p.y * POSITION_SCALE).round() as u16,
+        (p.z * POSITION_SCALE).round() as u16,
+    ]
+}
+
+///Wheel angles only grow, only the fraction of turn matters
+fn quantize_turn(rad: f32) -> u16 {
+    let two_pi = 2.0 * std::f32::consts::PI;
+    ((rad.rem_euclid(two_pi) / two_pi * 65536.0).round() as u32 % 65536) as u16
+}
+
+///Kbots of players are rebuilt from the kbots themselves instead of being sent
+fn rebuild_player_kbots(frame: &mut Frame) {
+    for player in frame.players.values_mut() {
+        player.kbots.clear();
+    }
+    for kbot in frame.kbots.values() {
+        if let Some(player) = frame.players.get_mut(&kbot.player_id) {
+            player.kbots.insert(kbot.id);
+        }
+    }
+}
+
+impl FrameDelta {
+    fn between(base: &Frame, frame: &Frame) -> Self {
+        let kbots = sorted_ids(&frame.kbots)
+            .iter()
+            .filter_map(|id| KBotDelta::between(base.kbots.get(id), &frame.kbots[id]))
+            .collect();
+        let kbots_removed = sorted_ids(&base.kbots)
+            .into_iter()
+            .filter(|id| !frame.kbots.contains_key(id))
+            .collect();
+        let wrecks = sorted_ids(&frame.wrecks)
+            .iter()
+            .filter(|id| base.wrecks.get(id) != frame.wrecks.get(id))
+            .map(|id| frame.wrecks[id].clone())
+            .collect();
+        let wrecks_removed = sorted_ids(&base.wrecks)
+            .into_iter()
+            .filter(|id| !frame.wrecks.contains_key(id))
+            .collect();
+        let projectiles_added = sorted_ids(&frame.kinematic_projectiles)
+            .iter()
+            .filter(|id| !base.kinematic_projectiles.contains_key(id))
+            .map(|id| frame.kinematic_projectiles[id].clone())
+            .collect();
+        let projectiles_removed = sorted_ids(&base.kinematic_projectiles)
+            .into_iter()
+            .filter(|id| !frame.kinematic_projectiles.contains_key(id))
+            .collect();
+        let players = sorted_ids(&frame.players)
+            .iter()
+            .map(|id| {
+                let mut player = frame.players[id].clone();
+                player.kbots.clear();
+                player
+            })
+            .collect();
+
+        FrameDelta {
+            base: base.number,
+            number: frame.number,
+            id_generator: frame.id_generator.clone(),
+            players,
+            kbots,
+            kbots_removed,
+            wrecks,
+            wrecks_removed,
+            radar_blips: frame.radar_blips.clone(),
+            projectiles_added,
+            projectiles_removed,
+            arrows: frame.arrows.clone(),
+            explosions: frame.explosions.clone(),
+            kbots_dead: frame.kbots_dead.clone(),
+            kinematic_projectiles_dead: frame.kinematic_projectiles_dead.clone(),
+            kinematic_projectiles_birth: frame.kinematic_projectiles_birth.clone(),
+            event_rejections: frame.event_rejections.clone(),
+            player_messages: frame.player_messages.clone(),
+            frame_profiler: frame.frame_profiler.clone(),
+        }
+    }
+
+    ///Both the encoder and the decoder go through here so that they hold the same frame
+    fn apply(&self, frame: &mut Frame) {
+        frame.number = self.number;
+        frame.id_generator = self.id_generator.clone();
+
+        for delta in self.kbots.iter() {
+            match frame.kbots.get_mut(&delta.id) {
+                Some(kbot) => delta.apply(kbot),
+                None => {
+                    if let Some(kbot) = delta.to_kbot() {
+                        frame.kbots.insert(kbot.id, kbot);
+                    }
+                }
+            }
+        }
+        for id in self.kbots_removed.iter() {
+            frame.kbots.remove(id);
+        }
+        for wreck in self.wrecks.iter() {
+            frame.wrecks.insert(wreck.id, wreck.clone());
+        }
+        for id in self.wrecks_removed.iter() {
+            frame.wrecks.remove(id);
+        }
+        frame.players = self
+            .players
+            .iter()
+            .map(|player| (player.id, player.clone()))
+            .collect();
+        rebuild_player_kbots(frame);
+        frame.radar_blips = self.radar_blips.clone();
+
+        for id in self.projectiles_removed.iter() {
+            frame.kinematic_projectiles.remove(id);
+        }
+        for proj in self.projectiles_added.iter() {
+            frame.kinematic_projectiles.insert(proj.id, proj.clone());
+        }
+
+        frame.arrows = self.arrows.clone();
+        frame.explosions = self.explosions.clone();
+        frame.kbots_dead = self.kbots_dead.clone();
+        frame.kinematic_projectiles_dead = self.kinematic_projectiles_dead.clone();
+        frame.kinematic_projectiles_birth = self.kinematic_projectiles_birth.clone();
+        frame.event_rejections = self.event_rejections.clone();
+        frame.player_messages = self.player_messages.clone();
+        frame.frame_profiler = self.frame_profiler.clone();
+    }
+}
+
+///Server side, keeps the frame as the clients rebuild it
+pub struct FrameEncoder {
+    ///Whose clients get the messages, None for spectators who see every unit
+    team: Option<u8>,
+    base: Option<Frame>,
+    moddef: Option<ModDef>,
+    bot_defs: Option<FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>>,
+    heightmap_phy: Option<HeightmapPhy>,
+    tick: usize,
+    ///A client joined from a frame the others don't have
+    force_keyframe: bool,
+}
+
+impl FrameEncoder {
+    pub fn new(team: Option<u8>) -> Self {
+        FrameEncoder {
+            team,
+            base: None,
+            moddef: None,
+            bot_defs: None,
+            heightmap_phy: None,
+            tick: 0,
+            force_keyframe: false,
+        }
+    }
+
+    ///Same statics as this encoder, for the clients of another team, starting with a keyframe
+    pub fn fork(&self, team: Option<u8>) -> Self {
+        FrameEncoder {
+            team,
+            base: None,
+            moddef: self.moddef.clone(),
+            bot_defs: self.bot_defs.clone(),
+            heightmap_phy: self.heightmap_phy.clone(),
+            tick: self.tick,
+            force_keyframe: true,
+        }
+    }
+
+    pub fn encode(&mut self, data: &DataToComputeNextFrame) -> FrameMessage {
+        let frame = &data.old_frame;
+
+        let mut heightmap_phy = None;
+        let team = self.team;
+        let mut events: Vec<FrameEventFromPlayer> = data
+            .events
+            .iter()
+            .filter(|event| match (event.message_target(), event.player_id()) {
+                (Some(target), Some(id)) => reaches(team, &frame.players, id, target),
+                (None, Some(id)) => is_ally(team, &frame.players, id),
+                (_, None) => true,
+            })
+            .cloned()
+            .collect();
+        for event in events.iter_mut() {
+            if let FrameEventFromPlayer::ReplaceFrame(replacer) = event {
+                if let Some(heightmap) = replacer.heightmap_phy.take() {
+                    self.heightmap_phy = Some(heightmap.clone());
+                    heightmap_phy = Some(heightmap);
+                }
+            }
+        }
+
+        let moddef = if self.moddef.as_ref() != Some(&frame.moddef) {
+            self.moddef = Some(frame.moddef.clone());
+            self.moddef.clone()
+        } else {
+            None
+        };
+        let bot_defs = if self.bot_defs.as_ref() != Some(&frame.bot_defs) {
+            self.bot_defs = Some(frame.bot_defs.clone());
+            self.bot_defs.clone()
+        } else {
+            None
+        };
+
+        let body = match &mut self.base {
+            _ if self.force_keyframe || self.tick.is_multiple_of(KEYFRAME_INTERVAL) => {
+                self.force_keyframe = false;
+                let keyframe = seen(team, frame);
+                self.base = Some(keyframe.clone());
+                FrameBody::Keyframe(keyframe)
+            }
+            Some(base) if self.tick.is_multiple_of(SNAPSHOT_INTERVAL) => {
+                let delta = FrameDelta::between(base, &seen(team, frame));
+                delta.apply(base);
+                FrameBody::Delta(delta)
+            }
+            _ => FrameBody::Events {
+                number: frame.number,
+            },
+        };
+        self.tick += 1;
+
+        FrameMessage {
+            moddef,
+            bot_defs,
+            heightmap_phy,
+            body,
+            events,
+        }
+    }
+
+    ///Everything a client joining now needs, to send instead of the last encoded message
+    pub fn keyframe(
+        &mut self,
+        data: &DataToComputeNextFrame,
+        message: &FrameMessage,
+    ) -> FrameMessage {
+        //The next deltas couldn't apply to this frame
+        self.force_keyframe = true;
+        FrameMessage {
+            moddef: self.moddef.clone(),
+            bot_defs: self.bot_defs.clone(),
+            heightmap_phy: self.heightmap_phy.clone(),
+            body: FrameBody::Keyframe(seen(self.team, &data.old_frame)),
+            events: message.events.clone(),
+        }
+    }
+}
+
+///Messages to allies only reach the clients of their team, spectators get none of them
+fn reaches(
+    team: Option<u8>,
+    players: &FnvHashMap<Id<Player>, Player>,
+    sender: Id<Player>,
+    target: MessageTarget,
+) -> bool {
+    let sender_team = players.get(&sender).map(|player| player.team);
+    target.reaches(sender_team, team)
+}
+
+///Orders of the other teams are hidden from the clients of team, spectators get them all
+fn is_ally(team: Option<u8>, players: &FnvHashMap<Id<Player>, Player>, sender: Id<Player>) -> bool {
+    team.is_none() || players.get(&sender).map(|player| player.team) == team
+}
+
+///What the clients of team may know of frame, without the statics
+fn seen(team: Option<u8>, frame: &Frame) -> Frame {
+    let mut seen = match team {
+        Some(team) => vision::seen_by(frame, team),
+        None => {
+            let mut seen = frame.clone();
+            seen.vision.clear();
+            seen
+        }
+    };
+    let players = &frame.players;
+    seen.player_messages
+        .retain(|message| reaches(team, players, message.player_id, message.target));
+    seen.moddef = ModDef::new();
+    seen.bot_defs = FnvHashMap::default();
+    seen.heightmap_phy = None;
+    seen
+}
+
+///Client side, rebuilds the frames from the messages of a FrameEncoder
+pub struct FrameDecoder {
+    base: Option<Frame>,
+    moddef: ModDef,
+    bot_defs: FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+    heightmap_phy: Option<HeightmapPhy>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            base: None,
+            moddef: ModDef::new(),
+            bot_defs: FnvHashMap::default(),
+            heightmap_phy: None,
+        }
+    }
+
+    ///None until the delta chain is unbroken, that is after the next keyframe
+    pub fn decode(&mut self, message: FrameMessage) -> Option<ServerTick> {
+        if let Some(moddef) = message.moddef {
+            self.moddef = moddef;
+        }
+        if let Some(bot_defs) = message.bot_defs {
+            self.bot_defs = bot_defs;
+        }
+        if message.heightmap_phy.is_some() {
+            self.heightmap_phy = message.heightmap_phy;
+        }
+
+        let mut events = message.events;
+        for event in events.iter_mut() {
+            if let FrameEventFromPlayer::ReplaceFrame(replacer) = event {
+                if replacer.heightmap_phy.is_none() {
+                    replacer.heightmap_phy = self.heightmap_phy.clone();
+                }
+            }
+        }
+
+        let number = match message.body {
+            FrameBody::Keyframe(frame) => {
+                //Joining a game in progress, our frame server never saw its ReplaceFrame
+                let replaced = events
+                    .iter()
+                    .any(|event| matches!(event, FrameEventFromPlayer::ReplaceFrame(_)));
+                if self.base.is_none() && !replaced && self.heightmap_phy.is_some() {
+                    let mut replacer = self.with_statics(&frame);
+                    replacer.heightmap_phy = self.heightmap_phy.clone();
+                    events.insert(0, FrameEventFromPlayer::ReplaceFrame(replacer));
+                }
+                let number = frame.number;
+                self.base = Some(frame);
+                number
+            }
+            FrameBody::Delta(delta) => match &mut self.base {
+                Some(base) if base.number == delta.base => {
+                    delta.apply(base);
+                    delta.number
+                }
+                _ => {
+                    log::warn!(
+                        "Dropping delta of frame {}, waiting for a keyframe",
+                        delta.number
+                    );
+                    self.base = None;
+                    return None;
+                }
+            },
+            FrameBody::Events { number } => {
+                //Nothing to apply the events to before the first keyframe
+                self.base.as_ref()?;
+                return Some(ServerTick {
+                    number,
+                    snapshot: None,
+                    events,
+                });
+            }
+        };
+
+        self.base.as_ref().map(|base| ServerTick {
+            number,
+            snapshot: Some(self.with_statics(base)),
+            events,
+        })
+    }
+
+    fn with_statics(&self, frame: &Frame) -> Frame {
+        let mut frame = frame.clone();
+        frame.moddef = self.moddef.clone();
+        frame.bot_defs = self.bot_defs.clone();
+        frame
+    }
+}
+
+///Corrects the KBots of our own frame that drifted away from the snapshot of the server,
+///returns the frame to compute next and how many KBots were corrected
+pub fn reconcile(local: Frame, snapshot: Frame) -> (Frame, usize) {
+    //Too late or too early to compare, the snapshot replaces everything
+    if local.number != snapshot.number {
+        let corrected = snapshot.kbots.len();
+        return (snapshot, corrected);
+    }
+
+    let mut frame = snapshot;
+    let mut local_kbots = local.kbots;
+    let mut corrected = 0;
+    for kbot in frame.kbots.values_mut() {
+        match local_kbots.remove(&kbot.id) {
+            Some(local_kbot) if !diverged(&local_kbot, kbot) => *kbot = local_kbot,
+            _ => corrected += 1,
+        }
+    }
+    //Known locally but gone on the server
+    corrected += local_kbots.len();
+
+    //What happened during this tick is what we showed
+    frame.arrows = local.arrows;
+    frame.explosions = local.explosions;
+    frame.kbots_dead = local.kbots_dead;
+    frame.kinematic_projectiles_dead = local.kinematic_projectiles_dead;
+    frame.kinematic_projectiles_birth = local.kinematic_projectiles_birth;
+    frame.event_rejections = local.event_rejections;
+    frame.player_messages = local.player_messages;
+    frame.frame_profiler = local.frame_profiler;
+    //Only keyframes hold a vision grid, the one we computed has seen more since
+    frame.vision = local.vision;
+    (frame, corrected)
+}
+
+///Snapshots are quantized, small drifts are kept rather than corrected
+fn diverged(local: &KBot, snapshot: &KBot) -> bool {
+    (local.position - snapshot.position).norm() > POSITION_TOLERANCE
+        || local.life != snapshot.life
+        || (local.con_completed - snapshot.con_completed).abs() > 0.01
+        || KBotState::of(local) != KBotState::of(snapshot)
+}
+
+///Fast zlib level, it runs every tick
+pub fn compress(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+pub fn decompress(bytes: &[u8]) -> bincode::Result<FrameMessage> {
+    bincode::deserialize_from(ZlibDecoder::new(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_server::vision::update_vision;
+
+    #[test]
+    fn clients_only_get_what_their_team_sees() {
+        let path = "src/asset/botdef/unit_example.json";
+        let tank: botdef::BotDef =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let heightmap = HeightmapPhy::new(256, 256);
+        let mut frame = Frame::new();
+        frame.bot_defs.insert(tank.id, tank.clone());
+        for (team, x) in [(0, 20.0), (1, 200.0)].iter() {
+            let mut player = Player::new();
+            player.id = Id::new(1 + *team as u64);
+            player.team = *team;
+            let mut kbot = KBot::new(Point3::new(*x, *x, 0.0), &tank, player.id);
+            kbot.id = Id::new(100 + *team as u64);
+            kbot.team = *team;
+            player.kbots.insert(kbot.id);
+            frame.kbots.insert(kbot.id, kbot);
+            frame.players.insert(player.id, player);
+        }
+        update_vision(
+            &mut frame.vision,
+            &frame.kbots,
+            &frame.players,
+            &frame.bot_defs,
+            &heightmap,
+        );
+        let data = DataToComputeNextFrame {
+            old_frame: frame,
+            events: vec![
+                FrameEventFromPlayer::Chat {
+                    id: Id::new(2),
+                    target: MessageTarget::Allies,
+                    text: "attack".to_owned(),
+                },
+                FrameEventFromPlayer::MoveOrder {
+                    id: Id::new(2),
+                    selected: vec![Id::new(101)].into_iter().collect(),
+                    mouse_world_pos: Vector3::new(20.0, 20.0, 0.0),
+                    queued: false,
+                },
+            ],
+        };
+
+        let message = FrameEncoder::new(None).encode(&data);
+        let tick = FrameDecoder::new().decode(message).unwrap();
+        match &tick.events[..] {
+            [FrameEventFromPlayer::MoveOrder { id, .. }] => assert_eq!(*id, Id::new(2)),
+            events => panic!("spectators got {:?}", events),
+        }
+
+        let message = FrameEncoder::new(Some(0)).encode(&data);
+        let tick = FrameDecoder::new().decode(message).unwrap();
+        let snapshot = tick.snapshot.unwrap();
+        assert!(tick.events.is_empty());
+        assert_eq!(
+            snapshot.kbots.keys().collect::<Vec<_>>(),
+            vec![&Id::new(100)]
+        );
+        assert!(snapshot.players[&Id::new(2)].kbots.is_empty());
+        assert_eq!(snapshot.vision.keys().collect::<Vec<_>>(), vec![&0]);
+    }
+}
@@ -0,0 +1,383 @@
+use crate::botdef::{BotDef, MoveClass, MoveType};
+use crate::heightmap_phy::HeightmapPhy;
+use crate::utils::Id;
+use fnv::FnvHashMap;
+use na::{Point3, Vector2};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+///Side of a flowfield cell, in heightmap texels
+pub const CELL_SIZE: usize = 8;
+///Cost of a cell that can't be crossed
+pub const IMPASSABLE: u8 = 255;
+///Flowfields not used for this many frames are dropped from the cache
+const UNUSED_FRAMES_BEFORE_EVICTION: i32 = 100;
+
+const NEIGHBORS: [(i32, i32, f32); 8] = [
+    (-1, -1, std::f32::consts::SQRT_2),
+    (0, -1, 1.0),
+    (1, -1, std::f32::consts::SQRT_2),
+    (-1, 0, 1.0),
+    (1, 0, 1.0),
+    (-1, 1, std::f32::consts::SQRT_2),
+    (0, 1, 1.0),
+    (1, 1, std::f32::consts::SQRT_2),
+];
+
+///Cost for a move class to cross each cell of the map, derived from the slope and the height of the heightmap
+pub struct CostField {
+    pub width: usize,
+    pub height: usize,
+    pub costs: Vec<u8>,
+}
+
+impl CostField {
+    pub fn new(heightmap_phy: &HeightmapPhy, move_class: &MoveClass) -> Self {
+        //At least one cell on maps smaller than a cell
+        let width = (heightmap_phy.width / CELL_SIZE).max(1);
+        let height = (heightmap_phy.height / CELL_SIZE).max(1);
+        let mut costs = Vec::with_capacity(width * height);
+        let water_level = heightmap_phy.data.water_level;
+
+        for j in 0..height {
+            for i in 0..width {
+                let mut blocked = false;
+                let mut max_slope: f32 = 0.0;
+                let mut min_z = f32::MAX;
+                for y in
+                    j * CELL_SIZE..((j + 1) * CELL_SIZE).min(heightmap_phy.height.saturating_sub(1))
+                {
+                    for x in i * CELL_SIZE
+                        ..((i + 1) * CELL_SIZE).min(heightmap_phy.width.saturating_sub(1))
+                    {
+                        let z = heightmap_phy.texels[x + y * heightmap_phy.width];
+                        let right = heightmap_phy.texels[x + 1 + y * heightmap_phy.width];
+                        let down = heightmap_phy.texels[x + (y + 1) * heightmap_phy.width];
+                        let slope = (right - z).abs().max((down - z).abs());
+                        blocked |= !move_class.can_cross(slope, z, water_level);
+                        //Water surface is flat for hovers and boats
+                        if move_class.stand_z(z, water_level) == z {
+                            max_slope = max_slope.max(slope);
+                        }
+                        min_z = min_z.min(z);
+                    }
+                }
+
+                let water_depth = match move_class.move_type {
+                    MoveType::Hover | MoveType::Naval | MoveType::Submarine => 0.0,
+                    _ => (water_level - min_z).max(0.0),
+                };
+                let cost = if blocked {
+                    IMPASSABLE
+                } else {
+                    //Flat dry land costs 1, steep or flooded land up to ~30
+                    (1.0 + (max_slope / move_class.max_slope).min(1.0) * 20.0
+                        + (water_depth / move_class.max_water_depth.max(1.0)).min(1.0) * 10.0)
+                        .min(IMPASSABLE as f32 - 1.0) as u8
+                };
+                costs.push(cost);
+            }
+        }
+
+        CostField {
+            width,
+            height,
+            costs,
+        }
+    }
+
+    pub fn cell_of(&self, x: f32, y: f32) -> (usize, usize) {
+        let i = (x.max(0.0) as usize / CELL_SIZE).min(self.width.saturating_sub(1));
+        let j = (y.max(0.0) as usize / CELL_SIZE).min(self.height.saturating_sub(1));
+        (i, j)
+    }
+
+    fn is_passable(&self, i: i32, j: i32) -> bool {
+        i >= 0
+            && j >= 0
+            && (i as usize) < self.width
+            && (j as usize) < self.height
+            && self.costs[i as usize + j as usize * self.width] != IMPASSABLE
+    }
+}
+
+#[derive(PartialEq)]
+struct OpenCell {
+    cost: f32,
+    index: usize,
+}
+
+impl Eq for OpenCell {}
+
+impl Ord for OpenCell {
+    //Reversed to make BinaryHeap a min-heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .cost
+            .partial_cmp(&self.cost)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for OpenCell {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+///Integration and flow field toward one destination cell
+pub struct FlowField {
+    pub destination: (usize, usize),
+    pub last_used_frame: i32,
+    integration: Vec<f32>,
+    flow: Vec<Vector2<f32>>,
+}
+
+impl FlowField {
+    pub fn new(cost_field: &CostField, destination: (usize, usize), frame_number: i32) -> Self {
+        let (w, h) = (cost_field.width, cost_field.height);
+        let mut integration = vec![f32::INFINITY; w * h];
+
+        //Dijkstra from the destination
+        let mut open = BinaryHeap::new();
+        let dest_index = destination.0 + destination.1 * w;
+        integration[dest_index] = 0.0;
+        open.push(OpenCell {
+            cost: 0.0,
+            index: dest_index,
+        });
+
+        while let Some(OpenCell { cost, index }) = open.pop() {
+            if cost > integration[index] {
+                continue;
+            }
+            let (i, j) = ((index % w) as i32, (index / w) as i32);
+            for &(di, dj, dist) in NEIGHBORS.iter() {
+                let (ni, nj) = (i + di, j + dj);
+                let is_diagonal = di != 0 && dj != 0;
+                //No corner cutting
+                if !cost_field.is_passable(ni, nj)
+                    || (is_diagonal
+                        && (!cost_field.is_passable(i + di, j)
+                            || !cost_field.is_passable(i, j + dj)))
+                {
+                    continue;
+                }
+                let n_index = ni as usize + nj as usize * w;
+                let n_cost = cost + cost_field.costs[n_index] as f32 * dist;
+                if n_cost < integration[n_index] {
+                    integration[n_index] = n_cost;
+                    open.push(OpenCell {
+                        cost: n_cost,
+                        index: n_index,
+                    });
+                }
+            }
+        }
+
+        //Each cell points to its cheapest neighbor
+        let mut flow = Vec::with_capacity(w * h);
+        for j in 0..h as i32 {
+            for i in 0..w as i32 {
+                let mut best = integration[i as usize + j as usize * w];
+                let mut best_dir = Vector2::new(0.0, 0.0);
+                for &(di, dj, _) in NEIGHBORS.iter() {
+                    let (ni, nj) = (i + di, j + dj);
+                    if ni < 0 || nj < 0 || ni as usize >= w || nj as usize >= h {
+                        continue;
+                    }
+                    let n_integration = integration[ni as usize + nj as usize * w];
+                    if n_integration < best {
+                        best = n_integration;
+                        best_dir = Vector2::new(di as f32, dj as f32).normalize();
+                    }
+                }
+                flow.push(best_dir);
+            }
+        }
+
+        FlowField {
+            destination,
+            last_used_frame: frame_number,
+            integration,
+            flow,
+        }
+    }
+
+    ///Bilinear sample of the flow, None when the destination is close or unreachable
+    pub fn flow_at(&self, cost_field: &CostField, x: f32, y: f32) -> Option<Vector2<f32>> {
+        let (i, j) = cost_field.cell_of(x, y);
+        let (di, dj) = (self.destination.0 as i32, self.destination.1 as i32);
+        if (i as i32 - di).abs() <= 1 && (j as i32 - dj).abs() <= 1 {
+            return None;
+        }
+
+        let fx = x / CELL_SIZE as f32 - 0.5;
+        let fy = y / CELL_SIZE as f32 - 0.5;
+        let (i0, j0) = (fx.floor() as i32, fy.floor() as i32);
+        let (tx, ty) = (fx - i0 as f32, fy - j0 as f32);
+
+        let mut acc = Vector2::new(0.0, 0.0);
+        for &(oi, oj, weight) in &[
+            (0, 0, (1.0 - tx) * (1.0 - ty)),
+            (1, 0, tx * (1.0 - ty)),
+            (0, 1, (1.0 - tx) * ty),
+            (1, 1, tx * ty),
+        ] {
+            let (ci, cj) = (i0 + oi, j0 + oj);
+            if ci < 0
+                || cj < 0
+                || ci as usize >= cost_field.width
+                || cj as usize >= cost_field.height
+            {
+                continue;
+            }
+            let index = ci as usize + cj as usize * cost_field.width;
+            if self.integration[index].is_finite() {
+                acc += self.flow[index] * weight;
+            }
+        }
+
+        if acc.magnitude_squared() > 0.0001 {
+            Some(acc.normalize())
+        } else {
+            //Might be stuck on an impassable cell, use the nearest flow
+            let flow = self.flow[i + j * cost_field.width];
+            if flow.magnitude_squared() > 0.0 {
+                Some(flow)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+///Flowfields of the current heightmap, one per botdef and distinct destination cell
+pub struct FlowFieldCache {
+    pub cost_fields: FnvHashMap<Id<BotDef>, CostField>,
+    pub flowfields: FnvHashMap<(Id<BotDef>, (usize, usize)), FlowField>,
+}
+
+impl FlowFieldCache {
+    pub fn new() -> Self {
+        FlowFieldCache {
+            cost_fields: FnvHashMap::default(),
+            flowfields: FnvHashMap::default(),
+        }
+    }
+
+    ///To call when the heightmap or the botdefs change
+    pub fn reset(&mut self) {
+        self.cost_fields.clear();
+        self.flowfields.clear();
+    }
+
+    ///Compute missing cost fields and flowfields for those destinations and evict the old ones
+    pub fn prepare(
+        &mut self,
+        heightmap_phy: &HeightmapPhy,
+        bot_defs: &FnvHashMap<Id<BotDef>, BotDef>,
+        destinations: &[(Id<BotDef>, Point3<f32>)],
+        frame_number: i32,
+    ) {
+        for (botdef_id, target) in destinations {
+            if let Some(botdef) = bot_defs.get(botdef_id) {
+                let cost_field = self
+                    .cost_fields
+                    .entry(*botdef_id)
+                    .or_insert_with(|| CostField::new(heightmap_phy, &botdef.move_class));
+                let cell = cost_field.cell_of(target.x, target.y);
+                let flowfield = self
+                    .flowfields
+                    .entry((*botdef_id, cell))
+                    .or_insert_with(|| FlowField::new(cost_field, cell, frame_number));
+                flowfield.last_used_frame = frame_number;
+            }
+        }
+
+        self.flowfields.retain(|_, flowfield| {
+            frame_number - flowfield.last_used_frame < UNUSED_FRAMES_BEFORE_EVICTION
+        });
+    }
+
+    pub fn flow_at(
+        &self,
+        botdef_id: Id<BotDef>,
+        target: &Point3<f32>,
+        x: f32,
+        y: f32,
+    ) -> Option<Vector2<f32>> {
+        let cost_field = self.cost_fields.get(&botdef_id)?;
+        let cell = cost_field.cell_of(target.x, target.y);
+        self.flowfields
+            .get(&(botdef_id, cell))
+            .and_then(|flowfield| flowfield.flow_at(cost_field, x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Flat dry 64x64 map, with a cliff in the column of cells i = 3 for rows of cells in walled
+    fn walled_map(walled: std::ops::Range<usize>) -> HeightmapPhy {
+        let mut heightmap_phy = HeightmapPhy::new(64, 64);
+        for y in walled.start * CELL_SIZE..walled.end * CELL_SIZE {
+            heightmap_phy.texels[3 * CELL_SIZE + 4 + y * 64] = 100.0;
+        }
+        heightmap_phy
+    }
+
+    #[test]
+    fn cliffs_are_impassable() {
+        let cost_field = CostField::new(&walled_map(0..8), &MoveClass::default());
+        assert_eq!((cost_field.width, cost_field.height), (8, 8));
+        for j in 0..8 {
+            for i in 0..8 {
+                let expected = if i == 3 { IMPASSABLE } else { 1 };
+                assert_eq!(cost_field.costs[i + j * 8], expected, "cell {} {}", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn flow_points_to_the_destination() {
+        let cost_field = CostField::new(&HeightmapPhy::new(64, 64), &MoveClass::default());
+        let flowfield = FlowField::new(&cost_field, (6, 4), 0);
+        let flow = flowfield.flow_at(&cost_field, 12.0, 36.0).unwrap();
+        assert!(flow.x > 0.9, "{:?}", flow);
+        //Next to the destination units steer by themselves
+        assert_eq!(flowfield.flow_at(&cost_field, 44.0, 36.0), None);
+    }
+
+    #[test]
+    fn flow_goes_around_cliffs() {
+        let cost_field = CostField::new(&walled_map(0..6), &MoveClass::default());
+        let flowfield = FlowField::new(&cost_field, (6, 1), 0);
+        //The only way is through the gap at the bottom
+        let flow = flowfield.flow_at(&cost_field, 12.0, 12.0).unwrap();
+        assert!(flow.y > 0.5, "{:?}", flow);
+
+        let walled = CostField::new(&walled_map(0..8), &MoveClass::default());
+        let flowfield = FlowField::new(&walled, (6, 1), 0);
+        assert_eq!(flowfield.flow_at(&walled, 12.0, 12.0), None);
+    }
+
+    #[test]
+    fn maps_smaller_than_a_cell_have_one_cell() {
+        let heightmap_phy = HeightmapPhy::new(4, 4);
+        let cost_field = CostField::new(&heightmap_phy, &MoveClass::default());
+        assert_eq!((cost_field.width, cost_field.height), (1, 1));
+        assert_eq!(cost_field.cell_of(100.0, -5.0), (0, 0));
+
+        let path = "src/asset/botdef/unit_example.json";
+        let botdef: BotDef = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let mut bot_defs = FnvHashMap::default();
+        bot_defs.insert(botdef.id, botdef.clone());
+        let target = Point3::new(3.0, 3.0, 0.0);
+        let mut cache = FlowFieldCache::new();
+        cache.prepare(&heightmap_phy, &bot_defs, &[(botdef.id, target)], 0);
+        assert_eq!(cache.flow_at(botdef.id, &target, 1.0, 1.0), None);
+    }
+}
@@ -4,11 +4,21 @@ use crate::botdef;
 use crate::heightmap_phy;
 use crate::mobile::*;
 use crate::utils::*;
+use crate::weapondef::{TargetLayer, TargetPriority};
 use crossbeam_channel::{Receiver, Sender};
 use fnv::{FnvHashMap, FnvHashSet};
 use na::{Matrix4, Point3, Vector2, Vector3};
 use std::time::Instant;
 
+pub mod flowfield;
+mod validation;
+pub mod vision;
+
+///m between the edges of a guarding unit and the unit it guards
+const GUARD_DISTANCE: f32 = 2.0;
+///Part of the metal_cost of a completed unit left in its wreck
+const WRECK_METAL_RATIO: f64 = 0.5;
+
 pub enum ToFrameServer {
     DataToComputeNextFrame(DataToComputeNextFrame),
 }
@@ -21,6 +31,7 @@ pub struct FrameServerCache {
     pub grid: Vec<Vec<Id<KBot>>>,
     pub small_grid: Vec<Vec<Id<KBot>>>,
     pub heightmap_phy: Option<heightmap_phy::HeightmapPhy>,
+    pub flowfield_cache: flowfield::FlowFieldCache,
 }
 
 impl FrameServerCache {
@@ -39,16 +50,6 @@ impl FrameServerCache {
                             events,
                         }) => {
                             let next_frame = fsc.next_frame(old_frame, events);
-                            // let dur = utils::time(|| {
-                            //     use flate2::write::ZlibEncoder;
-                            //     use flate2::Compression;
-                            //     use std::io::prelude::*;
-                            //     let mut e = ZlibEncoder::new(Vec::new(), Compression::new(1));
-                            //     e.write_all(&vec);
-                            //     let compressed_bytes = e.finish().unwrap();
-                            //     log::info!("Compressed is {} bytes", compressed_bytes.len());
-                            // });
-                            // log::info!("compression took {:?}", dur);
                             let _ = s_from_frame_server.send(FromFrameServer::NewFrame(next_frame));
                         }
                     }
@@ -61,6 +62,7 @@ impl FrameServerCache {
             grid: Vec::new(),
             small_grid: Vec::new(),
             heightmap_phy: None,
+            flowfield_cache: flowfield::FlowFieldCache::new(),
         }
     }
 
@@ -76,6 +78,7 @@ impl FrameServerCache {
             match event {
                 FrameEventFromPlayer::ReplaceFrame(frame) => {
                     self.heightmap_phy = frame.heightmap_phy.clone();
+                    self.flowfield_cache.reset();
                     replacer = Some(frame.clone());
                     log::trace!("Replacing frame");
                 }
@@ -90,35 +93,72 @@ impl FrameServerCache {
         frame.explosions.clear();
         frame.kinematic_projectiles_birth.clear();
         frame.kinematic_projectiles_dead.clear();
+        frame.event_rejections.clear();
+        frame.player_messages.clear();
+
+        let heightmap = self.heightmap_phy.as_ref();
+        let mut event_rejections = Vec::new();
+        let mut events: Vec<FrameEventFromPlayer> = events
+            .into_iter()
+            .filter(
+                |event| match validation::validate(event, &frame, heightmap) {
+                    Ok(()) => true,
+                    Err(rejection) => {
+                        log::warn!("Rejected event {:?}", rejection);
+                        event_rejections.push(rejection);
+                        false
+                    }
+                },
+            )
+            .collect();
+        frame.event_rejections = event_rejections;
 
-        //TODO order event by player then by type before doing any effect. This step should be deterministic
+        //Arrival order depends on the network, only the order within a player and type is kept
+        events.sort_by_key(|event| (event.player_id().map(|id| id.value), event.rank()));
         for event in events {
             match event {
                 FrameEventFromPlayer::MoveOrder {
                     id,
                     selected,
                     mouse_world_pos,
+                    queued,
                 } => {
-                    //TODO Validate selected are owned by id
-                    update_mobile_target(mouse_world_pos, &selected, &mut frame.kbots);
+                    let kbots = &frame.kbots;
+                    let (factories, mobiles): (FnvHashSet<Id<KBot>>, FnvHashSet<Id<KBot>>) =
+                        selected.iter().partition(|selected_raw_id| {
+                            kbots
+                                .get(selected_raw_id)
+                                .map(|kbot| kbot.factory.is_some())
+                                .unwrap_or(false)
+                        });
+                    for factory_id in factories {
+                        if let Some(factory) = frame
+                            .kbots
+                            .get_mut(&factory_id)
+                            .and_then(|kbot| kbot.factory.as_mut())
+                        {
+                            factory.rally_point = Some(Point3::from(mouse_world_pos));
+                        }
+                    }
+                    update_mobile_target(mouse_world_pos, &mobiles, &mut frame.kbots, queued);
                 }
                 FrameEventFromPlayer::ConOrder {
                     id,
                     selected,
                     mouse_world_pos,
                     botdef_id,
+                    queued,
                 } => {
-                    //TODO Validate selected are owned by id && botdef_id is constructable by at least 1 selected
-
                     let botdef = frame.bot_defs.get(&botdef_id).unwrap();
                     let mut m = KBot::new(Point3::from(mouse_world_pos), botdef, id);
+                    m.id = frame.id_generator.next_id();
                     m.team = frame.players.get(&id).unwrap().team;
                     m.con_completed = std::f32::MIN_POSITIVE;
                     m.life = 1;
 
                     for selected_raw_id in &selected {
                         for kbot in frame.kbots.get_mut(selected_raw_id) {
-                            kbot.current_command = Command::Build(m.id.clone())
+                            kbot.order(Command::Build(m.id), queued)
                         }
                     }
 
@@ -131,13 +171,168 @@ impl FrameServerCache {
                     id,
                     selected,
                     to_repair,
+                    queued,
                 } => {
                     for selected_raw_id in &selected {
                         for kbot in frame.kbots.get_mut(selected_raw_id) {
-                            kbot.current_command = Command::Repair(to_repair)
+                            kbot.order(Command::Repair(to_repair), queued)
                         }
                     }
                 }
+                FrameEventFromPlayer::ReclaimOrder {
+                    selected,
+                    to_reclaim,
+                    queued,
+                    ..
+                } => {
+                    if let Some(position) = frame.wrecks.get(&to_reclaim).map(|w| w.position) {
+                        for selected_raw_id in &selected {
+                            if let Some(kbot) = frame.kbots.get_mut(selected_raw_id) {
+                                let command = Command::Reclaim {
+                                    wreck: to_reclaim,
+                                    position,
+                                };
+                                kbot.order(command, queued);
+                            }
+                        }
+                    }
+                }
+                FrameEventFromPlayer::AttackOrder {
+                    selected,
+                    target,
+                    mouse_world_pos,
+                    queued,
+                    ..
+                } => {
+                    for selected_raw_id in &selected {
+                        if let Some(kbot) = frame.kbots.get_mut(selected_raw_id) {
+                            let command = match target {
+                                Some(target) => Command::Attack(target),
+                                None => Command::AttackGround(Point3::from(mouse_world_pos)),
+                            };
+                            kbot.order(command, queued);
+                        }
+                    }
+                }
+                FrameEventFromPlayer::GuardOrder {
+                    selected,
+                    to_guard,
+                    queued,
+                    ..
+                } => {
+                    for selected_raw_id in &selected {
+                        if let Some(kbot) = frame.kbots.get_mut(selected_raw_id) {
+                            if kbot.id != to_guard {
+                                kbot.order(Command::Guard(to_guard), queued);
+                            }
+                        }
+                    }
+                }
+                FrameEventFromPlayer::PatrolOrder {
+                    selected,
+                    mouse_world_pos,
+                    queued,
+                    ..
+                } => {
+                    let waypoint = Point3::from(mouse_world_pos);
+                    for selected_raw_id in &selected {
+                        //Patrol from where the unit will be when the order starts
+                        let start = match frame.kbots.get(selected_raw_id) {
+                            Some(kbot) if queued => kbot.queue_end_position(&frame.kbots),
+                            Some(kbot) => kbot.position,
+                            None => continue,
+                        };
+                        if let Some(kbot) = frame.kbots.get_mut(selected_raw_id) {
+                            let command = Command::Patrol {
+                                waypoints: vec![start, waypoint],
+                                index: 1,
+                            };
+                            kbot.order(command, queued);
+                        }
+                    }
+                }
+                FrameEventFromPlayer::StopOrder { selected, .. } => {
+                    for selected_raw_id in &selected {
+                        if let Some(kbot) = frame.kbots.get_mut(selected_raw_id) {
+                            kbot.order(Command::None, false);
+                            kbot.weapon0_target = None;
+                        }
+                    }
+                }
+                FrameEventFromPlayer::ProductionOrder {
+                    selected,
+                    botdef_id,
+                    amount,
+                    ..
+                } => {
+                    for selected_raw_id in &selected {
+                        if let Some(factory) = frame
+                            .kbots
+                            .get_mut(selected_raw_id)
+                            .and_then(|kbot| kbot.factory.as_mut())
+                        {
+                            if amount >= 0 {
+                                for _ in 0..amount {
+                                    factory.queue.push_back(botdef_id);
+                                }
+                            } else {
+                                for _ in 0..amount.unsigned_abs() {
+                                    //Never remove the unit being built
+                                    let min_len = if factory.building.is_some() { 1 } else { 0 };
+                                    match factory.queue.iter().rposition(|e| *e == botdef_id) {
+                                        Some(index) if index >= min_len => {
+                                            factory.queue.remove(index);
+                                        }
+                                        _ => break,
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                FrameEventFromPlayer::FactoryRepeat {
+                    selected, repeat, ..
+                } => {
+                    for selected_raw_id in &selected {
+                        if let Some(factory) = frame
+                            .kbots
+                            .get_mut(selected_raw_id)
+                            .and_then(|kbot| kbot.factory.as_mut())
+                        {
+                            factory.repeat = repeat;
+                        }
+                    }
+                }
+                FrameEventFromPlayer::ShareOverflow { id, share } => {
+                    if let Some(player) = frame.players.get_mut(&id) {
+                        player.share_overflow = share;
+                    }
+                }
+                FrameEventFromPlayer::Chat { id, target, text } => {
+                    frame.player_messages.push(PlayerMessage {
+                        player_id: id,
+                        target,
+                        kind: PlayerMessageKind::Chat(text),
+                    });
+                }
+                FrameEventFromPlayer::MapPing {
+                    id,
+                    target,
+                    position,
+                } => {
+                    frame.player_messages.push(PlayerMessage {
+                        player_id: id,
+                        target,
+                        kind: PlayerMessageKind::Ping(position),
+                    });
+                }
+                FrameEventFromPlayer::MapDrawing { id, target, points } => {
+                    frame.player_messages.push(PlayerMessage {
+                        player_id: id,
+                        target,
+                        kind: PlayerMessageKind::Drawing(points),
+                    });
+                }
                 _ => {}
             }
         }
@@ -152,6 +347,7 @@ impl FrameServerCache {
             update_units(
                 &mut frame_profiler,
                 &mut frame.kbots,
+                &mut frame.wrecks,
                 &mut frame.kbots_dead,
                 &mut frame.kinematic_projectiles_dead,
                 &mut frame.kinematic_projectiles_birth,
@@ -160,20 +356,31 @@ impl FrameServerCache {
                 &mut arrows,
                 frame.number,
                 &mut frame.players,
+                &mut frame.vision,
                 &mut self.grid,
                 &mut self.small_grid,
                 &mut frame.explosions,
                 &frame.bot_defs,
+                &mut self.flowfield_cache,
+                &mut frame.id_generator,
             );
         }
         frame_profiler.add("0 update_units", start_update_units.elapsed());
         frame_profiler.add("total", start.elapsed());
-        Frame {
+        let frame = Frame {
             number: frame.number,
             frame_profiler,
             arrows,
             ..frame
+        };
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!(
+                "Frame {} hash {:016x}",
+                frame.number,
+                frame.canonical_hash()
+            );
         }
+        frame
     }
 }
 
@@ -181,6 +388,7 @@ pub fn update_mobile_target(
     mouse_world_pos: Vector3<f32>,
     selected: &FnvHashSet<Id<KBot>>,
     kbots: &mut FnvHashMap<Id<KBot>, KBot>,
+    queued: bool,
 ) {
     let selected_count = selected.len();
     let formation_w = (selected_count as f32).sqrt().ceil() as i32;
@@ -202,11 +410,19 @@ pub fn update_mobile_target(
     let mut center = Vector3::new(0.0, 0.0, 0.0);
     let mut tap = 0.0;
 
+    let mut selected: Vec<Id<KBot>> = selected.iter().copied().collect();
+    selected.sort_by_key(|id| id.value);
+
     let mut id_to_pos = Vec::new();
     for &s in selected.iter() {
         if let Some(mobile) = kbots.get(&s) {
-            id_to_pos.push((mobile.id, mobile.position.coords));
-            center += mobile.position.coords;
+            let position = if queued {
+                mobile.queue_end_position(kbots)
+            } else {
+                mobile.position
+            };
+            id_to_pos.push((mobile.id, position.coords));
+            center += position.coords;
             tap += 1.0;
         }
     }
@@ -244,8 +460,7 @@ pub fn update_mobile_target(
     for ((id, _), (spot_id, _)) in id_to_proj.iter().zip(&projected_spot[..]) {
         if let Some(mobile) = kbots.get_mut(id) {
             log::trace!("New order for {}", mobile.id);
-            mobile.move_target = Some(Point3::<f32>::from(spot[*spot_id]));
-            mobile.current_command = Command::None;
+            mobile.order(Command::Move(Point3::<f32>::from(spot[*spot_id])), queued);
         }
     }
 }
@@ -253,6 +468,7 @@ pub fn update_mobile_target(
 pub fn update_units(
     frame_profiler: &mut ProfilerMap,
     kbots: &mut FnvHashMap<Id<KBot>, KBot>,
+    wrecks: &mut FnvHashMap<Id<Wreck>, Wreck>,
     kbots_dead: &mut FnvHashSet<Id<KBot>>,
     kinematic_projectiles_dead: &mut Vec<Id<KinematicProjectile>>,
     kinematic_projectiles_birth: &mut Vec<KinematicProjectile>,
@@ -261,11 +477,86 @@ pub fn update_units(
     arrows: &mut Vec<Arrow>,
     frame_count: i32,
     players: &mut FnvHashMap<Id<Player>, Player>,
+    vision: &mut FnvHashMap<u8, vision::VisionGrid>,
     grid: &mut Vec<Vec<Id<KBot>>>,
     small_grid: &mut Vec<Vec<Id<KBot>>>,
     explosions: &mut Vec<ExplosionEvent>,
     bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+    flowfield_cache: &mut flowfield::FlowFieldCache,
+    id_generator: &mut IdGenerator,
 ) {
+    //Factory production, before the grid so that spawned units are in it
+    let start = std::time::Instant::now();
+    {
+        let mut rallies = Vec::new();
+        let mut spawns = Vec::new();
+        for factory_kbot in sorted_ids(kbots).iter().map(|id| &kbots[id]) {
+            if factory_kbot.con_completed < 1.0 {
+                continue;
+            }
+            if let Some(factory) = &factory_kbot.factory {
+                let building = factory.building.and_then(|id| kbots.get(&id));
+                match building {
+                    Some(unit) if unit.con_completed >= 1.0 => {
+                        rallies.push((factory_kbot.id, unit.id, factory.rally_point));
+                    }
+                    Some(_) => {}
+                    None => {
+                        if let Some(botdef) = factory.queue.front().and_then(|id| bot_defs.get(id))
+                        {
+                            let factory_botdef = bot_defs.get(&factory_kbot.botdef_id).unwrap();
+                            let exit = factory_kbot.position.coords
+                                + factory_kbot.dir.normalize()
+                                    * (factory_botdef.radius + botdef.radius + 0.5);
+                            let x = exit.x.max(0.0).min(heightmap_phy.width as f32 - 1.0);
+                            let y = exit.y.max(0.0).min(heightmap_phy.height as f32 - 1.0);
+                            let z = botdef
+                                .move_class
+                                .stand_z(heightmap_phy.z(x, y), heightmap_phy.data.water_level);
+                            let mut m =
+                                KBot::new(Point3::new(x, y, z), botdef, factory_kbot.player_id);
+                            m.id = id_generator.next_id();
+                            m.team = factory_kbot.team;
+                            m.con_completed = f32::MIN_POSITIVE;
+                            m.life = 1;
+                            spawns.push((factory_kbot.id, m));
+                        }
+                    }
+                }
+            }
+        }
+
+        for (factory_id, unit_id, rally_point) in rallies {
+            let factory = kbots
+                .get_mut(&factory_id)
+                .and_then(|kbot| kbot.factory.as_mut())
+                .unwrap();
+            factory.building = None;
+            if let Some(done) = factory.queue.pop_front() {
+                if factory.repeat {
+                    factory.queue.push_back(done);
+                }
+            }
+            if let (Some(rally_point), Some(unit)) = (rally_point, kbots.get_mut(&unit_id)) {
+                unit.order(Command::Move(rally_point), false);
+            }
+        }
+
+        for (factory_id, m) in spawns {
+            if let Some(factory) = kbots
+                .get_mut(&factory_id)
+                .and_then(|kbot| kbot.factory.as_mut())
+            {
+                factory.building = Some(m.id);
+            }
+            if let Some(player) = players.get_mut(&m.player_id) {
+                player.kbots.insert(m.id);
+            }
+            kbots.insert(m.id, m);
+        }
+    }
+    frame_profiler.add("00  factory", start.elapsed());
+
     let start = std::time::Instant::now();
     let cell_size = 4;
     let grid_w = (heightmap_phy.width / cell_size) as usize;
@@ -285,7 +576,11 @@ pub fn update_units(
             + (y as usize / cell_size as usize) as usize * grid_w
     };
 
-    for (&id, mobile) in kbots.iter() {
+    //Every loop goes through kbots in this order, so that the next frame is deterministic
+    let kbot_ids = sorted_ids(kbots);
+
+    for &id in kbot_ids.iter() {
+        let mobile = &kbots[&id];
         let gp = grid_pos(mobile);
         grid[gp].push(id);
 
@@ -308,6 +603,11 @@ pub fn update_units(
 
     frame_profiler.add("01  grid", start.elapsed());
 
+    //Sight, radar and jammer coverage of each team, from the positions the grid holds
+    let start = std::time::Instant::now();
+    vision::update_vision(vision, kbots, players, bot_defs, heightmap_phy);
+    frame_profiler.add("01a vision", start.elapsed());
+
     //AABB for kbot and proj
     {
         let start = std::time::Instant::now();
@@ -349,7 +649,8 @@ pub fn update_units(
             indices
         }
 
-        for (id, kbot) in kbots.iter() {
+        for id in kbot_ids.iter() {
+            let kbot = &kbots[id];
             let radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
             for index in index_aabb(kbot.position.coords, radius, cell_size, grid_w).iter() {
                 small_grid[*index].push(*id);
@@ -361,9 +662,25 @@ pub fn update_units(
         let start = std::time::Instant::now();
         //Projectile move compute
         {
-            for proj in kinematic_projectiles.values_mut() {
+            struct Detonation {
+                position: Vector3<f32>,
+                direct_hit: Option<Id<KBot>>,
+                botdef_id: Id<botdef::BotDef>,
+                team: u8,
+            }
+            let mut detonations = Vec::new();
+
+            for proj_id in sorted_ids(kinematic_projectiles) {
+                let proj = kinematic_projectiles.get_mut(&proj_id).unwrap();
                 let current_pos = proj.position_at(frame_count - 1);
                 let next_pos = proj.position_at(frame_count);
+                let weapon = bot_defs
+                    .get(&proj.botdef_id)
+                    .and_then(|botdef| botdef.weapon0.as_ref());
+                let friendly_fire = weapon.map(|weapon| weapon.friendly_fire).unwrap_or(false);
+                let underwater_weapon = weapon
+                    .map(|weapon| weapon.can_target_underwater)
+                    .unwrap_or(false);
 
                 {
                     //Slowly interpolate to not miss collisions
@@ -391,7 +708,10 @@ pub fn update_units(
                         // &small_grid_kbot[index];
 
                         'bot_test: for kbot_id in kbots_in_proximity.iter() {
-                            let kbot = kbots.get_mut(kbot_id).unwrap();
+                            let kbot = kbots.get(kbot_id).unwrap();
+                            if kbot.team == proj.team && !friendly_fire {
+                                continue;
+                            }
                             let distance_to_target =
                                 (kbot.position.coords - current_interp).magnitude();
 
@@ -399,16 +719,39 @@ pub fn update_units(
                             let kbot_radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
                             if distance_to_target < (kbot_radius + proj.radius) {
                                 //Colission between Kbot and projectile
-                                kbot.life = (kbot.life - 10).max(0);
-                                proj.death_frame = frame_count;
-                                explosions.push(ExplosionEvent {
-                                    position: Point3::from(current_interp),
-                                    size: 0.5,
-                                    life_time: 0.8,
+                                detonations.push(Detonation {
+                                    position: current_interp,
+                                    direct_hit: Some(*kbot_id),
+                                    botdef_id: proj.botdef_id,
+                                    team: proj.team,
                                 });
+                                proj.death_frame = frame_count;
                                 break 'interp;
                             }
                         }
+
+                        //Colission between ground and projectile, or water for projectiles
+                        //coming from above that aren't made to go under
+                        let water_level = heightmap_phy.data.water_level;
+                        let into_water = !underwater_weapon
+                            && current_interp.z < water_level
+                            && current_pos.z >= water_level;
+                        if into_water
+                            || current_interp.z
+                                < heightmap_phy.z_linear(current_interp.x, current_interp.y)
+                        {
+                            if into_water {
+                                current_interp.z = water_level;
+                            }
+                            detonations.push(Detonation {
+                                position: current_interp,
+                                direct_hit: None,
+                                botdef_id: proj.botdef_id,
+                                team: proj.team,
+                            });
+                            proj.death_frame = frame_count;
+                            break 'interp;
+                        }
                     }
                 }
 
@@ -420,6 +763,61 @@ pub fn update_units(
             for r in kinematic_projectiles_dead.iter() {
                 kinematic_projectiles.remove(&r);
             }
+
+            //Direct hit takes the full damage, the area of effect falls off linearly
+            for detonation in detonations.iter() {
+                let weapon = match bot_defs
+                    .get(&detonation.botdef_id)
+                    .and_then(|botdef| botdef.weapon0.as_ref())
+                {
+                    Some(weapon) => weapon,
+                    None => continue,
+                };
+
+                let mut damages = Vec::new();
+                if let Some(direct_hit) = detonation.direct_hit {
+                    damages.push((direct_hit, 1.0));
+                }
+                if weapon.area_of_effect > 0.0 {
+                    for kbot_id in ids_in_range(
+                        kbots,
+                        grid,
+                        cell_size,
+                        grid_w,
+                        grid_h,
+                        &detonation.position,
+                        weapon.area_of_effect,
+                    ) {
+                        let kbot = kbots.get(&kbot_id).unwrap();
+                        if Some(kbot_id) == detonation.direct_hit
+                            || (kbot.team == detonation.team && !weapon.friendly_fire)
+                        {
+                            continue;
+                        }
+                        let kbot_radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
+                        let distance = ((kbot.position.coords - detonation.position).magnitude()
+                            - kbot_radius)
+                            .max(0.0);
+                        if distance < weapon.area_of_effect {
+                            damages.push((kbot_id, 1.0 - distance / weapon.area_of_effect));
+                        }
+                    }
+                }
+
+                for (kbot_id, falloff) in damages {
+                    let kbot = kbots.get_mut(&kbot_id).unwrap();
+                    let armor_class = &bot_defs.get(&kbot.botdef_id).unwrap().armor_class;
+                    let damage =
+                        weapon.damage as f32 * weapon.armor_multiplier(armor_class) * falloff;
+                    kbot.life = (kbot.life - damage.round() as i32).max(0);
+                }
+
+                explosions.push(ExplosionEvent {
+                    position: Point3::from(detonation.position),
+                    size: weapon.area_of_effect.max(0.5),
+                    life_time: 0.8,
+                });
+            }
         }
         frame_profiler.add("04  proj move", start.elapsed());
     }
@@ -446,68 +844,169 @@ pub fn update_units(
         frame_profiler.add("05  id_to_team", start.elapsed());
 
         let start = std::time::Instant::now();
-        struct Shot {
+        struct Aim {
             bot: Id<KBot>,
-            target: Vector3<f32>,
+            target_id: Option<Id<KBot>>,
+            target: Option<Vector3<f32>>,
         };
 
-        let mut shots = Vec::new();
+        let mut aims = Vec::new();
 
-        for (me, me_kbot) in kbots.iter() {
+        for me in kbot_ids.iter() {
+            let me_kbot = &kbots[me];
             if me_kbot.con_completed == 1.0 {
-                let grid_pos = grid_pos(me_kbot);
-
+                let botdef = bot_defs.get(&me_kbot.botdef_id).unwrap();
+                let weapon = match &botdef.weapon0 {
+                    Some(weapon) => weapon,
+                    None => continue,
+                };
                 let my_team = id_to_team.get(me).unwrap();
+                let water_level = heightmap_phy.data.water_level;
+                //What the weapon can't aim at is never in range
+                let in_range = |ennemy_kbot: &KBot| {
+                    weapon.can_target(target_layer(ennemy_kbot, bot_defs, water_level))
+                        && (ennemy_kbot.position.coords - me_kbot.position.coords).magnitude()
+                            < weapon.range
+                };
 
-                // let ennemies_in_cell = &team_to_ennemy_grid.get(my_team).unwrap()[grid_pos];
-
-                let mut ennemies_in_cell: Vec<Id<KBot>> = grid[grid_pos].clone();
-                let to_remove = ennemies_in_cell.iter().position(|e| e == me).unwrap();
-                ennemies_in_cell.remove(to_remove);
+                //Commanded targets come first, even out of the usual priorities
+                let commanded_target = match &me_kbot.current_command {
+                    Command::Attack(target) => kbots
+                        .get(target)
+                        .filter(|ennemy_kbot| in_range(ennemy_kbot))
+                        .map(|ennemy_kbot| (Some(ennemy_kbot.id), ennemy_kbot.position.coords)),
+                    Command::AttackGround(position) => {
+                        if weapon.can_target_ground
+                            && (position.coords - me_kbot.position.coords).magnitude()
+                                < weapon.range
+                        {
+                            Some((None, position.coords))
+                        } else {
+                            None
+                        }
+                    }
+                    //Defend the guarded unit by shooting at what it shoots
+                    Command::Guard(guarded) => kbots
+                        .get(guarded)
+                        .and_then(|guarded| guarded.weapon0_target)
+                        .and_then(|target| kbots.get(&target))
+                        .filter(|ennemy_kbot| in_range(ennemy_kbot))
+                        .map(|ennemy_kbot| (Some(ennemy_kbot.id), ennemy_kbot.position.coords)),
+                    _ => None,
+                };
+                if let Some((target_id, target)) = commanded_target {
+                    aims.push(Aim {
+                        bot: *me,
+                        target_id,
+                        target: Some(target),
+                    });
+                    continue;
+                }
 
-                let can_shoot =// *my_team == 0&&
-                 frame_count - me_kbot.frame_last_shot > me_kbot.reload_frame_count;
-                if can_shoot {
-                    //We choose the first ennemy in the cell, we could sort by distance or something else here
-                    //TODO Configurable strategy
-                    'meloop: for potential_ennemy in ennemies_in_cell {
-                        if id_to_team.get(&potential_ennemy).unwrap() != my_team {
+                let current_target = me_kbot
+                    .weapon0_target
+                    .and_then(|target| kbots.get(&target))
+                    .filter(|ennemy_kbot| ennemy_kbot.life > 0 && in_range(ennemy_kbot));
+
+                let target = match (&weapon.target_priority, current_target) {
+                    (TargetPriority::KeepCurrent, Some(ennemy_kbot)) => Some(ennemy_kbot),
+                    (target_priority, _) => {
+                        let mut best: Option<(&KBot, (f32, f32))> = None;
+                        for potential_ennemy in ids_in_range(
+                            kbots,
+                            grid,
+                            cell_size,
+                            grid_w,
+                            grid_h,
+                            &me_kbot.position.coords,
+                            weapon.range,
+                        ) {
                             let ennemy_kbot = kbots.get(&potential_ennemy).unwrap();
-                            if (ennemy_kbot.position.coords - me_kbot.position.coords).magnitude()
-                                < 6.0
+                            if id_to_team.get(&potential_ennemy).unwrap() == my_team
+                                || !in_range(ennemy_kbot)
                             {
-                                shots.push(Shot {
-                                    bot: *me,
-                                    target: ennemy_kbot.position.coords,
-                                });
-                                break 'meloop;
+                                continue;
+                            }
+
+                            let score = target_score(
+                                target_priority,
+                                me_kbot,
+                                ennemy_kbot,
+                                bot_defs.get(&ennemy_kbot.botdef_id).unwrap(),
+                            );
+                            if best
+                                .map(|(_, best_score)| score < best_score)
+                                .unwrap_or(true)
+                            {
+                                best = Some((ennemy_kbot, score));
                             }
                         }
+                        best.map(|(ennemy_kbot, _)| ennemy_kbot)
                     }
-                }
+                };
+
+                aims.push(Aim {
+                    bot: *me,
+                    target_id: target.map(|ennemy_kbot| ennemy_kbot.id),
+                    target: target.map(|ennemy_kbot| ennemy_kbot.position.coords),
+                });
             }
         }
 
-        for shot in shots.iter() {
-            let kbot = kbots.get_mut(&shot.bot).unwrap();
-            let dir = (shot.target - kbot.position.coords).normalize();
-
-            kbot.weapon0_dir = dir;
-            kbot.frame_last_shot = frame_count;
-            let kbot_radius = bot_defs.get(&kbot.botdef_id).unwrap().radius;
-            let proj = KinematicProjectile {
-                id: rand_id(),
-                birth_frame: frame_count,
-                death_frame: frame_count + 6,
-                position_at_birth: kbot.position + dir * (kbot_radius + 0.25 + 0.01),
-                speed_per_frame_at_birth: dir * 2.0 + Vector3::new(0.0, 0.0, 0.2),
-                accel_per_frame: Vector3::new(0.0, 0.0, -0.08),
-                radius: 0.25,
-                position_cache: Vec::new(),
-                speed_cache: Vec::new(),
+        //Turn turrets toward their target, or back to the front, and fire when aligned
+        let aim_tolerance = 0.1;
+        for aim in aims.iter() {
+            let kbot = kbots.get_mut(&aim.bot).unwrap();
+            let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+            let weapon = botdef.weapon0.as_ref().unwrap();
+            kbot.weapon0_target = aim.target_id;
+            let target = aim.target;
+
+            let wanted_angle: Angle = match target {
+                Some(target) => (target - kbot.position.coords).xy().into(),
+                None => kbot.angle,
             };
-            kinematic_projectiles_birth.push(proj.clone());
-            kinematic_projectiles.insert(proj.id, proj);
+            let current_angle: Angle = kbot.weapon0_dir.xy().into();
+            let turn = (wanted_angle - current_angle)
+                .rad
+                .max(-weapon.turret_turn_rate)
+                .min(weapon.turret_turn_rate);
+            let from_front = ((current_angle + Angle::new(turn)) - kbot.angle)
+                .rad
+                .max(-weapon.firing_arc)
+                .min(weapon.firing_arc);
+            let new_angle = kbot.angle + Angle::new(from_front);
+            let new_dir: Vector2<f32> = new_angle.into();
+            kbot.weapon0_dir = Vector3::new(new_dir.x, new_dir.y, 0.0);
+
+            let can_shoot = frame_count - kbot.frame_last_shot > weapon.reload_frame_count
+                && (wanted_angle - new_angle).rad.abs() < aim_tolerance;
+
+            if let (Some(target), true) = (target, can_shoot) {
+                kbot.frame_last_shot = frame_count;
+                let dir = kbot.weapon0_dir;
+                //Muzzle on top of the unit, so it doesn't hit the ground right away
+                let position_at_birth = kbot.position
+                    + dir * (botdef.radius + weapon.projectile_radius + 0.01)
+                    + Vector3::new(0.0, 0.0, botdef.radius);
+                let (speed_per_frame_at_birth, flight_frames) =
+                    weapon.launch_speed(target - position_at_birth.coords);
+                let proj = KinematicProjectile {
+                    id: id_generator.next_id(),
+                    birth_frame: frame_count,
+                    death_frame: frame_count + flight_frames.ceil() as i32 + 2,
+                    position_at_birth,
+                    speed_per_frame_at_birth,
+                    accel_per_frame: Vector3::new(0.0, 0.0, -weapon.projectile_gravity),
+                    radius: weapon.projectile_radius,
+                    botdef_id: kbot.botdef_id,
+                    team: kbot.team,
+                    position_cache: Vec::new(),
+                    speed_cache: Vec::new(),
+                };
+                kinematic_projectiles_birth.push(proj.clone());
+                kinematic_projectiles.insert(proj.id, proj);
+            }
         }
         frame_profiler.add("07  kbot_fire", start.elapsed());
     }
@@ -515,6 +1014,15 @@ pub fn update_units(
     let start = std::time::Instant::now();
     let mobiles2 = kbots.clone();
 
+    //Get a bit closer than the weapon range, so the target can't escape right away
+    let attack_range = |mobile: &KBot| {
+        bot_defs
+            .get(&mobile.botdef_id)
+            .and_then(|botdef| botdef.weapon0.as_ref())
+            .map(|weapon| weapon.range * 0.9)
+            .unwrap_or(0.0)
+    };
+
     struct BuildPart {
         amount: f64,
         repair: bool,
@@ -523,11 +1031,19 @@ pub fn update_units(
         to: Id<KBot>,
     }
     let mut build_throughputs = Vec::new();
+    //Player, wreck and metal per frame
+    let mut reclaims: Vec<(Id<Player>, Id<Wreck>, f64)> = Vec::new();
     //Build compute
-    for (id, mobile) in kbots.iter_mut() {
+    for id in kbot_ids.iter() {
+        let mobile = kbots.get_mut(id).unwrap();
         if mobile.con_completed >= 1.0 {
             // Look at current_command, change move_target if necessary
             match mobile.current_command {
+                Command::Move(_) => {
+                    if mobile.move_target.is_none() {
+                        mobile.next_command();
+                    }
+                }
                 Command::Build(to_build) => match mobiles2.get(&to_build) {
                     Some(to_build) => {
                         if to_build.con_completed < 1.0 {
@@ -547,11 +1063,10 @@ pub fn update_units(
                                 mobile.move_target = Some(to_build.position);
                             }
                         } else {
-                            mobile.current_command = Command::None;
-                            mobile.move_target = None;
+                            mobile.next_command();
                         }
                     }
-                    None => {}
+                    None => mobile.next_command(),
                 },
                 Command::Repair(to_build) => match mobiles2.get(&to_build) {
                     Some(to_build) => {
@@ -575,17 +1090,190 @@ pub fn update_units(
                                 mobile.move_target = Some(to_build.position);
                             }
                         } else {
-                            mobile.current_command = Command::None;
+                            mobile.next_command();
+                        }
+                    }
+                    None => mobile.next_command(),
+                },
+                Command::Reclaim { wreck, position } => {
+                    let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                    if wrecks.contains_key(&wreck) && botdef.build_power > 0.0 {
+                        let dist = (position.coords - mobile.position.coords).magnitude();
+                        if dist <= botdef.build_dist {
                             mobile.move_target = None;
+                            reclaims.push((mobile.player_id, wreck, botdef.build_power as f64));
+                        } else {
+                            mobile.move_target = Some(position);
                         }
+                    } else {
+                        mobile.next_command();
+                    }
+                }
+                Command::Attack(target) => match mobiles2.get(&target) {
+                    Some(target) => {
+                        let dist = (target.position.coords - mobile.position.coords).magnitude();
+                        mobile.move_target = if dist <= attack_range(mobile) {
+                            None
+                        } else {
+                            Some(target.position)
+                        };
+                    }
+                    None => {
+                        mobile.next_command();
                     }
-                    None => {}
                 },
-                _ => {}
+                Command::AttackGround(position) => {
+                    let dist = (position.coords - mobile.position.coords).magnitude();
+                    mobile.move_target = if dist <= attack_range(mobile) {
+                        None
+                    } else {
+                        Some(position)
+                    };
+                }
+                Command::Guard(guarded) => match mobiles2.get(&guarded) {
+                    Some(guarded) => {
+                        let dist = (guarded.position.coords - mobile.position.coords).magnitude();
+                        let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                        let guarded_botdef = bot_defs.get(&guarded.botdef_id).unwrap();
+                        mobile.move_target =
+                            if dist <= botdef.radius + guarded_botdef.radius + GUARD_DISTANCE {
+                                None
+                            } else {
+                                Some(guarded.position)
+                            };
+                    }
+                    None => {
+                        mobile.next_command();
+                    }
+                },
+                Command::Patrol {
+                    ref waypoints,
+                    ref mut index,
+                } => {
+                    //Arrived at the current waypoint, go to the next one
+                    if mobile.move_target.is_none() && !waypoints.is_empty() {
+                        *index = (*index + 1) % waypoints.len();
+                        mobile.move_target = Some(waypoints[*index]);
+                    }
+                }
+                Command::None => {}
+            }
+
+            if let Some(building) = mobile
+                .factory
+                .as_ref()
+                .and_then(|factory| factory.building)
+                .and_then(|id| mobiles2.get(&id))
+            {
+                if building.con_completed < 1.0 {
+                    let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+                    build_throughputs.push(BuildPart {
+                        amount: botdef.build_power as f64,
+                        repair: false,
+                        player: mobile.player_id,
+                        from: *id,
+                        to: building.id,
+                    })
+                }
             }
         }
     }
 
+    //Resource flows of this frame, rolled into the player stats at the end
+    struct ResourceFlow {
+        metal: ResourceStats,
+        energy: ResourceStats,
+    }
+    let mut flows: FnvHashMap<Id<Player>, ResourceFlow> = players
+        .keys()
+        .map(|id| {
+            (
+                *id,
+                ResourceFlow {
+                    metal: ResourceStats::default(),
+                    energy: ResourceStats::default(),
+                },
+            )
+        })
+        .collect();
+
+    //Resource production and storage
+    {
+        //Each metal spot is extracted by the closest extractor in range
+        let mut extracted = FnvHashMap::<Id<KBot>, f64>::default();
+        let max_extraction_radius = bot_defs
+            .values()
+            .map(|botdef| botdef.extraction_radius)
+            .fold(0.0, f32::max);
+        for spot in heightmap_phy.data.metal_spots.iter() {
+            let spot_pos = Vector2::new(spot.x as f32, spot.y as f32);
+            let extractor = ids_in_range(
+                kbots,
+                grid,
+                cell_size,
+                grid_w,
+                grid_h,
+                &Vector3::new(spot_pos.x, spot_pos.y, 0.0),
+                max_extraction_radius,
+            )
+            .into_iter()
+            .filter_map(|id| {
+                let kbot = kbots.get(&id).unwrap();
+                let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                let distance = (kbot.position.coords.xy() - spot_pos).magnitude();
+                if kbot.con_completed >= 1.0 && distance <= botdef.extraction_radius {
+                    Some((id, distance))
+                } else {
+                    None
+                }
+            })
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+            if let Some((id, _)) = extractor {
+                *extracted.entry(id).or_insert(0.0) += spot.metal_per_frame as f64;
+            }
+        }
+
+        for player in players.values_mut() {
+            player.metal_storage = BASE_STORAGE;
+            player.energy_storage = BASE_STORAGE;
+        }
+
+        for kbot in kbot_ids.iter().map(|id| &kbots[id]) {
+            if kbot.con_completed >= 1.0 {
+                let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+                if let (Some(player), Some(flow)) = (
+                    players.get_mut(&kbot.player_id),
+                    flows.get_mut(&kbot.player_id),
+                ) {
+                    let metal_production = botdef.metal_production as f64
+                        + extracted.get(&kbot.id).cloned().unwrap_or(0.0);
+                    let energy_production = botdef.energy_production as f64;
+                    player.metal += metal_production - botdef.metal_upkeep as f64;
+                    player.energy += energy_production - botdef.energy_upkeep as f64;
+                    player.metal_storage += botdef.metal_storage as f64;
+                    player.energy_storage += botdef.energy_storage as f64;
+
+                    flow.metal.production += metal_production;
+                    flow.metal.upkeep += botdef.metal_upkeep as f64;
+                    flow.energy.production += energy_production;
+                    flow.energy.upkeep += botdef.energy_upkeep as f64;
+                }
+            }
+        }
+
+        for player in players.values_mut() {
+            player.metal = player.metal.max(0.0);
+            player.energy = player.energy.max(0.0);
+        }
+    }
+
+    for (player_id, metal) in reclaim(&reclaims, wrecks, players) {
+        if let Some(flow) = flows.get_mut(&player_id) {
+            flow.metal.reclaim += metal;
+        }
+    }
+
     //Compute resource usage for each player
     struct ResourceUsage {
         metal: f64,
@@ -605,9 +1293,17 @@ pub fn update_units(
             metal: 0.0,
             energy: 0.0,
         });
+        //Energy is spent at the same pace as metal, so both are done at the same time
+        let botdef = bot_defs.get(&kbots.get(to).unwrap().botdef_id).unwrap();
+        let energy_per_metal = botdef.energy_per_metal();
         *stat = ResourceUsage {
             metal: stat.metal + if *repair { 0.0 } else { *amount as f64 },
-            energy: 0.0,
+            energy: stat.energy
+                + if *repair {
+                    0.0
+                } else {
+                    *amount as f64 * energy_per_metal
+                },
         };
     }
     //Compute what proportion of usage is usable without negative stock
@@ -624,19 +1320,24 @@ pub fn update_units(
             let metal_needed = ru.metal;
             let energy_needed = ru.energy;
 
-            //TODO Energy count too
-            let metal_prop_max: f64 = (current_metal_stock / metal_needed)
+            //The most lacking resource stalls every build
+            let prop_max: f64 = (current_metal_stock / metal_needed)
                 .min(current_energy_stock / energy_needed)
                 .min(1.0);
             usage_props_max.insert(
                 *player_id,
                 ResourceUsagePropMax {
-                    metal: metal_prop_max,
-                    energy: 1.0,
+                    metal: prop_max,
+                    energy: prop_max,
                 },
             );
 
-            player.metal = (player.metal - metal_needed * metal_prop_max).max(0.0);
+            player.metal = (player.metal - metal_needed * prop_max).max(0.0);
+            player.energy = (player.energy - energy_needed * prop_max).max(0.0);
+
+            let flow = flows.get_mut(player_id).unwrap();
+            flow.metal.build += metal_needed * prop_max;
+            flow.energy.build += energy_needed * prop_max;
         }
     }
 
@@ -658,6 +1359,7 @@ pub fn update_units(
     {
         let kbot = kbots.get_mut(&to).unwrap();
         let botdef = bot_defs.get(&kbot.botdef_id).unwrap();
+        let energy_per_metal = botdef.energy_per_metal();
         let metal_available = amount * usage_props_max.get(&player).unwrap().metal;
         let metal_needed = if repair {
             0.0
@@ -674,41 +1376,134 @@ pub fn update_units(
             let metal_not_used = metal_available - metal_needed;
             metal_used = metal_available - metal_not_used;
             if !repair {
-                players.get_mut(&player).unwrap().metal += metal_not_used;
+                let player = players.get_mut(&player).unwrap();
+                player.metal += metal_not_used;
+                player.energy += metal_not_used * energy_per_metal;
+                let flow = flows.get_mut(&player.id).unwrap();
+                flow.metal.build -= metal_not_used;
+                flow.energy.build -= metal_not_used * energy_per_metal;
                 kbot.con_completed = 1.0;
             }
         }
-        let lambda = if repair {
-            amount as f32
+        //Units costing no metal are built and repaired at once
+        let lambda = if botdef.metal_cost == 0 {
+            1.0
+        } else if repair {
+            amount as f32 / botdef.metal_cost as f32
         } else {
-            metal_used as f32
-        } / botdef.metal_cost as f32;
+            metal_used as f32 / botdef.metal_cost as f32
+        };
         kbot.life = ((kbot.life as f32 + lambda * botdef.max_life as f32).ceil() as i32)
             .min((botdef.max_life as f32 * kbot.con_completed).ceil() as i32);
     }
 
+    //Overflow goes to allies with storage room left if shared, the rest is wasted
+    {
+        let teams: FnvHashSet<u8> = players.values().map(|p| p.team).collect();
+        for team in teams {
+            let mut members: Vec<Id<Player>> = players
+                .values()
+                .filter(|p| p.team == team)
+                .map(|p| p.id)
+                .collect();
+            members.sort_by_key(|id| id.value);
+
+            let shares: Vec<bool> = members
+                .iter()
+                .map(|id| players[id].share_overflow)
+                .collect();
+            let mut metal: Vec<f64> = members.iter().map(|id| players[id].metal).collect();
+            let metal_storage: Vec<f64> =
+                members.iter().map(|id| players[id].metal_storage).collect();
+            let mut energy: Vec<f64> = members.iter().map(|id| players[id].energy).collect();
+            let energy_storage: Vec<f64> = members
+                .iter()
+                .map(|id| players[id].energy_storage)
+                .collect();
+
+            let metal_spread = spread_overflow(&mut metal, &metal_storage, &shares);
+            let energy_spread = spread_overflow(&mut energy, &energy_storage, &shares);
+
+            for (i, id) in members.iter().enumerate() {
+                let player = players.get_mut(id).unwrap();
+                player.metal = metal[i];
+                player.energy = energy[i];
+
+                let flow = flows.get_mut(id).unwrap();
+                let (shared_in, shared_out, wasted) = metal_spread[i];
+                flow.metal.shared_in += shared_in;
+                flow.metal.shared_out += shared_out;
+                flow.metal.wasted += wasted;
+                let (shared_in, shared_out, wasted) = energy_spread[i];
+                flow.energy.shared_in += shared_in;
+                flow.energy.shared_out += shared_out;
+                flow.energy.wasted += wasted;
+            }
+        }
+
+        for (id, player) in players.iter_mut() {
+            let flow = flows.get(id).unwrap();
+            player.metal_stats.roll(&flow.metal);
+            player.energy_stats.roll(&flow.energy);
+        }
+    }
+
     frame_profiler.add("01b build compute", start.elapsed());
 
+    //Flowfield compute
+    let start = std::time::Instant::now();
+    {
+        let destinations: Vec<(Id<botdef::BotDef>, Point3<f32>)> = kbot_ids
+            .iter()
+            .map(|id| &kbots[id])
+            .filter(|mobile| mobile.con_completed >= 1.0)
+            //Air units fly straight
+            .filter(|mobile| !bot_defs[&mobile.botdef_id].is_air())
+            .filter_map(|mobile| mobile.move_target.map(|target| (mobile.botdef_id, target)))
+            .collect();
+        flowfield_cache.prepare(heightmap_phy, bot_defs, &destinations, frame_count);
+    }
+    frame_profiler.add("01c flowfield", start.elapsed());
+
     //Movement compute
+    let start = std::time::Instant::now();
 
-    for (id, mobile) in kbots.iter_mut() {
+    for id in kbot_ids.iter() {
+        let mobile = kbots.get_mut(id).unwrap();
         if mobile.con_completed >= 1.0 {
+            let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
+            //Air units only avoid each other, in the air
+            if let Some(flight) = &botdef.flight {
+                let neighbors_id: Vec<Id<KBot>> = grid[grid_pos(mobile)]
+                    .iter()
+                    .filter(|other| *other != id && airborne(&mobiles2[other], bot_defs))
+                    .copied()
+                    .collect();
+                let avoidance_force = if mobile.grounded {
+                    Vector2::new(0.0, 0.0)
+                } else {
+                    avoid_neighbors_force(mobile, neighbors_id, &mobiles2) * 0.3
+                };
+                fly(mobile, botdef, flight, avoidance_force, heightmap_phy);
+                continue;
+            }
             if mobile.speed.magnitude_squared() > 0.001
                 || mobile.move_target.is_some()
                 || !mobile.grounded
             {
-                let botdef = bot_defs.get(&mobile.botdef_id).unwrap();
                 let grid_pos = grid_pos(mobile);
-                let mut neighbors_id: Vec<Id<KBot>> = grid[grid_pos].clone();
-                let to_remove = neighbors_id.iter().position(|e| e == id).unwrap();
-                neighbors_id.remove(to_remove);
+                let neighbors_id: Vec<Id<KBot>> = grid[grid_pos]
+                    .iter()
+                    .filter(|other| *other != id && !airborne(&mobiles2[other], bot_defs))
+                    .copied()
+                    .collect();
 
                 let avoidance_force = avoid_neighbors_force(mobile, neighbors_id, &mobiles2) * 0.3;
 
                 let TargetForce {
                     target_force,
                     stop_tracking,
-                } = to_target_force(mobile, botdef);
+                } = to_target_force(mobile, botdef, flowfield_cache);
 
                 // arrows.push(Arrow {
                 //     position: mobile.position,
@@ -737,11 +1532,6 @@ pub fn update_units(
                 let wanted_angle: Angle = dir.into();
                 let current_angle = mobile.angle;
 
-                fn clamp_abs(x: f32, max_abs: f32) -> f32 {
-                    let sign = x.signum();
-                    sign * (x.abs().min(max_abs))
-                }
-
                 let diff = (wanted_angle - (current_angle + mobile.angular_velocity.into())).rad;
 
                 mobile.angular_velocity = clamp_abs(
@@ -794,7 +1584,13 @@ pub fn update_units(
                     mobile.speed /= speed / botdef.max_speed;
                 }
 
-                mobile.position += mobile.speed;
+                //Slow down on slopes, refuse to enter ground the move class can't cross
+                let old_position = mobile.position;
+                let water_level = heightmap_phy.data.water_level;
+                let (slope, ground_z) = slope_and_z(heightmap_phy, &mobile.position);
+                let was_crossable = botdef.move_class.can_cross(slope, ground_z, water_level);
+                mobile.position +=
+                    mobile.speed * botdef.move_class.speed_factor(slope, ground_z, water_level);
                 mobile.position.x = mobile
                     .position
                     .x
@@ -805,15 +1601,30 @@ pub fn update_units(
                     .y
                     .max(0.0)
                     .min(heightmap_phy.height as f32 - 1.0);
-                mobile.position.z = heightmap_phy.z_linear(mobile.position.x, mobile.position.y);
+
+                let (slope, ground_z) = slope_and_z(heightmap_phy, &mobile.position);
+                if was_crossable && !botdef.move_class.can_cross(slope, ground_z, water_level) {
+                    mobile.position = old_position;
+                    mobile.speed = Vector3::new(0.0, 0.0, 0.0);
+                }
+
+                let ground_z = heightmap_phy.z_linear(mobile.position.x, mobile.position.y);
+                let stand_z = botdef.move_class.stand_z(ground_z, water_level);
+                mobile.position.z = stand_z;
                 mobile.grounded = true;
-                mobile.up = heightmap_phy.normal(mobile.position.x, mobile.position.y);
+                mobile.up = if stand_z > ground_z {
+                    Vector3::new(0.0, 0.0, 1.0)
+                } else {
+                    heightmap_phy.normal(mobile.position.x, mobile.position.y)
+                };
 
                 let y = -mobile.dir.cross(&mobile.up);
                 let x = y.cross(&mobile.up);
                 mobile.dir = x;
 
-                mobile.weapon0_dir = (mobile.weapon0_dir + mobile.dir).normalize();
+                if botdef.weapon0.is_none() {
+                    mobile.weapon0_dir = (mobile.weapon0_dir + mobile.dir).normalize();
+                }
                 //w = v/r
                 mobile.wheel0_angle += mobile.speed.norm() / 0.5;
             }
@@ -822,10 +1633,27 @@ pub fn update_units(
     frame_profiler.add("02  movement", start.elapsed());
 
     //Remove dead kbot
-    for (id, kbot) in kbots.iter() {
+    for id in kbot_ids.iter() {
+        let kbot = &kbots[id];
         if kbot.life <= 0 {
             kbots_dead.insert(*id);
 
+            if let (true, Some(botdef)) = (kbot.con_completed >= 1.0, bot_defs.get(&kbot.botdef_id))
+            {
+                //Air units crash and ships sink
+                let mut position = kbot.position;
+                position.z = heightmap_phy.z_linear(position.x, position.y);
+                let wreck = Wreck {
+                    id: id_generator.next_id(),
+                    position,
+                    dir: kbot.dir,
+                    up: kbot.up,
+                    botdef_id: kbot.botdef_id,
+                    metal: botdef.metal_cost as f64 * WRECK_METAL_RATIO,
+                };
+                wrecks.insert(wreck.id, wreck);
+            }
+
             explosions.push(ExplosionEvent {
                 position: Point3::from(kbot.position),
                 size: 1.0,
@@ -866,7 +1694,232 @@ struct TargetForce {
     target_force: Vector2<f32>,
     stop_tracking: bool,
 }
-fn to_target_force(me: &KBot, botdef: &botdef::BotDef) -> TargetForce {
+///Units in the grid cells overlapping a square of half side range around center,
+///a unit is counted only in its own cell
+fn ids_in_range(
+    kbots: &FnvHashMap<Id<KBot>, KBot>,
+    grid: &[Vec<Id<KBot>>],
+    cell_size: usize,
+    grid_w: usize,
+    grid_h: usize,
+    center: &Vector3<f32>,
+    range: f32,
+) -> Vec<Id<KBot>> {
+    let cell_of = |x: f32, y: f32| {
+        (
+            (x.max(0.0) as usize / cell_size) as i32,
+            (y.max(0.0) as usize / cell_size) as i32,
+        )
+    };
+    let cell_range = (range / cell_size as f32).ceil() as i32;
+    let (ci, cj) = cell_of(center.x, center.y);
+
+    let mut ids = Vec::new();
+    for j in (cj - cell_range).max(0)..=(cj + cell_range).min(grid_h as i32 - 1) {
+        for i in (ci - cell_range).max(0)..=(ci + cell_range).min(grid_w as i32 - 1) {
+            let cell = i as usize + j as usize * grid_w;
+            for id in grid[cell].iter() {
+                let kbot = kbots.get(id).unwrap();
+                if cell_of(kbot.position.x, kbot.position.y) == (i, j) {
+                    ids.push(*id);
+                }
+            }
+        }
+    }
+    ids
+}
+
+///Lowest score is the preferred target, distance breaks ties
+fn target_score(
+    target_priority: &TargetPriority,
+    me: &KBot,
+    ennemy: &KBot,
+    ennemy_botdef: &botdef::BotDef,
+) -> (f32, f32) {
+    let distance = (ennemy.position.coords - me.position.coords).magnitude();
+    match target_priority {
+        TargetPriority::Closest | TargetPriority::KeepCurrent => (0.0, distance),
+        TargetPriority::LowestHealth => (ennemy.life as f32, distance),
+        TargetPriority::HighestMetalCost => (-ennemy_botdef.metal_cost as f32, distance),
+        TargetPriority::Category(category) => {
+            if &ennemy_botdef.category == category {
+                (0.0, distance)
+            } else {
+                (1.0, distance)
+            }
+        }
+    }
+}
+
+fn clamp_abs(x: f32, max_abs: f32) -> f32 {
+    let sign = x.signum();
+    sign * (x.abs().min(max_abs))
+}
+
+///x / max, 0 for botdefs whose max is 0
+fn ratio(x: f32, max: f32) -> f32 {
+    if max > 0.0 {
+        x / max
+    } else {
+        0.0
+    }
+}
+
+///Flying, not landed
+fn airborne(kbot: &KBot, bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>) -> bool {
+    !kbot.grounded
+        && bot_defs
+            .get(&kbot.botdef_id)
+            .map(|botdef| botdef.is_air())
+            .unwrap_or(false)
+}
+
+///Units fully under the water are only seen by weapons aiming there
+fn target_layer(
+    kbot: &KBot,
+    bot_defs: &FnvHashMap<Id<botdef::BotDef>, botdef::BotDef>,
+    water_level: f32,
+) -> TargetLayer {
+    let radius = bot_defs
+        .get(&kbot.botdef_id)
+        .map(|botdef| botdef.radius)
+        .unwrap_or(0.0);
+    if airborne(kbot, bot_defs) {
+        TargetLayer::Air
+    } else if kbot.position.z + radius < water_level {
+        TargetLayer::Underwater
+    } else {
+        TargetLayer::Ground
+    }
+}
+
+///Air units fly straight to their target at cruise altitude and bank in turns, once idle they
+///land where they can, fixed wings circle until then
+fn fly(
+    mobile: &mut KBot,
+    botdef: &botdef::BotDef,
+    flight: &botdef::FlightDef,
+    avoidance_force: Vector2<f32>,
+    heightmap_phy: &heightmap_phy::HeightmapPhy,
+) {
+    let fixed_wing = flight.flight_type == botdef::FlightType::FixedWing;
+    let idle = mobile.move_target.is_none() && mobile.current_command == Command::None;
+    if mobile.grounded {
+        if idle {
+            return;
+        }
+        //Take off
+        mobile.grounded = false;
+    }
+
+    //Fixed wings can't stop, they arrive within their turn radius
+    let speed = mobile.speed.xy().norm();
+    let arrival_distance = if fixed_wing {
+        ratio(speed, botdef.max_turn_rate).max(botdef.radius)
+    } else {
+        botdef.radius * 2.0
+    };
+    let mut to_target = mobile
+        .move_target
+        .map(|target| (target.coords - mobile.position.coords).xy());
+    if to_target.map(|v| v.norm() < arrival_distance) == Some(true) {
+        mobile.move_target = None;
+        to_target = None;
+    }
+
+    let wanted_angle: Angle = match to_target {
+        Some(to_target) => (to_target.normalize() + avoidance_force).into(),
+        None if fixed_wing => mobile.angle + Angle::new(botdef.max_turn_rate),
+        None => mobile.angle,
+    };
+    let diff = (wanted_angle - (mobile.angle + mobile.angular_velocity.into())).rad;
+    mobile.angular_velocity = clamp_abs(
+        mobile.angular_velocity + clamp_abs(diff, botdef.turn_accel),
+        botdef.max_turn_rate,
+    );
+    mobile.angle = mobile.angle + mobile.angular_velocity.into();
+    let dir: Vector2<f32> = mobile.angle.into();
+
+    //Gunships slow down to stop on their target, and to turn toward it
+    let wanted_speed = match to_target {
+        Some(to_target) if !fixed_wing => {
+            let facing = dir.dot(&to_target.normalize()).max(0.0);
+            botdef
+                .max_speed
+                .min((2.0 * botdef.break_accel * to_target.norm()).sqrt())
+                * facing
+        }
+        Some(_) => botdef.max_speed,
+        None => 0.0,
+    };
+    let speed = if wanted_speed > speed {
+        (speed + botdef.accel).min(wanted_speed)
+    } else {
+        (speed - botdef.break_accel).max(wanted_speed)
+    };
+    let speed = if fixed_wing {
+        speed.max(flight.min_speed)
+    } else {
+        speed
+    };
+
+    let horizontal = dir * speed;
+    let x = (mobile.position.x + horizontal.x)
+        .max(0.0)
+        .min(heightmap_phy.width as f32 - 1.0);
+    let y = (mobile.position.y + horizontal.y)
+        .max(0.0)
+        .min(heightmap_phy.height as f32 - 1.0);
+
+    //Cruise over the ground and the water, land only where the move class could stand
+    let (slope, ground_z) = slope_and_z(heightmap_phy, &Point3::new(x, y, 0.0));
+    let water_level = heightmap_phy.data.water_level;
+    let floor_z = botdef.move_class.stand_z(ground_z, water_level);
+    let landing = idle && botdef.move_class.can_cross(slope, ground_z, water_level);
+    let wanted_z = if landing {
+        floor_z
+    } else {
+        ground_z.max(water_level) + flight.cruise_altitude
+    };
+    let climb = (wanted_z - mobile.position.z)
+        .max(-flight.climb_rate)
+        .min(flight.climb_rate);
+    let z = (mobile.position.z + climb).max(floor_z);
+
+    mobile.speed = Vector3::new(horizontal.x, horizontal.y, z - mobile.position.z);
+    mobile.position = Point3::new(x, y, z);
+
+    if landing && z <= floor_z + 0.01 {
+        //Touchdown
+        mobile.grounded = true;
+        mobile.speed = Vector3::new(0.0, 0.0, 0.0);
+        mobile.angular_velocity = 0.0;
+        mobile.up = heightmap_phy.normal(x, y);
+        let side = -Vector3::new(dir.x, dir.y, 0.0).cross(&mobile.up);
+        mobile.dir = side.cross(&mobile.up);
+    } else {
+        //Roll into the turn, more so when fast
+        let bank = flight.max_bank
+            * ratio(mobile.angular_velocity, botdef.max_turn_rate)
+            * ratio(speed, botdef.max_speed);
+        let left = Vector3::new(-dir.y, dir.x, 0.0);
+        mobile.up = Vector3::new(0.0, 0.0, bank.cos()) + left * bank.sin();
+        mobile.dir = Vector3::new(dir.x, dir.y, 0.0);
+    }
+}
+
+///Slope (dz/dx) and height of the ground under a position
+fn slope_and_z(heightmap_phy: &heightmap_phy::HeightmapPhy, position: &Point3<f32>) -> (f32, f32) {
+    let normal = heightmap_phy.normal(position.x, position.y);
+    let slope = normal.xy().norm() / normal.z;
+    (slope, heightmap_phy.z_linear(position.x, position.y))
+}
+
+fn to_target_force(
+    me: &KBot,
+    botdef: &botdef::BotDef,
+    flowfield_cache: &flowfield::FlowFieldCache,
+) -> TargetForce {
     if let Some(target) = me.move_target {
         let to_target = (target.coords - (me.position.coords + me.speed)).xy();
         let to_target_distance = to_target.norm();
@@ -876,8 +1929,13 @@ fn to_target_force(me: &KBot, botdef: &botdef::BotDef) -> TargetForce {
             1.0 - ((botdef.radius - to_target_distance) / botdef.radius)
         };
 
+        //Follow the flowfield until close to the target, then go straight
+        let to_target_dir = flowfield_cache
+            .flow_at(me.botdef_id, &target, me.position.x, me.position.y)
+            .unwrap_or(to_target / to_target_distance);
+
         TargetForce {
-            target_force: (to_target / to_target_distance) * will_to_go_target,
+            target_force: to_target_dir * will_to_go_target,
             stop_tracking: to_target_distance < botdef.radius / 2.0,
         }
     } else {
@@ -887,3 +1945,284 @@ fn to_target_force(me: &KBot, botdef: &botdef::BotDef) -> TargetForce {
         }
     }
 }
+
+///Wrecks are emptied at the build power of their reclaimers, the empty ones are removed.
+///Metal reclaimed by each player
+fn reclaim(
+    reclaims: &[(Id<Player>, Id<Wreck>, f64)],
+    wrecks: &mut FnvHashMap<Id<Wreck>, Wreck>,
+    players: &mut FnvHashMap<Id<Player>, Player>,
+) -> FnvHashMap<Id<Player>, f64> {
+    let mut reclaimed = FnvHashMap::default();
+    for (player_id, wreck_id, amount) in reclaims {
+        if let (Some(wreck), Some(player)) = (wrecks.get_mut(wreck_id), players.get_mut(player_id))
+        {
+            let metal = amount.min(wreck.metal);
+            wreck.metal -= metal;
+            player.metal += metal;
+            *reclaimed.entry(*player_id).or_insert(0.0) += metal;
+        }
+    }
+    wrecks.retain(|_, wreck| wreck.metal > 0.0);
+    reclaimed
+}
+
+///Clamp each stock to its storage, and spread what exceeds among the others if shared
+///Returns (shared_in, shared_out, wasted) for each stock
+fn spread_overflow(stocks: &mut [f64], storages: &[f64], shares: &[bool]) -> Vec<(f64, f64, f64)> {
+    let overflows: Vec<f64> = stocks
+        .iter_mut()
+        .zip(storages)
+        .map(|(stock, storage)| {
+            let overflow = (*stock - storage).max(0.0);
+            *stock -= overflow;
+            overflow
+        })
+        .collect();
+
+    let pool: f64 = overflows
+        .iter()
+        .zip(shares)
+        .filter(|(_, share)| **share)
+        .map(|(overflow, _)| overflow)
+        .sum();
+    let room: f64 = stocks.iter().zip(storages).map(|(s, max)| max - s).sum();
+    let given = pool.min(room);
+    let give_ratio = if pool > 0.0 { given / pool } else { 0.0 };
+    let fill_ratio = if room > 0.0 { given / room } else { 0.0 };
+
+    (0..stocks.len())
+        .map(|i| {
+            let shared_in = (storages[i] - stocks[i]) * fill_ratio;
+            stocks[i] += shared_in;
+            let shared_out = if shares[i] {
+                overflows[i] * give_ratio
+            } else {
+                0.0
+            };
+            (shared_in, shared_out, overflows[i] - shared_out)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_botdef(name: &str) -> botdef::BotDef {
+        let path = format!("src/asset/botdef/{}.json", name);
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    ///Two teams of tanks facing each other on a flat map, with fixed ids
+    fn battle_frame() -> Frame {
+        let tank = load_botdef("unit_example");
+        let mut frame = Frame::new();
+        frame.bot_defs.insert(tank.id, tank.clone());
+        frame.heightmap_phy = Some(heightmap_phy::HeightmapPhy::new(256, 256));
+        for team in 0..2 {
+            let mut player = Player::new();
+            player.id = Id::new(1 + team as u64);
+            player.team = team;
+            for i in 0..20 {
+                let position = Point3::new(
+                    100.0 + (i % 5) as f32 * 3.0 + team as f32 * 30.0,
+                    100.0 + (i / 5) as f32 * 3.0,
+                    50.0,
+                );
+                let mut kbot = KBot::new(position, &tank, player.id);
+                kbot.id = Id::new(100 + team as u64 * 20 + i);
+                kbot.team = team;
+                player.kbots.insert(kbot.id);
+                frame.kbots.insert(kbot.id, kbot);
+            }
+            frame.players.insert(player.id, player);
+        }
+        frame
+    }
+
+    ///Same content, maps filled in reverse order and with another capacity so they iterate
+    ///differently
+    fn reordered(frame: &Frame) -> Frame {
+        let mut reordered = frame.clone();
+        let mut kbots: Vec<_> = frame.kbots.values().cloned().collect();
+        kbots.reverse();
+        reordered.kbots = FnvHashMap::with_capacity_and_hasher(1024, Default::default());
+        for kbot in kbots {
+            reordered.kbots.insert(kbot.id, kbot);
+        }
+        for player in reordered.players.values_mut() {
+            let mut kbots: Vec<_> = player.kbots.iter().copied().collect();
+            kbots.reverse();
+            player.kbots = FnvHashSet::with_capacity_and_hasher(1024, Default::default());
+            player.kbots.extend(kbots);
+        }
+        reordered
+    }
+
+    ///Every player orders all its units toward the other team, the event list is reversed
+    ///when reverse_events is set
+    fn run(frame: Frame, reverse_events: bool) -> Vec<u64> {
+        let mut orders: Vec<FrameEventFromPlayer> = sorted_ids(&frame.players)
+            .iter()
+            .map(|id| {
+                let player = &frame.players[id];
+                let x = if player.team == 0 { 140.0 } else { 100.0 };
+                FrameEventFromPlayer::MoveOrder {
+                    id: *id,
+                    selected: player.kbots.clone(),
+                    mouse_world_pos: Vector3::new(x, 105.0, 50.0),
+                    queued: false,
+                }
+            })
+            .collect();
+        if reverse_events {
+            orders.reverse();
+        }
+
+        let mut cache = FrameServerCache::new();
+        let mut frame = cache.next_frame(
+            frame.clone(),
+            vec![FrameEventFromPlayer::ReplaceFrame(frame)],
+        );
+        let mut hashes = Vec::new();
+        for tick in 0..150 {
+            let events = if tick == 0 {
+                orders.clone()
+            } else {
+                Vec::new()
+            };
+            frame = cache.next_frame(frame, events);
+            hashes.push(frame.canonical_hash());
+        }
+        assert!(
+            frame.kbots.values().any(|kbot| kbot.life < 100),
+            "the teams never fought"
+        );
+        hashes
+    }
+
+    #[test]
+    fn same_events_give_same_frames_whatever_the_ordering() {
+        let frame = battle_frame();
+        let hashes = run(frame.clone(), false);
+        assert_eq!(hashes, run(reordered(&frame), true));
+    }
+
+    #[test]
+    fn reclaimed_wrecks_credit_their_metal_then_vanish() {
+        let mut player = Player::new();
+        player.id = Id::new(1);
+        player.metal = 0.0;
+        let mut players = FnvHashMap::default();
+        players.insert(player.id, player);
+        let wreck = Wreck {
+            id: Id::new(10),
+            position: Point3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(1.0, 0.0, 0.0),
+            up: Vector3::new(0.0, 0.0, 1.0),
+            botdef_id: Id::new(0),
+            metal: 5.0,
+        };
+        let mut wrecks = FnvHashMap::default();
+        wrecks.insert(wreck.id, wreck);
+
+        let reclaimed = reclaim(&[(Id::new(1), Id::new(10), 3.0)], &mut wrecks, &mut players);
+        assert_eq!(reclaimed[&Id::new(1)], 3.0);
+        assert_eq!(wrecks[&Id::new(10)].metal, 2.0);
+
+        //Two reclaimers, the second one gets what the first left
+        let reclaims = [
+            (Id::new(1), Id::new(10), 3.0),
+            (Id::new(1), Id::new(10), 3.0),
+        ];
+        let reclaimed = reclaim(&reclaims, &mut wrecks, &mut players);
+        assert_eq!(reclaimed[&Id::new(1)], 2.0);
+        assert!(wrecks.is_empty());
+        assert_eq!(players[&Id::new(1)].metal, 5.0);
+    }
+
+    #[test]
+    fn overflow_goes_to_the_room_of_the_others_when_shared() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        let mut stocks = [15.0, 2.0, 9.0];
+        let flows = spread_overflow(&mut stocks, &[10.0; 3], &[true, false, true]);
+        assert!(close(stocks[0], 10.0));
+        assert!(close(stocks[1], 2.0 + 8.0 * 5.0 / 9.0));
+        assert!(close(stocks[2], 9.0 + 5.0 / 9.0));
+        assert!(close(flows[0].1, 5.0) && close(flows[0].2, 0.0));
+        assert!(close(flows[1].0, 8.0 * 5.0 / 9.0));
+
+        //Not shared, wasted
+        let mut stocks = [15.0, 2.0];
+        let flows = spread_overflow(&mut stocks, &[10.0; 2], &[false, true]);
+        assert_eq!(stocks, [10.0, 2.0]);
+        assert_eq!(flows[0], (0.0, 0.0, 5.0));
+
+        //More than the others can store, the rest is wasted
+        let mut stocks = [20.0, 9.0];
+        let flows = spread_overflow(&mut stocks, &[10.0; 2], &[true, true]);
+        assert_eq!(stocks, [10.0, 10.0]);
+        assert!(close(flows[0].1, 1.0) && close(flows[0].2, 9.0));
+        assert!(close(flows[1].0, 1.0));
+    }
+
+    #[test]
+    fn planes_take_off_cruise_and_land_when_idle() {
+        let plane = load_botdef("plane_example");
+        let flight = plane.flight.clone().unwrap();
+        let heightmap = heightmap_phy::HeightmapPhy::new(256, 256);
+        let ground_z = heightmap.z_linear(10.0, 128.0);
+        let mut kbot = KBot::new(Point3::new(10.0, 128.0, ground_z), &plane, Id::new(1));
+        kbot.grounded = true;
+        let no_avoidance = Vector2::new(0.0, 0.0);
+
+        //Stays on the ground until ordered to move
+        fly(&mut kbot, &plane, &flight, no_avoidance, &heightmap);
+        assert!(kbot.grounded);
+
+        kbot.move_target = Some(Point3::new(250.0, 128.0, ground_z));
+        fly(&mut kbot, &plane, &flight, no_avoidance, &heightmap);
+        assert!(!kbot.grounded);
+        assert!(kbot.speed.xy().norm() >= flight.min_speed);
+        for _ in 0..60 {
+            fly(&mut kbot, &plane, &flight, no_avoidance, &heightmap);
+        }
+        assert!((kbot.position.z - (ground_z + flight.cruise_altitude)).abs() < 0.01);
+
+        kbot.move_target = None;
+        for _ in 0..200 {
+            if kbot.grounded {
+                break;
+            }
+            fly(&mut kbot, &plane, &flight, no_avoidance, &heightmap);
+        }
+        assert!(kbot.grounded, "never landed at {:?}", kbot.position);
+        assert!((kbot.position.z - ground_z).abs() < 0.01);
+        //Aiming is left to the weapon code
+        assert_eq!(kbot.weapon0_dir, Vector3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn planes_that_cant_turn_or_move_stay_finite() {
+        let mut plane = load_botdef("plane_example");
+        plane.max_turn_rate = 0.0;
+        plane.max_speed = 0.0;
+        let flight = plane.flight.clone().unwrap();
+        let heightmap = heightmap_phy::HeightmapPhy::new(64, 64);
+        let mut kbot = KBot::new(Point3::new(32.0, 32.0, 50.0), &plane, Id::new(1));
+        kbot.move_target = Some(Point3::new(60.0, 60.0, 50.0));
+        for _ in 0..10 {
+            fly(
+                &mut kbot,
+                &plane,
+                &flight,
+                Vector2::new(0.0, 0.0),
+                &heightmap,
+            );
+        }
+        assert!(kbot.position.coords.iter().all(|x| x.is_finite()));
+        assert!(kbot.up.iter().all(|x| x.is_finite()));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::botdef::BotDef;
+use crate::frame::*;
+use crate::heightmap_phy::HeightmapPhy;
+use crate::mobile::KBot;
+use crate::utils::Id;
+use fnv::FnvHashSet;
+use na::Vector3;
+
+///Check an event against the state of the frame it applies to
+pub fn validate(
+    event: &FrameEventFromPlayer,
+    frame: &Frame,
+    heightmap: Option<&HeightmapPhy>,
+) -> Result<(), EventRejection> {
+    use FrameEventFromPlayer::*;
+    let (id, selected) = match event {
+        ReplaceFrame(_) => return Ok(()),
+        ShareOverflow { id, .. } | Chat { id, .. } | MapPing { id, .. } | MapDrawing { id, .. } => {
+            (*id, None)
+        }
+        RepairOrder { id, selected, .. }
+        | ReclaimOrder { id, selected, .. }
+        | ConOrder { id, selected, .. }
+        | MoveOrder { id, selected, .. }
+        | AttackOrder { id, selected, .. }
+        | GuardOrder { id, selected, .. }
+        | PatrolOrder { id, selected, .. }
+        | StopOrder { id, selected }
+        | ProductionOrder { id, selected, .. }
+        | FactoryRepeat { id, selected, .. } => (*id, Some(selected)),
+    };
+    let reject = |reason| EventRejection {
+        player_id: id,
+        event: event_name(event).to_owned(),
+        reason,
+    };
+
+    let player = frame
+        .players
+        .get(&id)
+        .ok_or_else(|| reject(RejectionReason::UnknownPlayer))?;
+
+    //Selected units that died since the order was given are skipped later on
+    if let Some(selected) = selected {
+        if let Some(not_owned) = selected.iter().find(|kbot_id| {
+            frame
+                .kbots
+                .get(kbot_id)
+                .map(|kbot| kbot.player_id != id)
+                .unwrap_or(false)
+        }) {
+            return Err(reject(RejectionReason::NotOwned(*not_owned)));
+        }
+    }
+
+    //Every position an order carries must be on the map
+    let positions: Vec<&Vector3<f32>> = match event {
+        ConOrder {
+            mouse_world_pos, ..
+        }
+        | MoveOrder {
+            mouse_world_pos, ..
+        }
+        | AttackOrder {
+            mouse_world_pos, ..
+        }
+        | PatrolOrder {
+            mouse_world_pos, ..
+        } => vec![mouse_world_pos],
+        MapPing { position, .. } => vec![position],
+        MapDrawing { points, .. } if points.len() <= MAX_DRAWING_POINTS => points.iter().collect(),
+        _ => Vec::new(),
+    };
+    if !positions
+        .iter()
+        .all(|position| is_on_map(position, heightmap))
+    {
+        return Err(reject(RejectionReason::OffMap));
+    }
+
+    match event {
+        ConOrder {
+            selected,
+            botdef_id,
+            ..
+        } => {
+            let botdef = frame
+                .bot_defs
+                .get(botdef_id)
+                .ok_or_else(|| reject(RejectionReason::UnknownBotDef))?;
+            if !can_build(frame, selected, botdef_id, false) {
+                return Err(reject(RejectionReason::NotBuildable));
+            }
+            if !can_afford(player, botdef) {
+                return Err(reject(RejectionReason::Unaffordable));
+            }
+            Ok(())
+        }
+        ProductionOrder { amount, .. }
+            if !(-MAX_PRODUCTION_AMOUNT..=MAX_PRODUCTION_AMOUNT).contains(amount) =>
+        {
+            Err(reject(RejectionReason::BadAmount))
+        }
+        ProductionOrder {
+            selected,
+            botdef_id,
+            amount,
+            ..
+        } if *amount > 0 => {
+            if !frame.bot_defs.contains_key(botdef_id) {
+                return Err(reject(RejectionReason::UnknownBotDef));
+            }
+            if !can_build(frame, selected, botdef_id, true) {
+                return Err(reject(RejectionReason::NotBuildable));
+            }
+            Ok(())
+        }
+        Chat { text, .. } if text.chars().count() > MAX_CHAT_LEN => {
+            Err(reject(RejectionReason::TooLong))
+        }
+        MapDrawing { points, .. } if points.len() > MAX_DRAWING_POINTS => {
+            Err(reject(RejectionReason::TooLong))
+        }
+        _ => Ok(()),
+    }
+}
+
+///Written so that NaN coordinates are off the map too
+fn is_on_map(position: &Vector3<f32>, heightmap: Option<&HeightmapPhy>) -> bool {
+    let (width, height) = heightmap
+        .map(|heightmap| (heightmap.width as f32, heightmap.height as f32))
+        .unwrap_or((f32::INFINITY, f32::INFINITY));
+    position.x >= 0.0
+        && position.x < width
+        && position.y >= 0.0
+        && position.y < height
+        && position.z.is_finite()
+}
+
+///At least one completed builder (or factory) of selected has botdef_id in its ModDef::con_map entry
+fn can_build(
+    frame: &Frame,
+    selected: &FnvHashSet<Id<KBot>>,
+    botdef_id: &Id<BotDef>,
+    factory: bool,
+) -> bool {
+    selected
+        .iter()
+        .filter_map(|id| frame.kbots.get(id))
+        .filter(|kbot| kbot.con_completed >= 1.0 && kbot.factory.is_some() == factory)
+        .any(|kbot| {
+            frame
+                .moddef
+                .con_map
+                .get(&kbot.botdef_id)
+                .map(|con| con.contains(botdef_id))
+                .unwrap_or(false)
+        })
+}
+
+fn can_afford(player: &Player, botdef: &BotDef) -> bool {
+    player.metal >= botdef.metal_cost as f64 && player.energy >= botdef.energy_cost as f64
+}
+
+fn event_name(event: &FrameEventFromPlayer) -> &'static str {
+    use FrameEventFromPlayer::*;
+    match event {
+        RepairOrder { .. } => "RepairOrder",
+        ReclaimOrder { .. } => "ReclaimOrder",
+        ConOrder { .. } => "ConOrder",
+        MoveOrder { .. } => "MoveOrder",
+        AttackOrder { .. } => "AttackOrder",
+        GuardOrder { .. } => "GuardOrder",
+        PatrolOrder { .. } => "PatrolOrder",
+        StopOrder { .. } => "StopOrder",
+        ProductionOrder { .. } => "ProductionOrder",
+        FactoryRepeat { .. } => "FactoryRepeat",
+        ShareOverflow { .. } => "ShareOverflow",
+        Chat { .. } => "Chat",
+        MapPing { .. } => "MapPing",
+        MapDrawing { .. } => "MapDrawing",
+        ReplaceFrame(_) => "ReplaceFrame",
+    }
+}
@@ -0,0 +1,232 @@
+use crate::botdef::BotDef;
+use crate::frame::{Frame, Player};
+use crate::heightmap_phy::HeightmapPhy;
+use crate::mobile::KBot;
+use crate::utils::Id;
+use fnv::FnvHashMap;
+use na::Point3;
+use serde::{Deserialize, Serialize};
+
+///m, side of a cell of the vision grids
+pub const CELL_SIZE: f32 = 8.0;
+///m above the ground units look from
+const EYE_HEIGHT: f32 = 2.0;
+///m above the ground a cell is looked at
+const TARGET_HEIGHT: f32 = 1.0;
+
+///m, radar blips are drawn this far from the unit at most
+pub const RADAR_ERROR: f32 = 6.0;
+
+///What a team sees of the map
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VisionGrid {
+    pub width: usize,
+    pub height: usize,
+    ///In sight of a completed unit of the team this frame
+    pub visible: Vec<bool>,
+    ///Seen at least once
+    pub explored: Vec<bool>,
+    ///In range of a radar of the team, units there are known by position only
+    pub radar: Vec<bool>,
+    ///In range of a jammer of the team, its units there are hidden from enemy radars
+    pub jammed: Vec<bool>,
+}
+
+impl VisionGrid {
+    pub fn new(map_width: usize, map_height: usize) -> Self {
+        let width = (map_width as f32 / CELL_SIZE).ceil() as usize;
+        let height = (map_height as f32 / CELL_SIZE).ceil() as usize;
+        VisionGrid {
+            width,
+            height,
+            visible: vec![false; width * height],
+            explored: vec![false; width * height],
+            radar: vec![false; width * height],
+            jammed: vec![false; width * height],
+        }
+    }
+
+    fn index(&self, x: f32, y: f32) -> Option<usize> {
+        let (i, j) = ((x / CELL_SIZE).floor(), (y / CELL_SIZE).floor());
+        if i < 0.0 || j < 0.0 || i >= self.width as f32 || j >= self.height as f32 {
+            None
+        } else {
+            Some(i as usize + j as usize * self.width)
+        }
+    }
+
+    ///Off the map is never visible
+    pub fn is_visible(&self, x: f32, y: f32) -> bool {
+        self.index(x, y).map(|i| self.visible[i]).unwrap_or(false)
+    }
+
+    pub fn is_explored(&self, x: f32, y: f32) -> bool {
+        self.index(x, y).map(|i| self.explored[i]).unwrap_or(false)
+    }
+
+    ///Cells whose center is within radius of x y, whatever the terrain
+    fn cells_around(&self, x: f32, y: f32, radius: f32) -> Vec<usize> {
+        let (ci, cj) = (
+            (x / CELL_SIZE).floor() as i64,
+            (y / CELL_SIZE).floor() as i64,
+        );
+        let r = (radius / CELL_SIZE).ceil() as i64;
+        let mut cells = Vec::new();
+        for j in (cj - r).max(0)..=(cj + r).min(self.height as i64 - 1) {
+            for i in (ci - r).max(0)..=(ci + r).min(self.width as i64 - 1) {
+                let cx = (i as f32 + 0.5) * CELL_SIZE;
+                let cy = (j as f32 + 0.5) * CELL_SIZE;
+                if (cx - x).powi(2) + (cy - y).powi(2) <= radius * radius {
+                    cells.push(i as usize + j as usize * self.width);
+                }
+            }
+        }
+        cells
+    }
+
+    ///Marks the cells within radius of eye that the terrain doesn't hide
+    fn see_around(&mut self, eye: Point3<f32>, radius: f32, heightmap: &HeightmapPhy) {
+        let eye = Point3::new(eye.x, eye.y, eye.z + EYE_HEIGHT);
+        let (ci, cj) = (
+            (eye.x / CELL_SIZE).floor() as i64,
+            (eye.y / CELL_SIZE).floor() as i64,
+        );
+        let r = (radius / CELL_SIZE).ceil() as i64;
+        for j in (cj - r).max(0)..=(cj + r).min(self.height as i64 - 1) {
+            for i in (ci - r).max(0)..=(ci + r).min(self.width as i64 - 1) {
+                let index = i as usize + j as usize * self.width;
+                //Already seen by another unit of the team
+                if self.visible[index] {
+                    continue;
+                }
+                let x = (i as f32 + 0.5) * CELL_SIZE;
+                let y = (j as f32 + 0.5) * CELL_SIZE;
+                let own_cell = i == ci && j == cj;
+                let distance = ((x - eye.x).powi(2) + (y - eye.y).powi(2)).sqrt();
+                if own_cell || (distance <= radius && in_line_of_sight(heightmap, eye, x, y)) {
+                    self.visible[index] = true;
+                    self.explored[index] = true;
+                }
+            }
+        }
+    }
+}
+
+///No terrain above the segment from eye to the ground at x y
+fn in_line_of_sight(heightmap: &HeightmapPhy, eye: Point3<f32>, x: f32, y: f32) -> bool {
+    let target_z = heightmap.safe_z(x, y) + TARGET_HEIGHT;
+    let distance = ((x - eye.x).powi(2) + (y - eye.y).powi(2)).sqrt();
+    let steps = (distance / (CELL_SIZE / 2.0)).ceil() as usize;
+    (1..steps).all(|step| {
+        let t = step as f32 / steps as f32;
+        let z = eye.z + (target_z - eye.z) * t;
+        heightmap.safe_z(eye.x + (x - eye.x) * t, eye.y + (y - eye.y) * t) <= z
+    })
+}
+
+///Visible, radar and jammed cells of every team from its completed units, explored cells are kept
+pub fn update_vision(
+    vision: &mut FnvHashMap<u8, VisionGrid>,
+    kbots: &FnvHashMap<Id<KBot>, KBot>,
+    players: &FnvHashMap<Id<Player>, Player>,
+    bot_defs: &FnvHashMap<Id<BotDef>, BotDef>,
+    heightmap: &HeightmapPhy,
+) {
+    let fresh = VisionGrid::new(heightmap.width, heightmap.height);
+    //Teams gone, or grids of another map
+    vision.retain(|team, grid| {
+        players.values().any(|player| player.team == *team)
+            && (grid.width, grid.height) == (fresh.width, fresh.height)
+    });
+    for player in players.values() {
+        vision.entry(player.team).or_insert_with(|| fresh.clone());
+    }
+    for grid in vision.values_mut() {
+        for cells in &mut [&mut grid.visible, &mut grid.radar, &mut grid.jammed] {
+            for cell in cells.iter_mut() {
+                *cell = false;
+            }
+        }
+    }
+
+    //Cells are only ever set, the order units are looked at doesn't matter
+    for kbot in kbots.values().filter(|kbot| kbot.con_completed >= 1.0) {
+        let (botdef, grid) = match (bot_defs.get(&kbot.botdef_id), vision.get_mut(&kbot.team)) {
+            (Some(botdef), Some(grid)) => (botdef, grid),
+            _ => continue,
+        };
+        if botdef.sight_radius > 0.0 {
+            grid.see_around(kbot.position, botdef.sight_radius, heightmap);
+        }
+        let (x, y) = (kbot.position.x, kbot.position.y);
+        for cell in grid.cells_around(x, y, botdef.radar_radius) {
+            grid.radar[cell] = true;
+        }
+        for cell in grid.cells_around(x, y, botdef.jammer_radius) {
+            grid.jammed[cell] = true;
+        }
+    }
+}
+
+///Position of kbot known to team by radar: in range of one of its radars, not stealthy and not
+///jammed by its own team
+pub fn on_radar(frame: &Frame, team: u8, kbot: &KBot) -> bool {
+    let (x, y) = (kbot.position.x, kbot.position.y);
+    let stealth = frame
+        .bot_defs
+        .get(&kbot.botdef_id)
+        .map(|botdef| botdef.stealth)
+        .unwrap_or(false);
+    let covered = |team, cells: fn(&VisionGrid) -> &Vec<bool>| {
+        frame
+            .vision
+            .get(&team)
+            .and_then(|grid| grid.index(x, y).map(|i| cells(grid)[i]))
+            .unwrap_or(false)
+    };
+    !stealth && covered(team, |grid| &grid.radar) && !covered(kbot.team, |grid| &grid.jammed)
+}
+
+///Where the radar blip of kbot is drawn, off by up to RADAR_ERROR and moving every 3 seconds
+pub fn radar_blip(kbot: &KBot, frame_number: i32) -> Point3<f32> {
+    let hash = (kbot.id.value ^ (frame_number / 30) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let angle = (hash >> 32) as f32 / u32::MAX as f32 * std::f32::consts::PI * 2.0;
+    let distance = (hash & 0xFFFF_FFFF) as f32 / u32::MAX as f32 * RADAR_ERROR;
+    kbot.position + na::Vector3::new(angle.cos(), angle.sin(), 0.0) * distance
+}
+
+///Takes the units of other teams out of sight of team out of the frame, and returns them
+pub fn strip_hidden(frame: &mut Frame, team: u8) -> Vec<KBot> {
+    let grid = match frame.vision.get(&team) {
+        Some(grid) => grid,
+        None => return Vec::new(),
+    };
+    let hidden: Vec<Id<KBot>> = frame
+        .kbots
+        .values()
+        .filter(|kbot| kbot.team != team && !grid.is_visible(kbot.position.x, kbot.position.y))
+        .map(|kbot| kbot.id)
+        .collect();
+    hidden
+        .iter()
+        .filter_map(|id| frame.kbots.remove(id))
+        .collect()
+}
+
+///What team may know of frame: its units and the others in its sight, blips for those on its
+///radar, and its own vision grid
+pub fn seen_by(frame: &Frame, team: u8) -> Frame {
+    let mut seen = frame.clone();
+    let hidden = strip_hidden(&mut seen, team);
+    seen.radar_blips = hidden
+        .iter()
+        .filter(|kbot| on_radar(frame, team, kbot))
+        .map(|kbot| radar_blip(kbot, frame.number))
+        .collect();
+    let kbots = &seen.kbots;
+    for player in seen.players.values_mut() {
+        player.kbots.retain(|id| kbots.contains_key(id));
+    }
+    seen.vision.retain(|grid_team, _| *grid_team == team);
+    seen
+}
@@ -1,9 +1,19 @@
 use na::Vector3;
 use serde::{Deserialize, Serialize};
 
+///Water level of maps made before it could be set
+pub const WATER_LEVEL: f32 = 40.0;
+
+fn default_water_level() -> f32 {
+    WATER_LEVEL
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Data {
     pub metal_spots: Vec<MetalSpot>,
+    ///m, height of the sea, also given to water.vert and water.frag
+    #[serde(default = "default_water_level")]
+    pub water_level: f32,
 }
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct HeightmapPhy {
@@ -14,9 +24,9 @@ pub struct HeightmapPhy {
 }
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct MetalSpot {
-    metal_per_frame: f32,
-    x: usize,
-    y: usize,
+    pub metal_per_frame: f32,
+    pub x: usize,
+    pub y: usize,
 }
 
 trait HeightMapPhyUsize {
@@ -43,6 +53,7 @@ impl HeightmapPhy {
             height,
             data: Data {
                 metal_spots: Vec::new(),
+                water_level: WATER_LEVEL,
             },
         }
     }
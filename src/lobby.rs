@@ -0,0 +1,285 @@
+use crate::frame::Player;
+use crate::utils::{self, Id};
+use serde::{Deserialize, Serialize};
+
+pub const MAP_DIR: &str = "src/asset/map";
+pub const DEFAULT_MAP: &str = "src/asset/map/map_example";
+pub const MAX_SLOTS: usize = 8;
+pub const MAX_TEAMS: u8 = 4;
+
+///Given to new slots in turn
+const COLORS: [[f32; 3]; MAX_SLOTS] = [
+    [0.1, 0.4, 1.0],
+    [1.0, 0.2, 0.1],
+    [0.1, 0.9, 0.2],
+    [1.0, 0.9, 0.1],
+    [0.8, 0.2, 1.0],
+    [0.1, 0.9, 0.9],
+    [1.0, 0.5, 0.0],
+    [0.9, 0.9, 0.9],
+];
+
+///A player of the game being set up
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LobbySlot {
+    pub player_id: Id<Player>,
+    pub name: String,
+    pub team: u8,
+    pub color: [f32; 3],
+    pub ready: bool,
+    ///Chooses map and mod, and starts the game
+    pub host: bool,
+    ///Where the others join this player if it becomes the host
+    pub address: String,
+    ///Watches the game without units
+    pub spectator: bool,
+    ///False while the server keeps the slot of a player gone during the game
+    pub connected: bool,
+}
+
+///Kept by the server, sent to every client on change
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Lobby {
+    pub slots: Vec<LobbySlot>,
+    ///Folder in src/asset/map
+    pub map: String,
+    ///Name of a mod in src/asset/mod
+    pub mod_name: String,
+    pub started: bool,
+}
+
+///What a player asks to change, of its own slot or of the whole lobby for the host
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LobbyChange {
+    Name(String),
+    Team(u8),
+    Color([f32; 3]),
+    Ready(bool),
+    Map(String),
+    Mod(String),
+}
+
+impl Lobby {
+    pub fn new(map: String, mod_name: String) -> Self {
+        Lobby {
+            slots: Vec::new(),
+            map,
+            mod_name,
+            started: false,
+        }
+    }
+
+    ///None when full or already started
+    pub fn join(&mut self, name: String, host: bool, address: String) -> Option<Id<Player>> {
+        if self.started || self.slots.len() >= MAX_SLOTS {
+            return None;
+        }
+        let player_id = utils::rand_id();
+        let index = self.slots.len();
+        self.slots.push(LobbySlot {
+            player_id,
+            name,
+            team: index as u8 % 2,
+            color: COLORS[index],
+            ready: false,
+            host,
+            address,
+            spectator: false,
+            connected: true,
+        });
+        Some(player_id)
+    }
+
+    ///Spectators can join full and started games
+    pub fn join_spectator(&mut self, name: String, address: String) -> Id<Player> {
+        let player_id = utils::rand_id();
+        self.slots.push(LobbySlot {
+            player_id,
+            name,
+            team: 0,
+            color: COLORS[self.slots.len() % MAX_SLOTS],
+            ready: true,
+            host: false,
+            address,
+            spectator: true,
+            connected: true,
+        });
+        player_id
+    }
+
+    pub fn leave(&mut self, player_id: Id<Player>) {
+        self.slots.retain(|slot| slot.player_id != player_id);
+    }
+
+    pub fn set_connected(&mut self, player_id: Id<Player>, connected: bool) {
+        for slot in self.slots.iter_mut() {
+            if slot.player_id == player_id {
+                slot.connected = connected;
+            }
+        }
+    }
+
+    ///False when the player isn't allowed to make this change
+    pub fn apply(&mut self, player_id: Id<Player>, change: LobbyChange) -> bool {
+        if self.started {
+            return false;
+        }
+        let slot = match self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.player_id == player_id)
+        {
+            Some(slot) => slot,
+            None => return false,
+        };
+        match change {
+            LobbyChange::Name(name) => slot.name = name,
+            LobbyChange::Team(team) if team < MAX_TEAMS => slot.team = team,
+            LobbyChange::Color(color) => slot.color = color,
+            LobbyChange::Ready(ready) => slot.ready = ready,
+            LobbyChange::Map(map) if slot.host => {
+                self.map = map;
+                self.unready();
+            }
+            LobbyChange::Mod(mod_name) if slot.host => {
+                self.mod_name = mod_name;
+                self.unready();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    ///Everyone has to agree again after a change of map or mod
+    fn unready(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.ready = false;
+        }
+    }
+
+    ///The host is gone, the first other connected player in join order takes over,
+    ///a spectator only when no player is left
+    pub fn elect_host(&mut self) -> Option<&LobbySlot> {
+        self.slots.retain(|slot| !slot.host);
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.connected && !slot.spectator)
+            .or_else(|| self.slots.iter().position(|slot| slot.connected))?;
+        let host = &mut self.slots[index];
+        host.host = true;
+        Some(host)
+    }
+
+    ///Every player but the host is ready
+    pub fn can_start(&self) -> bool {
+        !self.started
+            && self
+                .slots
+                .iter()
+                .all(|slot| slot.ready || slot.host || slot.spectator)
+    }
+}
+
+///Folders of src/asset/map holding a heightmap
+pub fn list_maps() -> Vec<String> {
+    let mut maps: Vec<String> = std::fs::read_dir(MAP_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| has_heightmap(path))
+                .filter_map(|path| path.to_str().map(|path| path.to_owned()))
+                .collect()
+        })
+        .unwrap_or_default();
+    maps.sort();
+    maps
+}
+
+///Maps are loaded from their height.png, the current heightmap is kept without one
+pub fn has_heightmap<P: AsRef<std::path::Path>>(map: P) -> bool {
+    map.as_ref().join("height.png").exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby() -> Lobby {
+        Lobby::new(DEFAULT_MAP.to_owned(), "example".to_owned())
+    }
+
+    #[test]
+    fn only_the_host_changes_the_game_and_everyone_readies_again() {
+        let mut lobby = lobby();
+        let host = lobby
+            .join("host".to_owned(), true, "a:1".to_owned())
+            .unwrap();
+        let player = lobby
+            .join("player".to_owned(), false, "b:1".to_owned())
+            .unwrap();
+        lobby.join_spectator("spectator".to_owned(), "c:1".to_owned());
+        assert!(!lobby.can_start());
+
+        assert!(lobby.apply(player, LobbyChange::Ready(true)));
+        assert!(lobby.can_start(), "the host and spectators don't ready");
+
+        assert!(!lobby.apply(player, LobbyChange::Map("other".to_owned())));
+        assert!(lobby.apply(host, LobbyChange::Mod("other".to_owned())));
+        assert_eq!(lobby.mod_name, "other");
+        assert!(!lobby.can_start());
+
+        assert!(!lobby.apply(player, LobbyChange::Team(MAX_TEAMS)));
+        assert!(lobby.apply(player, LobbyChange::Team(MAX_TEAMS - 1)));
+        assert!(!lobby.apply(utils::rand_id(), LobbyChange::Ready(true)));
+
+        assert!(lobby.apply(player, LobbyChange::Ready(true)));
+        lobby.started = true;
+        assert!(!lobby.can_start());
+        assert!(!lobby.apply(player, LobbyChange::Name("late".to_owned())));
+    }
+
+    #[test]
+    fn connected_players_are_elected_before_spectators() {
+        let mut lobby = lobby();
+        let host = lobby
+            .join("host".to_owned(), true, "a:1".to_owned())
+            .unwrap();
+        let spectator = lobby.join_spectator("spectator".to_owned(), "b:1".to_owned());
+        let gone = lobby
+            .join("gone".to_owned(), false, "c:1".to_owned())
+            .unwrap();
+        let player = lobby
+            .join("player".to_owned(), false, "d:1".to_owned())
+            .unwrap();
+        lobby.set_connected(gone, false);
+
+        let elected = lobby.elect_host().unwrap().player_id;
+        assert_eq!(elected, player);
+        assert!(lobby.slots.iter().all(|slot| slot.player_id != host));
+        assert_eq!(
+            lobby.slots.iter().filter(|slot| slot.host).count(),
+            1,
+            "{:?}",
+            lobby.slots
+        );
+
+        //The elected host is gone too, only the spectator is left connected
+        assert_eq!(lobby.elect_host().unwrap().player_id, spectator);
+    }
+
+    #[test]
+    fn nobody_is_elected_when_everyone_is_gone() {
+        let mut lobby = lobby();
+        lobby.join("host".to_owned(), true, "a:1".to_owned());
+        assert!(lobby.elect_host().is_none());
+        assert!(lobby.slots.is_empty());
+
+        let gone = lobby
+            .join("gone".to_owned(), false, "b:1".to_owned())
+            .unwrap();
+        lobby.set_connected(gone, false);
+        assert!(lobby.elect_host().is_none());
+    }
+}
@@ -1,20 +1,25 @@
 mod botdef;
 mod client;
 mod frame;
+mod frame_delta;
 mod frame_server;
 mod glsl;
 mod gpu_obj;
 mod heightmap_phy;
+mod lobby;
 mod manager;
 mod mobile;
 mod model;
 mod moddef;
 mod net_client;
+mod net_protocol;
 mod net_server;
 mod procedural_texels;
+mod replay;
 mod unit;
 
 mod utils;
+mod weapondef;
 extern crate byteorder;
 extern crate crossbeam_channel;
 extern crate nalgebra as na;
@@ -31,9 +36,17 @@ use winit::event::Event;
 use winit::event_loop::ControlFlow;
 #[derive(Debug)]
 pub enum ToClient {
-    MapReadAsyncMessage { vec: Vec<f32>, usage: String },
+    MapReadAsyncMessage {
+        vec: Vec<f32>,
+        usage: String,
+    },
     NewFrame(frame::Frame),
     GlobalInfo(manager::GlobalInfo),
+    ///Sent on every change of the multiplayer lobby
+    Lobby {
+        lobby: lobby::Lobby,
+        my_player_id: utils::Id<frame::Player>,
+    },
 }
 
 pub enum EventLoopMsg {
@@ -1,12 +1,18 @@
 use crate::client;
 use crate::frame;
 use crate::frame_server;
+use crate::lobby::Lobby;
 use crate::net_client;
+use crate::net_protocol::PeerToken;
 use crate::net_server;
+use crate::replay;
+use crate::utils::Id;
 use crate::ToClient;
 use crossbeam_channel::{Receiver, Sender};
+use fnv::FnvHashMap;
 use net_client::NetClient;
 use net_server::NetServer;
+use replay::{ReplayPlayer, ReplayRecorder};
 use spin_sleep::LoopHelper;
 pub struct Manager {}
 
@@ -26,8 +32,20 @@ impl Manager {
                     },
                     net_client: None,
                     net_server: None,
+                    replay: None,
                 };
                 let mut net: Net = Net::Offline;
+                //Last lobby known, to pick a new host if ours is gone
+                let mut lobby: Option<(Lobby, Id<frame::Player>)> = None;
+                let mut host = Host {
+                    address: String::new(),
+                    retried: false,
+                    peer_tokens: FnvHashMap::default(),
+                };
+                let mut recorder: Option<ReplayRecorder> = None;
+                let mut replay: Option<ReplayPlayer> = None;
+                //Events replacing the next tick of the replay, after a seek
+                let mut replay_seek: Option<Vec<frame::FrameEventFromPlayer>> = None;
 
                 let frame0 = frame::Frame::new();
                 let _ =
@@ -44,24 +62,30 @@ impl Manager {
                     log::trace!("loop sleep");
                     loop_helper.loop_sleep();
                     global_info.manager.loop_time = loop_helper.loop_start();
-                    log::trace!("receive");
-                    //Receiving new frame
-                    let mut frame = match r_from_frame_server.recv() {
-                        Ok(frame_server::FromFrameServer::NewFrame(new_frame)) => new_frame,
-                        _ => panic!("frame_server disconnected"),
-                    };
-
                     //Receiving local player event
                     let mut player_inputs = Vec::new();
                     for from_client in r_from_client.try_iter() {
                         use client::FromClient;
                         match from_client {
                             FromClient::PlayerInput(event) => player_inputs.push(event),
-                            FromClient::StartClient(client::StartClient { bind }) => {
-                                net = Net::IsClient(NetClient::new(&bind))
+                            FromClient::StartClient(client::StartClient { bind, name }) => {
+                                lobby = None;
+                                host.address = bind.clone();
+                                net = Net::IsClient(NetClient::new(&bind, &name))
                             }
-                            FromClient::StartServer(client::StartServer { bind }) => {
-                                net = Net::IsServer(NetServer::new(&bind))
+                            FromClient::StartServer(client::StartServer { bind, name }) => {
+                                lobby = None;
+                                net = Net::IsServer(NetServer::new(&bind, &name))
+                            }
+                            FromClient::LobbyChange(change) => match &mut net {
+                                Net::IsServer(server) => server.change_lobby(change),
+                                Net::IsClient(net_client) => net_client.send_lobby_change(change),
+                                Net::Offline => {}
+                            },
+                            FromClient::StartGame => {
+                                if let Net::IsServer(server) = &mut net {
+                                    server.start_game();
+                                }
                             }
                             FromClient::DisconnectServer => {
                                 if let Net::IsServer(net_server) = &mut net {
@@ -77,9 +101,87 @@ impl Manager {
                                     net = Net::Offline;
                                 }
                             }
+                            FromClient::StartReplay { path } => match ReplayPlayer::load(&path) {
+                                Ok(mut player) => {
+                                    finish_recording(&mut recorder);
+                                    replay_seek = Some(player.seek(0));
+                                    replay = Some(player);
+                                }
+                                Err(e) => log::error!("Could not load replay {}: {}", path, e),
+                            },
+                            FromClient::StopReplay => {
+                                replay = None;
+                                replay_seek = None;
+                                global_info.replay = None;
+                                loop_helper =
+                                    LoopHelper::builder().build_with_target_rate(10.0_f64);
+                            }
+                            FromClient::ReplayPause(paused) => {
+                                if let Some(replay) = &mut replay {
+                                    replay.paused = paused;
+                                }
+                            }
+                            FromClient::ReplaySeek(tick) => {
+                                if let Some(replay) = &mut replay {
+                                    replay_seek = Some(replay.seek(tick));
+                                }
+                            }
+                            FromClient::ReplaySpeed(speed) => {
+                                if let Some(replay) = &mut replay {
+                                    replay.speed = speed;
+                                    loop_helper = LoopHelper::builder()
+                                        .build_with_target_rate(10.0_f64 * speed);
+                                }
+                            }
+                        }
+                    }
+
+                    //A paused replay keeps the frame being computed in the channel
+                    if let Some(replay) = &replay {
+                        global_info.replay = Some(replay.info());
+                        if replay.paused && replay_seek.is_none() {
+                            let _ = s_to_client_from_root_manager
+                                .send(ToClient::GlobalInfo(global_info));
+                            continue;
+                        }
+                    }
+
+                    //A client waiting for the server keeps the frame being computed in the channel
+                    if let Net::IsClient(net_client) = &mut net {
+                        if net_client.poll_server_ticks() == Some(0) {
+                            net_client.send_player_inputs(
+                                player_inputs
+                                    .into_iter()
+                                    .filter(|e| {
+                                        !matches!(e, frame::FrameEventFromPlayer::ReplaceFrame(_))
+                                    })
+                                    .collect(),
+                            );
+                            global_info.net_client = Some(net_client.get_info());
+                            let _ = s_to_client_from_root_manager
+                                .send(ToClient::GlobalInfo(global_info));
+                            continue;
                         }
                     }
 
+                    log::trace!("receive");
+                    //Receiving new frame
+                    let mut frame = match r_from_frame_server.recv() {
+                        Ok(frame_server::FromFrameServer::NewFrame(new_frame)) => new_frame,
+                        _ => panic!("frame_server disconnected"),
+                    };
+
+                    //If replaying : Local inputs are replaced by the recorded ones
+                    if let Some(replay) = &mut replay {
+                        player_inputs = match replay_seek.take() {
+                            Some(events) => events,
+                            None => replay.next_events().unwrap_or_else(|| {
+                                replay.paused = true;
+                                Vec::new()
+                            }),
+                        };
+                    }
+
                     //If local is client : Send player events
                     if let Net::IsClient(net_client) = &mut net {
                         net_client.send_player_inputs(
@@ -104,12 +206,32 @@ impl Manager {
                         events: player_inputs,
                     };
 
-                    //If local is client : Get remote frame (TEMPORARY TOTAL BYPASS OF LOCAL FRAME_SERVER)
+                    //If local is client : Compute the ticks of the server ourselves, corrected by its snapshots
                     if let Net::IsClient(net_client) = &mut net {
-                        data_to_compute_next_frame =
-                            net_client.collect_data_to_compute_next_frame().unwrap();
-
-                        frame = data_to_compute_next_frame.old_frame.clone();
+                        match net_client.collect_server_tick() {
+                            Some(tick) => {
+                                host.retried = false;
+                                if let Some(snapshot) = tick.snapshot {
+                                    frame = net_client.reconcile(frame, snapshot);
+                                } else if tick.number != frame.number {
+                                    log::debug!(
+                                        "Computing tick {} of the server on frame {}",
+                                        tick.number,
+                                        frame.number
+                                    );
+                                }
+                                data_to_compute_next_frame = frame::DataToComputeNextFrame {
+                                    old_frame: frame.clone(),
+                                    events: tick.events,
+                                };
+                            }
+                            None => {
+                                let token = net_client.token;
+                                host.peer_tokens.extend(net_client.peer_tokens.drain());
+                                global_info.net_client = None;
+                                net = reconnect(&mut lobby, &mut host, token);
+                            }
+                        }
                     }
                     //If local is server : Broadcast to remotes
                     else if let Net::IsServer(server) = &mut net {
@@ -118,6 +240,26 @@ impl Manager {
                         );
                     }
 
+                    //Recording every game, from the ReplaceFrame that starts it
+                    if replay.is_none() {
+                        for event in data_to_compute_next_frame.events.iter() {
+                            if let frame::FrameEventFromPlayer::ReplaceFrame(new_frame) = event {
+                                finish_recording(&mut recorder);
+                                if new_frame.heightmap_phy.is_some() {
+                                    recorder = ReplayRecorder::create(&ReplayRecorder::new_path())
+                                        .map_err(|e| log::error!("Could not record replay: {}", e))
+                                        .ok();
+                                }
+                            }
+                        }
+                        if let Some(rec) = &mut recorder {
+                            if let Err(e) = rec.record(&data_to_compute_next_frame) {
+                                log::error!("Stopped recording replay: {}", e);
+                                recorder = None;
+                            }
+                        }
+                    }
+
                     //Sending to local frame_server and local client
                     let _ = s_to_frame_server.send(
                         frame_server::ToFrameServer::DataToComputeNextFrame(
@@ -129,8 +271,22 @@ impl Manager {
                     //Gathering and sending GlobalInfo
                     if let Net::IsClient(net_client) = &mut net {
                         global_info.net_client = Some(net_client.get_info());
+                        if let Some((new_lobby, my_player_id)) = net_client.collect_lobby() {
+                            lobby = Some((new_lobby.clone(), my_player_id));
+                            let _ = s_to_client_from_root_manager.send(ToClient::Lobby {
+                                lobby: new_lobby,
+                                my_player_id,
+                            });
+                        }
                     } else if let Net::IsServer(server) = &mut net {
                         global_info.net_server = Some(server.get_info());
+                        if let Some(new_lobby) = server.collect_lobby() {
+                            lobby = Some((new_lobby.clone(), server.player_id));
+                            let _ = s_to_client_from_root_manager.send(ToClient::Lobby {
+                                lobby: new_lobby,
+                                my_player_id: server.player_id,
+                            });
+                        }
                     }
                     let _ = s_to_client_from_root_manager.send(ToClient::GlobalInfo(global_info));
                 }
@@ -138,6 +294,92 @@ impl Manager {
     }
 }
 
+///Server we play on as a client
+struct Host {
+    address: String,
+    ///Already tried to rejoin it since the last tick received
+    retried: bool,
+    ///Of the other clients of the game, kept across hosts
+    peer_tokens: FnvHashMap<Id<frame::Player>, PeerToken>,
+}
+
+///Lost our host: during a game we first try to rejoin it, then the first player left in join
+///order hosts the game from its own frame and the others rejoin it with the token they share.
+///Otherwise we play offline
+fn reconnect(
+    lobby: &mut Option<(Lobby, Id<frame::Player>)>,
+    host: &mut Host,
+    token: Option<u64>,
+) -> Net {
+    let (lobby, my_player_id, token) = match (lobby, token) {
+        (Some((lobby, my_player_id)), Some(token)) if lobby.started => {
+            (lobby, *my_player_id, token)
+        }
+        _ => {
+            log::info!("Disconnected from server, playing offline");
+            return Net::Offline;
+        }
+    };
+    if !host.retried {
+        host.retried = true;
+        log::info!("Host lost, rejoining it on {}", host.address);
+        return Net::IsClient(NetClient::rejoin(&host.address, my_player_id, token));
+    }
+    match lobby.elect_host().cloned() {
+        Some(elected) if elected.player_id == my_player_id => {
+            //The address peers saw us at may not be ours behind a NAT, listen on every interface
+            let port = elected.address.rsplit(':').next().unwrap_or_default();
+            let bind = format!("0.0.0.0:{}", port);
+            log::info!("Host lost, taking over on {}", bind);
+            let tokens = host
+                .peer_tokens
+                .iter()
+                .map(|(player_id, token)| (*player_id, token.accept))
+                .collect();
+            Net::IsServer(NetServer::with_lobby(
+                &bind,
+                lobby.clone(),
+                my_player_id,
+                tokens,
+            ))
+        }
+        Some(elected) => match host.peer_tokens.get(&elected.player_id) {
+            Some(peer_token) => {
+                log::info!(
+                    "Host lost, rejoining {} on {}",
+                    elected.name,
+                    elected.address
+                );
+                host.address = elected.address;
+                Net::IsClient(NetClient::rejoin(
+                    &host.address,
+                    my_player_id,
+                    peer_token.present,
+                ))
+            }
+            None => {
+                log::info!(
+                    "Host lost, no token to rejoin {}, playing offline",
+                    elected.name
+                );
+                Net::Offline
+            }
+        },
+        None => {
+            log::info!("Host lost and nobody left, playing offline");
+            Net::Offline
+        }
+    }
+}
+
+fn finish_recording(recorder: &mut Option<ReplayRecorder>) {
+    if let Some(recorder) = recorder.take() {
+        if let Err(e) = recorder.finish() {
+            log::error!("Could not finish replay: {}", e);
+        }
+    }
+}
+
 enum Net {
     Offline,
     IsServer(NetServer),
@@ -155,4 +397,5 @@ pub struct GlobalInfo {
     pub manager: ManagerInfo,
     pub net_server: Option<net_server::NetServerInfo>,
     pub net_client: Option<net_client::NetClientInfo>,
+    pub replay: Option<replay::ReplayInfo>,
 }
@@ -2,8 +2,10 @@ use super::frame::Player;
 use crate::botdef;
 use crate::unit;
 use crate::utils;
+use fnv::FnvHashMap;
 use na::{Matrix4, Point3, Vector2, Vector3};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
 use typename::TypeName;
 use utils::Id;
@@ -107,8 +109,38 @@ impl From<f32> for Angle {
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
 pub enum Command {
     None,
+    Move(Point3<f32>),
     Build(Id<KBot>),
     Repair(Id<KBot>),
+    Attack(Id<KBot>),
+    AttackGround(Point3<f32>),
+    Guard(Id<KBot>),
+    ///Loop between the waypoints, index is the current one
+    Patrol {
+        waypoints: Vec<Point3<f32>>,
+        index: usize,
+    },
+    ///Wrecks don't move, position is where it lies
+    Reclaim {
+        wreck: Id<Wreck>,
+        position: Point3<f32>,
+    },
+}
+
+impl Command {
+    ///Where the unit will be heading for this command, None if unknown
+    pub fn position(&self, kbots: &FnvHashMap<Id<KBot>, KBot>) -> Option<Point3<f32>> {
+        match self {
+            Command::None => None,
+            Command::Move(position)
+            | Command::AttackGround(position)
+            | Command::Reclaim { position, .. } => Some(*position),
+            Command::Build(id) | Command::Repair(id) | Command::Attack(id) | Command::Guard(id) => {
+                kbots.get(id).map(|kbot| kbot.position)
+            }
+            Command::Patrol { waypoints, .. } => waypoints.last().cloned(),
+        }
+    }
 }
 
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
@@ -122,6 +154,8 @@ pub struct KBot {
     pub up: Vector3<f32>,
     pub move_target: Option<Point3<f32>>,
     pub current_command: Command,
+    ///Shift queued commands, to do after current_command
+    pub command_queue: VecDeque<Command>,
     pub life: i32,
     pub con_completed: f32,
     pub player_id: Id<Player>,
@@ -129,9 +163,11 @@ pub struct KBot {
     pub grounded: bool,
     pub frame_last_shot: i32,
     pub weapon0_dir: Vector3<f32>,
+    pub weapon0_target: Option<Id<KBot>>,
     pub wheel0_angle: f32,
-    pub reload_frame_count: i32,
     pub botdef_id: Id<botdef::BotDef>,
+    ///Only for units whose BotDef is a factory
+    pub factory: Option<Factory>,
 }
 
 impl KBot {
@@ -146,18 +182,96 @@ impl KBot {
             up: Vector3::new(0.0, 0.0, 1.0),
             move_target: None,
             current_command: Command::None,
+            command_queue: VecDeque::new(),
             id: utils::rand_id(),
             frame_last_shot: 0,
-            reload_frame_count: 3,
             weapon0_dir: Vector3::new(1.0, 0.0, 0.0),
+            weapon0_target: None,
             wheel0_angle: 0.0,
             life: botdef.max_life,
             con_completed: 1.0,
             grounded: false,
             botdef_id: botdef.id,
+            factory: if botdef.is_factory {
+                Some(Factory::new())
+            } else {
+                None
+            },
             angular_velocity: 0.0,
         }
     }
+
+    ///Replace the current orders, or add to the queue when queued
+    pub fn order(&mut self, command: Command, queued: bool) {
+        let is_idle = self.current_command == Command::None && self.move_target.is_none();
+        if queued && !is_idle {
+            self.command_queue.push_back(command);
+        } else {
+            self.command_queue.clear();
+            self.start_command(command);
+        }
+    }
+
+    ///To call when current_command is done
+    pub fn next_command(&mut self) {
+        let next = self.command_queue.pop_front().unwrap_or(Command::None);
+        self.start_command(next);
+    }
+
+    fn start_command(&mut self, command: Command) {
+        self.move_target = match &command {
+            Command::Move(position) => Some(*position),
+            Command::Patrol { waypoints, index } => waypoints.get(*index).cloned(),
+            _ => None,
+        };
+        self.current_command = command;
+    }
+
+    ///Where the unit will be once its queue is done
+    pub fn queue_end_position(&self, kbots: &FnvHashMap<Id<KBot>, KBot>) -> Point3<f32> {
+        self.command_queue
+            .iter()
+            .rev()
+            .chain(std::iter::once(&self.current_command))
+            .find_map(|command| command.position(kbots))
+            .unwrap_or(self.position)
+    }
+}
+
+///Production state of a factory
+#[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Factory {
+    pub queue: VecDeque<Id<botdef::BotDef>>,
+    ///Finished units go back at the end of the queue
+    pub repeat: bool,
+    ///Where finished units walk to
+    pub rally_point: Option<Point3<f32>>,
+    ///Unit under construction at the exit
+    pub building: Option<Id<KBot>>,
+}
+
+impl Factory {
+    pub fn new() -> Self {
+        Factory {
+            queue: VecDeque::new(),
+            repeat: false,
+            rally_point: None,
+            building: None,
+        }
+    }
+}
+
+///Left where a completed unit died, builders reclaim its metal
+#[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Wreck {
+    pub id: Id<Wreck>,
+    pub position: Point3<f32>,
+    pub dir: Vector3<f32>,
+    pub up: Vector3<f32>,
+    ///Of the dead unit, for its part_tree and radius
+    pub botdef_id: Id<botdef::BotDef>,
+    ///Left to reclaim
+    pub metal: f64,
 }
 
 pub struct ClientKbot {
@@ -199,6 +313,9 @@ pub struct KinematicProjectile {
     pub speed_per_frame_at_birth: Vector3<f32>,
     pub accel_per_frame: Vector3<f32>,
     pub radius: f32,
+    ///Shooter's botdef, to find the weapon
+    pub botdef_id: Id<botdef::BotDef>,
+    pub team: u8,
 
     pub position_cache: Vec<Point3<f32>>,
     pub speed_cache: Vec<Vector3<f32>>,
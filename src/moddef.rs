@@ -3,15 +3,28 @@ use crate::unit;
 use crate::utils;
 use fnv::FnvHashMap;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use typename::TypeName;
 use utils::Id;
 
+pub const MOD_DIR: &str = "src/asset/mod";
+pub const DEFAULT_MOD: &str = "example";
+const BOTDEF_DIR: &str = "src/asset/botdef";
+
 #[derive(Clone, TypeName, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ModDef {
     pub units_id: Vec<Id<BotDef>>,
     pub con_map: FnvHashMap<Id<BotDef>, Vec<Id<BotDef>>>,
 }
 
+///Mod as written on disk, botdefs are named after their file in src/asset/botdef
+#[derive(Debug, Serialize, Deserialize)]
+struct ModFile {
+    ///The first one is what players start with
+    units: Vec<String>,
+    con_map: HashMap<String, Vec<String>>,
+}
+
 impl ModDef {
     pub fn new() -> Self {
         Self {
@@ -19,4 +32,60 @@ impl ModDef {
             con_map: FnvHashMap::default(),
         }
     }
+
+    ///Mod named after its file in src/asset/mod, along with the botdefs it uses
+    pub fn load_on_disk(name: &str) -> Result<(ModDef, FnvHashMap<Id<BotDef>, BotDef>), String> {
+        let path = format!("{}/{}.json", MOD_DIR, name);
+        let file = std::fs::File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let mod_file: ModFile = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut ids = HashMap::new();
+        let mut bot_defs = FnvHashMap::default();
+        let mut units_id = Vec::new();
+        for unit in mod_file.units.iter() {
+            let path = format!("{}/{}.json", BOTDEF_DIR, unit);
+            let file = std::fs::File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let botdef: BotDef = serde_json::from_reader(std::io::BufReader::new(file))
+                .map_err(|e| format!("{}: {}", path, e))?;
+            botdef.check().map_err(|e| format!("{}: {}", path, e))?;
+            ids.insert(unit.clone(), botdef.id);
+            units_id.push(botdef.id);
+            bot_defs.insert(botdef.id, botdef);
+        }
+
+        let id_of = |unit: &String| {
+            ids.get(unit)
+                .copied()
+                .ok_or_else(|| format!("{}: {} is not in units", path, unit))
+        };
+        let mut con_map = FnvHashMap::default();
+        for (builder, buildables) in mod_file.con_map.iter() {
+            let buildables = buildables.iter().map(id_of).collect::<Result<_, _>>()?;
+            con_map.insert(id_of(builder)?, buildables);
+        }
+
+        Ok((ModDef { units_id, con_map }, bot_defs))
+    }
+
+    ///Names of the mods in src/asset/mod
+    pub fn list_on_disk() -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(MOD_DIR)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| {
+                        let path = entry.path();
+                        if path.extension()? == "json" {
+                            Some(path.file_stem()?.to_str()?.to_owned())
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
 }
@@ -1,10 +1,18 @@
 use crate::frame::*;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::frame_delta::{self, FrameDecoder, ServerTick};
+use crate::lobby::{Lobby, LobbyChange};
+use crate::net_protocol::{FramedStream, NetMessage, PeerToken};
+use crate::utils::Id;
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use fnv::FnvHashMap;
 use spin_sleep::LoopHelper;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+///Ticks we may run behind the server before jumping to its latest snapshot
+const MAX_LAG: usize = 20;
+///Time an elected host has to start listening
+const REJOIN_PATIENCE: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone, Copy)]
 pub enum BindState {
@@ -17,30 +25,63 @@ pub enum BindState {
 #[derive(Debug, Clone, Copy)]
 pub struct NetClientInfo {
     bind_state: BindState,
+    ///Received but not computed yet
+    ticks_behind: usize,
+    ///Diverging KBots replaced by the last snapshot
+    kbots_corrected: usize,
+    snapshot_number: i32,
 }
 
 pub enum ToNetClientInner {
     PlayerInput(Vec<FrameEventFromPlayer>),
+    LobbyChange(LobbyChange),
+    Rejoin(Id<Player>, u64),
 }
 
 pub enum FromNetClientInner {
-    DataToComputeNextFrame(DataToComputeNextFrame),
+    ServerTick(ServerTick),
+    Token(u64),
+    PeerToken(Id<Player>, PeerToken),
 }
 
 pub struct NetClient {
     s: Sender<ToNetClientInner>,
     r: Receiver<FromNetClientInner>,
     info: NetClientInfo,
-    r_info: Receiver<NetClientInfo>,
+    r_bind_state: Receiver<BindState>,
     s_kill: Sender<()>,
+    r_lobby: Receiver<(Lobby, Id<Player>)>,
+    ticks: VecDeque<ServerTick>,
+    ///Given by the server with our slot, to rejoin as it
+    pub token: Option<u64>,
+    ///Shared with each other client, to rejoin it or be rejoined when the server is lost
+    pub peer_tokens: FnvHashMap<Id<Player>, PeerToken>,
 }
 
 impl NetClient {
-    pub fn new(bind: &str) -> Self {
+    pub fn new(bind: &str, name: &str) -> Self {
+        let first = ToNetClientInner::LobbyChange(LobbyChange::Name(name.to_owned()));
+        Self::connect(bind, first, Duration::from_secs(0))
+    }
+
+    ///Takes our slot back in a started game, from a host that may not be listening yet
+    pub fn rejoin(bind: &str, player_id: Id<Player>, token: u64) -> Self {
+        let mut net_client = Self::connect(
+            bind,
+            ToNetClientInner::Rejoin(player_id, token),
+            REJOIN_PATIENCE,
+        );
+        net_client.token = Some(token);
+        net_client
+    }
+
+    fn connect(bind: &str, first: ToNetClientInner, patience: Duration) -> Self {
         let (s_to, r_to) = unbounded::<ToNetClientInner>();
         let (s_from, r_from) = unbounded::<FromNetClientInner>();
+        let (s_lobby, r_lobby) = unbounded::<(Lobby, Id<Player>)>();
+        let _ = s_to.send(first);
 
-        let (s_info, r_info) = unbounded::<NetClientInfo>();
+        let (s_bind_state, r_bind_state) = unbounded::<BindState>();
 
         let (s_kill, r_kill) = unbounded::<()>();
 
@@ -48,92 +89,186 @@ impl NetClient {
         std::thread::spawn(move || {
             let s = s_from;
             let r = r_to;
-            let s_info = s_info;
-
-            match TcpStream::connect(bind_addr) {
-                Ok(mut stream) => {
-                    s_info
-                        .try_send(NetClientInfo {
-                            bind_state: BindState::Success,
-                        })
-                        .unwrap();
-
-                    let _ = stream.set_read_timeout(Some(std::time::Duration::from_millis(2)));
-                    let _ = stream.set_nodelay(true);
+            let s_bind_state = s_bind_state;
+
+            let deadline = Instant::now() + patience;
+            let mut connected = FramedStream::connect(&bind_addr);
+            while connected.is_err() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(100));
+                connected = FramedStream::connect(&bind_addr);
+            }
+            match connected {
+                Ok(mut framed) => {
+                    let _ = s_bind_state.try_send(BindState::Success);
                     log::info!("Connection established!");
+                    let mut decoder = FrameDecoder::new();
+                    let mut my_player_id = None;
 
                     let mut loop_helper = LoopHelper::builder().build_with_target_rate(100.0_f64);
                     'streamloop: loop {
                         loop_helper.loop_sleep();
                         loop_helper.loop_start();
-                        match r.try_recv() {
-                            Ok(ToNetClientInner::PlayerInput(fe)) => {
-                                log::trace!("stream: Sending local player input to remote server");
-                                bincode::serialize_into(&mut stream, &fe).unwrap();
-                            }
-                            _ => {
-                                log::trace!("no player input to send");
+                        //Killed, or the NetClient is gone
+                        if let Ok(()) | Err(TryRecvError::Disconnected) = r_kill.try_recv() {
+                            framed.close();
+                            break 'streamloop;
+                        }
+
+                        for msg in r.try_iter() {
+                            let message = match msg {
+                                ToNetClientInner::PlayerInput(fe) => {
+                                    log::trace!(
+                                        "stream: Sending local player input to remote server"
+                                    );
+                                    NetMessage::PlayerInputs(fe)
+                                }
+                                ToNetClientInner::LobbyChange(change) => {
+                                    NetMessage::LobbyChange(change)
+                                }
+                                ToNetClientInner::Rejoin(player_id, token) => {
+                                    NetMessage::Rejoin { player_id, token }
+                                }
+                            };
+                            if let Err(e) = framed.send(&message) {
+                                log::info!("Lost server: {}", e);
+                                break 'streamloop;
                             }
                         }
 
                         log::trace!("read");
-                        let result_bincode: bincode::Result<DataToComputeNextFrame> =
-                            bincode::deserialize_from(&mut stream);
-                        match result_bincode {
-                            Ok(data) => {
-                                log::trace!("   Receive Frame from remote server");
-                                let _ =
-                                    s.try_send(FromNetClientInner::DataToComputeNextFrame(data));
+                        let messages = match framed.receive() {
+                            Ok(messages) => messages,
+                            Err(e) => {
+                                log::info!("Lost server: {}", e);
+                                break 'streamloop;
                             }
-                            x => {
-                                log::trace!("   Error read {:?}", x);
+                        };
+                        for message in messages {
+                            match message {
+                                NetMessage::Frame(bytes) => match frame_delta::decompress(&bytes) {
+                                    Ok(message) => {
+                                        log::trace!("   Receive Frame from remote server");
+                                        if let Some(tick) = decoder.decode(message) {
+                                            let _ =
+                                                s.try_send(FromNetClientInner::ServerTick(tick));
+                                        }
+                                    }
+                                    Err(e) => log::warn!("Bad frame from server: {}", e),
+                                },
+                                NetMessage::Assign { player_id, token } => {
+                                    log::info!("Playing as {:?}", player_id);
+                                    my_player_id = Some(player_id);
+                                    let _ = s.try_send(FromNetClientInner::Token(token));
+                                }
+                                NetMessage::PeerToken { player_id, token } => {
+                                    let _ =
+                                        s.try_send(FromNetClientInner::PeerToken(player_id, token));
+                                }
+                                NetMessage::Lobby(lobby) => {
+                                    if let Some(player_id) = my_player_id {
+                                        let _ = s_lobby.try_send((lobby, player_id));
+                                    }
+                                }
+                                NetMessage::Goodbye => {
+                                    log::info!("Server closed the connection");
+                                    break 'streamloop;
+                                }
+                                _ => log::warn!("Unexpected message from remote server"),
                             }
                         }
 
-                        if let Ok(()) = r_kill.try_recv() {
-                            let _ = s_info.try_send(NetClientInfo {
-                                bind_state: BindState::Disconnected,
-                            });
+                        if let Err(e) = framed.keep_alive() {
+                            log::info!("Lost server: {}", e);
                             break 'streamloop;
                         }
                     }
-                    log::info!("Killed");
+                    let _ = s_bind_state.try_send(BindState::Disconnected);
+                    log::info!("Disconnected");
                 }
-                _ => {
-                    s_info
-                        .try_send(NetClientInfo {
-                            bind_state: BindState::Error,
-                        })
-                        .unwrap();
+                Err(e) => {
+                    log::error!("Could not join {}: {}", bind_addr, e);
+                    let _ = s_bind_state.try_send(BindState::Error);
                 }
             }
         });
         NetClient {
             s: s_to,
             r: r_from,
-            r_info,
+            r_bind_state,
             info: NetClientInfo {
                 bind_state: BindState::Unknown,
+                ticks_behind: 0,
+                kbots_corrected: 0,
+                snapshot_number: 0,
             },
             s_kill,
+            r_lobby,
+            ticks: VecDeque::new(),
+            token: None,
+            peer_tokens: FnvHashMap::default(),
         }
     }
 
     pub fn kill(&mut self) {
-        self.s_kill.try_send(()).unwrap();
+        let _ = self.s_kill.try_send(());
     }
 
-    pub fn collect_data_to_compute_next_frame(&mut self) -> Option<DataToComputeNextFrame> {
-        if self.r.is_empty() {
-            match self.r.recv() {
-                Ok(FromNetClientInner::DataToComputeNextFrame(data)) => Some(data),
-                _ => None,
+    ///Ticks of the server received so far, without waiting, None once disconnected with none left
+    pub fn poll_server_ticks(&mut self) -> Option<usize> {
+        loop {
+            match self.r.try_recv() {
+                Ok(msg) => self.receive(msg),
+                Err(TryRecvError::Empty) => return Some(self.ticks.len()),
+                Err(TryRecvError::Disconnected) => {
+                    return Some(self.ticks.len()).filter(|&len| len > 0)
+                }
             }
-        } else {
-            match self.r.try_iter().last().unwrap() {
-                FromNetClientInner::DataToComputeNextFrame(data) => Some(data),
+        }
+    }
+
+    ///Next tick of the server, None if none arrived yet or once disconnected
+    pub fn collect_server_tick(&mut self) -> Option<ServerTick> {
+        self.poll_server_ticks()?;
+
+        //Too far behind to catch up one tick at a time
+        if self.ticks.len() > MAX_LAG {
+            if let Some(latest) = self.ticks.iter().rposition(|tick| tick.snapshot.is_some()) {
+                log::warn!("Skipping {} ticks to catch up with the server", latest);
+                self.ticks.drain(..latest);
             }
         }
+        self.info.ticks_behind = self.ticks.len().saturating_sub(1);
+        self.ticks.pop_front()
+    }
+
+    fn receive(&mut self, msg: FromNetClientInner) {
+        match msg {
+            FromNetClientInner::ServerTick(tick) => self.ticks.push_back(tick),
+            FromNetClientInner::Token(token) => self.token = Some(token),
+            FromNetClientInner::PeerToken(player_id, token) => {
+                self.peer_tokens.insert(player_id, token);
+            }
+        }
+    }
+
+    ///Our own frame, corrected by the snapshot of the same tick
+    pub fn reconcile(&mut self, frame: Frame, snapshot: Frame) -> Frame {
+        self.info.snapshot_number = snapshot.number;
+        let (frame, corrected) = frame_delta::reconcile(frame, snapshot);
+        self.info.kbots_corrected = corrected;
+        if corrected > 0 {
+            log::debug!("Corrected {} kbots at frame {}", corrected, frame.number);
+        }
+        frame
+    }
+
+    ///The lobby and the player given to us, when it changed since the last call
+    pub fn collect_lobby(&mut self) -> Option<(Lobby, Id<Player>)> {
+        self.r_lobby.try_iter().last()
+    }
+
+    pub fn send_lobby_change(&mut self, change: LobbyChange) {
+        let _ = self.s.try_send(ToNetClientInner::LobbyChange(change));
     }
 
     pub fn send_player_inputs(&mut self, player_inputs: Vec<FrameEventFromPlayer>) {
@@ -146,9 +281,8 @@ impl NetClient {
     }
 
     pub fn get_info(&mut self) -> NetClientInfo {
-        let last = self.r_info.try_iter().last();
-        if let Some(info) = last {
-            self.info = info;
+        if let Some(bind_state) = self.r_bind_state.try_iter().last() {
+            self.info.bind_state = bind_state;
         }
         self.info
     }
@@ -0,0 +1,515 @@
+use crate::frame::{FrameEventFromPlayer, Player};
+use crate::frame_delta::MAX_MAP_SIZE;
+use crate::lobby::{Lobby, LobbyChange};
+use crate::utils::Id;
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+///Bumped on any change of the messages, peers of another version are rejected
+pub const PROTOCOL_VERSION: u32 = 8;
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+///Silence after which the peer is considered gone
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+///Keyframes holding the f32 texels of the biggest heightmap are the biggest messages, twice
+///that leaves room for the rest of the frame
+const MAX_MESSAGE_LEN: usize = 2 * MAX_MAP_SIZE * MAX_MAP_SIZE * 4;
+///Unsent bytes a peer too slow to read may leave us with before being dropped
+const MAX_WRITE_BUFFER_LEN: usize = 2 * MAX_MESSAGE_LEN;
+///Payload length as u32 then tag as u8
+const HEADER_LEN: usize = 5;
+
+#[derive(Clone)]
+pub enum NetMessage {
+    ///First message of a client
+    Hello {
+        version: u32,
+    },
+    ///Server answer to a Hello of the same version
+    Welcome,
+    ///Server answer to a Hello of another version, before closing
+    Reject {
+        version: u32,
+    },
+    Heartbeat,
+    PlayerInputs(Vec<FrameEventFromPlayer>),
+    ///Compressed frame_delta::FrameMessage
+    Frame(Vec<u8>),
+    ///Clean disconnection
+    Goodbye,
+    ///Whole lobby, sent by the server on every change
+    Lobby(Lobby),
+    ///Player the client plays as, sent by the server once joined, with the token to rejoin as it
+    Assign {
+        player_id: Id<Player>,
+        token: u64,
+    },
+    ///Asked by a client for its own slot
+    LobbyChange(LobbyChange),
+    ///First message of a client taking its slot back in a started game
+    Rejoin {
+        player_id: Id<Player>,
+        token: u64,
+    },
+    ///Sent by the server to both clients of each pair, in case one of them hosts the game
+    PeerToken {
+        player_id: Id<Player>,
+        token: PeerToken,
+    },
+}
+
+///Shared by two clients only: the one elected host when the server is lost checks the
+///rejoin of the other with it, no other peer could take the slot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerToken {
+    ///To rejoin the peer when it hosts
+    pub present: u64,
+    ///The peer rejoins us with it when we host
+    pub accept: u64,
+}
+
+impl NetMessage {
+    fn tag(&self) -> u8 {
+        match self {
+            NetMessage::Hello { .. } => 0,
+            NetMessage::Welcome => 1,
+            NetMessage::Reject { .. } => 2,
+            NetMessage::Heartbeat => 3,
+            NetMessage::PlayerInputs(_) => 4,
+            NetMessage::Frame(_) => 5,
+            NetMessage::Goodbye => 6,
+            NetMessage::Lobby(_) => 7,
+            NetMessage::Assign { .. } => 8,
+            NetMessage::LobbyChange(_) => 9,
+            NetMessage::Rejoin { .. } => 10,
+            NetMessage::PeerToken { .. } => 11,
+        }
+    }
+
+    fn write_payload(&self, out: &mut Vec<u8>) -> bincode::Result<()> {
+        match self {
+            NetMessage::Hello { version } | NetMessage::Reject { version } => {
+                bincode::serialize_into(out, version)
+            }
+            NetMessage::PlayerInputs(player_inputs) => bincode::serialize_into(out, player_inputs),
+            NetMessage::Frame(bytes) => {
+                out.extend_from_slice(bytes);
+                Ok(())
+            }
+            NetMessage::Lobby(lobby) => bincode::serialize_into(out, lobby),
+            NetMessage::Assign { player_id, token } | NetMessage::Rejoin { player_id, token } => {
+                bincode::serialize_into(out, &(player_id, token))
+            }
+            NetMessage::LobbyChange(change) => bincode::serialize_into(out, change),
+            NetMessage::PeerToken { player_id, token } => {
+                bincode::serialize_into(out, &(player_id, token.present, token.accept))
+            }
+            NetMessage::Welcome | NetMessage::Heartbeat | NetMessage::Goodbye => Ok(()),
+        }
+    }
+
+    fn read_payload(tag: u8, payload: &[u8]) -> bincode::Result<Self> {
+        match tag {
+            0 => Ok(NetMessage::Hello {
+                version: bincode::deserialize(payload)?,
+            }),
+            1 => Ok(NetMessage::Welcome),
+            2 => Ok(NetMessage::Reject {
+                version: bincode::deserialize(payload)?,
+            }),
+            3 => Ok(NetMessage::Heartbeat),
+            4 => Ok(NetMessage::PlayerInputs(bincode::deserialize(payload)?)),
+            5 => Ok(NetMessage::Frame(payload.to_vec())),
+            6 => Ok(NetMessage::Goodbye),
+            7 => Ok(NetMessage::Lobby(bincode::deserialize(payload)?)),
+            8 => {
+                let (player_id, token) = bincode::deserialize(payload)?;
+                Ok(NetMessage::Assign { player_id, token })
+            }
+            9 => Ok(NetMessage::LobbyChange(bincode::deserialize(payload)?)),
+            10 => {
+                let (player_id, token) = bincode::deserialize(payload)?;
+                Ok(NetMessage::Rejoin { player_id, token })
+            }
+            11 => {
+                let (player_id, present, accept) = bincode::deserialize(payload)?;
+                Ok(NetMessage::PeerToken {
+                    player_id,
+                    token: PeerToken { present, accept },
+                })
+            }
+            _ => Err(Box::new(bincode::ErrorKind::Custom(format!(
+                "Unknown message tag {}",
+                tag
+            )))),
+        }
+    }
+}
+
+///Non blocking TcpStream cut into whole NetMessages, partial reads and writes are buffered
+pub struct FramedStream {
+    stream: TcpStream,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    ///Received along with the handshake answer
+    pending: VecDeque<NetMessage>,
+    last_received: Instant,
+    last_sent: Instant,
+}
+
+impl FramedStream {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(FramedStream {
+            stream,
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            pending: VecDeque::new(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+        })
+    }
+
+    ///Client side of the handshake
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let mut framed = FramedStream::new(TcpStream::connect(addr)?)?;
+        framed.send(&NetMessage::Hello {
+            version: PROTOCOL_VERSION,
+        })?;
+        match framed.wait_message()? {
+            NetMessage::Welcome => Ok(framed),
+            NetMessage::Reject { version } => Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "Server speaks protocol version {}, we speak {}",
+                    version, PROTOCOL_VERSION
+                ),
+            )),
+            message => Err(unexpected(&message)),
+        }
+    }
+
+    ///Server side of the handshake
+    pub fn accept(stream: TcpStream) -> io::Result<Self> {
+        let mut framed = FramedStream::new(stream)?;
+        match framed.wait_message()? {
+            NetMessage::Hello { version } if version == PROTOCOL_VERSION => {
+                framed.send(&NetMessage::Welcome)?;
+                Ok(framed)
+            }
+            NetMessage::Hello { version } => {
+                let _ = framed.send(&NetMessage::Reject {
+                    version: PROTOCOL_VERSION,
+                });
+                framed.close();
+                Err(io::Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!(
+                        "Client speaks protocol version {}, we speak {}",
+                        version, PROTOCOL_VERSION
+                    ),
+                ))
+            }
+            message => Err(unexpected(&message)),
+        }
+    }
+
+    ///Blocks until a message comes, only for the handshake
+    fn wait_message(&mut self) -> io::Result<NetMessage> {
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let mut messages = VecDeque::from(self.receive()?);
+            if let Some(message) = messages.pop_front() {
+                self.pending = messages;
+                return Ok(message);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "No answer to handshake",
+        ))
+    }
+
+    pub fn send(&mut self, message: &NetMessage) -> io::Result<()> {
+        let start = self.write_buffer.len();
+        self.write_buffer.extend_from_slice(&[0; HEADER_LEN]);
+        if let Err(e) = message.write_payload(&mut self.write_buffer) {
+            self.write_buffer.truncate(start);
+            return Err(io::Error::new(ErrorKind::InvalidData, e));
+        }
+        let len = (self.write_buffer.len() - start - HEADER_LEN) as u32;
+        self.write_buffer[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        self.write_buffer[start + 4] = message.tag();
+        self.last_sent = Instant::now();
+        self.flush()?;
+        if self.write_buffer.len() > MAX_WRITE_BUFFER_LEN {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Peer left {} bytes unread", self.write_buffer.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    ///Writes what the socket takes without blocking, the rest waits for the next call
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.write_buffer.len() {
+            match self.stream.write(&self.write_buffer[written..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "Connection closed")),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.write_buffer.drain(..written);
+        Ok(())
+    }
+
+    ///Every whole message received so far, heartbeats only keep the connection alive
+    pub fn receive(&mut self) -> io::Result<Vec<NetMessage>> {
+        let mut closed = false;
+        let mut chunk = [0; 64 * 1024];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.read_buffer.extend_from_slice(&chunk[..n]);
+                    self.last_received = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut messages: Vec<NetMessage> = self.pending.drain(..).collect();
+        let mut offset = 0;
+        while self.read_buffer.len() - offset >= HEADER_LEN {
+            let mut len = [0; 4];
+            len.copy_from_slice(&self.read_buffer[offset..offset + 4]);
+            let len = u32::from_le_bytes(len) as usize;
+            if len > MAX_MESSAGE_LEN {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Message of {} bytes is too big", len),
+                ));
+            }
+            if self.read_buffer.len() - offset < HEADER_LEN + len {
+                break;
+            }
+            let tag = self.read_buffer[offset + 4];
+            let payload = &self.read_buffer[offset + HEADER_LEN..offset + HEADER_LEN + len];
+            match NetMessage::read_payload(tag, payload)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+            {
+                NetMessage::Heartbeat => {}
+                message => messages.push(message),
+            }
+            offset += HEADER_LEN + len;
+        }
+        self.read_buffer.drain(..offset);
+
+        //Messages sent right before closing still count
+        if closed && messages.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Connection closed by peer",
+            ));
+        }
+        Ok(messages)
+    }
+
+    ///Sends a heartbeat after a quiet second, fails once the peer is silent for too long
+    pub fn keep_alive(&mut self) -> io::Result<()> {
+        if self.last_received.elapsed() > TIMEOUT {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Peer stopped answering",
+            ));
+        }
+        if self.last_sent.elapsed() > HEARTBEAT_INTERVAL {
+            self.send(&NetMessage::Heartbeat)
+        } else {
+            self.flush()
+        }
+    }
+
+    ///Says goodbye and shuts the connection down, errors don't matter anymore
+    pub fn close(mut self) {
+        let _ = self.send(&NetMessage::Goodbye);
+        let deadline = Instant::now() + Duration::from_millis(200);
+        while !self.write_buffer.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+            if self.flush().is_err() {
+                break;
+            }
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn unexpected(message: &NetMessage) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Unexpected message tag {} during handshake", message.tag()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    ///Server side accepted in a thread, client side connected through the handshake
+    fn connected_pair() -> (FramedStream, FramedStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            FramedStream::accept(stream).unwrap()
+        });
+        let client = FramedStream::connect(&addr).unwrap();
+        (server.join().unwrap(), client)
+    }
+
+    ///Server side accepted in a thread, client side a raw stream that already said Hello
+    fn raw_client() -> (FramedStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        client
+            .write_all(&encode(&NetMessage::Hello {
+                version: PROTOCOL_VERSION,
+            }))
+            .unwrap();
+        (FramedStream::accept(stream).unwrap(), client)
+    }
+
+    fn encode(message: &NetMessage) -> Vec<u8> {
+        let mut payload = Vec::new();
+        message.write_payload(&mut payload).unwrap();
+        let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+        bytes.push(message.tag());
+        bytes.extend(payload);
+        bytes
+    }
+
+    ///Receives until count messages came or a second passed
+    fn receive_n(framed: &mut FramedStream, count: usize) -> Vec<NetMessage> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut messages = Vec::new();
+        while messages.len() < count && Instant::now() < deadline {
+            messages.extend(framed.receive().unwrap());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        messages
+    }
+
+    #[test]
+    fn peer_tokens_keep_their_direction() {
+        let token = PeerToken {
+            present: 1,
+            accept: 2,
+        };
+        let message = NetMessage::PeerToken {
+            player_id: Id::new(3),
+            token,
+        };
+        let mut payload = Vec::new();
+        message.write_payload(&mut payload).unwrap();
+        match NetMessage::read_payload(message.tag(), &payload).unwrap() {
+            NetMessage::PeerToken {
+                player_id,
+                token: received,
+            } => assert_eq!((player_id, received), (Id::new(3), token)),
+            _ => panic!("wrong message"),
+        }
+    }
+
+    #[test]
+    fn messages_arrive_whole_and_in_order() {
+        let (mut server, mut client) = connected_pair();
+        let big: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+        client.send(&NetMessage::Frame(vec![1, 2, 3])).unwrap();
+        client.send(&NetMessage::Heartbeat).unwrap();
+        client.send(&NetMessage::Frame(big.clone())).unwrap();
+        client
+            .send(&NetMessage::Rejoin {
+                player_id: Id::new(7),
+                token: 42,
+            })
+            .unwrap();
+        //The big frame doesn't fit the socket at once
+        let mut messages = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !client.write_buffer.is_empty() && Instant::now() < deadline {
+            client.flush().unwrap();
+            messages.extend(server.receive().unwrap());
+        }
+        messages.extend(receive_n(&mut server, 3 - messages.len()));
+        assert_eq!(messages.len(), 3, "heartbeats are not returned");
+        match &messages[..] {
+            [NetMessage::Frame(small), NetMessage::Frame(received), NetMessage::Rejoin { player_id, token }] =>
+            {
+                assert_eq!(small, &vec![1, 2, 3]);
+                assert!(received == &big);
+                assert_eq!((*player_id, *token), (Id::new(7), 42));
+            }
+            _ => panic!("wrong messages"),
+        }
+    }
+
+    #[test]
+    fn partial_messages_wait_for_the_rest() {
+        let (mut server, mut client) = raw_client();
+        let bytes = encode(&NetMessage::Frame(vec![9; 1000]));
+        for chunk in &[
+            &bytes[..3],
+            &bytes[3..HEADER_LEN + 10],
+            &bytes[HEADER_LEN + 10..],
+        ] {
+            assert!(receive_n(&mut server, 1).is_empty());
+            client.write_all(chunk).unwrap();
+        }
+        match &receive_n(&mut server, 1)[..] {
+            [NetMessage::Frame(payload)] => assert_eq!(payload, &vec![9; 1000]),
+            _ => panic!("wrong messages"),
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let (mut server, mut client) = raw_client();
+        let mut header = ((MAX_MESSAGE_LEN + 1) as u32).to_le_bytes().to_vec();
+        header.push(5);
+        client.write_all(&header).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        loop {
+            match server.receive() {
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::InvalidData);
+                    break;
+                }
+                Ok(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(1)),
+                Ok(_) => panic!("oversized message accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn peer_not_reading_is_dropped() {
+        let (mut server, _client) = raw_client();
+        let frame = NetMessage::Frame(vec![0; MAX_MESSAGE_LEN / 4]);
+        let sends = (0..16).take_while(|_| server.send(&frame).is_ok()).count();
+        assert!(sends < 16, "write buffer grew without bound");
+    }
+}
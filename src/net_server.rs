@@ -1,9 +1,17 @@
 use crate::frame::*;
-use crossbeam_channel::{unbounded, Receiver, Sender};
+use crate::frame_delta::{self, FrameEncoder};
+use crate::lobby::{self, Lobby, LobbyChange};
+use crate::moddef;
+use crate::net_protocol::{FramedStream, NetMessage, PeerToken};
+use crate::utils::Id;
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use fnv::{FnvHashMap, FnvHashSet};
 use spin_sleep::LoopHelper;
-use std::io::prelude::*;
-use std::net::TcpListener;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+
+///Time the slot of a player gone during the game is kept for a rejoin
+const GRACE_PERIOD: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BindState {
@@ -16,14 +24,37 @@ pub enum BindState {
 pub struct NetServerInfo {
     bind_state: BindState,
     number_of_client_connected: usize,
+    ///Size of the last message before and after compression
+    message_bytes: usize,
+    compressed_bytes: usize,
+    ///Time to encode and compress the last message
+    encode_time: std::time::Duration,
+    ///Sent to each client during the last second
+    bytes_per_sec: usize,
+}
+
+impl NetServerInfo {
+    fn new() -> Self {
+        NetServerInfo {
+            bind_state: BindState::Unknown,
+            number_of_client_connected: 0,
+            message_bytes: 0,
+            compressed_bytes: 0,
+            encode_time: std::time::Duration::from_millis(0),
+            bytes_per_sec: 0,
+        }
+    }
 }
 
 pub enum ToNetServerInner {
     DataToComputeNextFrame(DataToComputeNextFrame),
+    LobbyChange(LobbyChange),
+    StartGame,
 }
 
 pub enum FromNetServerInner {
     PlayerInputs(Vec<FrameEventFromPlayer>),
+    Lobby(Lobby),
 }
 
 pub struct NetServer {
@@ -31,46 +62,108 @@ pub struct NetServer {
     r_inner: Receiver<FromNetServerInner>,
     info: NetServerInfo,
     r_info: Receiver<NetServerInfo>,
+    s_kill: Sender<()>,
+    ///Slot of the host in the lobby
+    pub player_id: Id<Player>,
+    player_inputs: Vec<FrameEventFromPlayer>,
+    lobby: Option<Lobby>,
 }
 
 impl NetServer {
-    pub fn new(bind: &str) -> Self {
+    pub fn new(bind: &str, name: &str) -> Self {
+        let mut lobby = Lobby::new(
+            lobby::DEFAULT_MAP.to_owned(),
+            moddef::DEFAULT_MOD.to_owned(),
+        );
+        let player_id = lobby.join(name.to_owned(), true, bind.to_owned()).unwrap();
+        Self::with_lobby(bind, lobby, player_id, FnvHashMap::default())
+    }
+
+    ///Hosts a lobby made elsewhere, like the one of a started game whose host is gone,
+    ///its players rejoin with the tokens we share with them
+    pub fn with_lobby(
+        bind: &str,
+        lobby: Lobby,
+        player_id: Id<Player>,
+        tokens: FnvHashMap<Id<Player>, u64>,
+    ) -> Self {
         let (s_to, r_to) = unbounded::<ToNetServerInner>();
         let (s_from, r_from) = unbounded::<FromNetServerInner>();
 
         let (s_info, r_info) = unbounded::<NetServerInfo>();
+        let (s_kill, r_kill) = unbounded::<()>();
         let bind_addr = bind.to_owned();
+        //Clients would host on the same port if elected
+        let port = bind.rsplit(':').next().unwrap_or_default().to_owned();
         std::thread::spawn(move || {
             let r = r_to;
             let s = s_from;
 
             let s_info = s_info;
-            let mut info = NetServerInfo {
-                bind_state: BindState::Unknown,
-                number_of_client_connected: 0,
-            };
+            let mut info = NetServerInfo::new();
+            let mut encoders: FnvHashMap<Option<u8>, FrameEncoder> = FnvHashMap::default();
+            encoders.insert(None, FrameEncoder::new(None));
+            let mut second_start = std::time::Instant::now();
+            let mut bytes_this_second = 0;
+            let mut lobby = lobby;
+            let mut lobby_changed = true;
+            //Of each slot, to rejoin as it
+            let mut tokens = tokens;
+            //Players gone during the game, with the time they left
+            let mut away: FnvHashMap<Id<Player>, Instant> = FnvHashMap::default();
+            //Taking over a game, everyone else has to rejoin
+            for slot in lobby.slots.iter_mut().filter(|slot| !slot.host) {
+                slot.connected = false;
+                away.insert(slot.player_id, Instant::now());
+            }
 
-            let mut net_streams = Vec::new();
+            let mut net_streams: Vec<NetStream> = Vec::new();
             //Thread that will give us the connected clients
             let (s_bind_state, r_bind_state) = unbounded::<BindState>();
             let (s_of_net_stream, r_of_net_stream) = unbounded::<NetStream>();
+            //Dropped when this thread ends, which stops the listener
+            let (_s_listening, r_listening) = unbounded::<()>();
             std::thread::spawn(move || match TcpListener::bind(bind_addr) {
                 Ok(listener) => {
-                    s_bind_state.send(BindState::Success).unwrap();
-                    for stream in listener.incoming() {
-                        let stream = stream.unwrap();
-                        log::info!("Connection established!");
-                        let net_stream = NetStream::new(stream);
-                        s_of_net_stream.try_send(net_stream).unwrap();
+                    let _ = s_bind_state.send(BindState::Success);
+                    let _ = listener.set_nonblocking(true);
+                    while let Err(TryRecvError::Empty) = r_listening.try_recv() {
+                        match listener.accept() {
+                            Ok((stream, addr)) => {
+                                log::info!("Connection from {}", addr);
+                                //Handshake away from the listener, a slow client doesn't block others
+                                let s_of_net_stream = s_of_net_stream.clone();
+                                std::thread::spawn(move || match FramedStream::accept(stream) {
+                                    Ok(framed) => {
+                                        let _ = s_of_net_stream.send(NetStream::new(framed, addr));
+                                    }
+                                    Err(e) => log::warn!("Handshake with {} failed: {}", addr, e),
+                                });
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                std::thread::sleep(std::time::Duration::from_millis(10));
+                            }
+                            Err(e) => log::warn!("Could not accept connection: {}", e),
+                        }
                     }
                 }
-                _ => {
-                    s_bind_state.send(BindState::Error).unwrap();
+                Err(e) => {
+                    log::error!("Could not bind: {}", e);
+                    let _ = s_bind_state.send(BindState::Error);
                 }
             });
 
             let mut loop_helper = LoopHelper::builder().build_with_target_rate(100.0_f64);
             loop {
+                //Killed, or the NetServer is gone
+                if let Ok(()) | Err(TryRecvError::Disconnected) = r_kill.try_recv() {
+                    for net_stream in net_streams.iter_mut() {
+                        net_stream.kill();
+                    }
+                    log::info!("Server stopped");
+                    break;
+                }
+
                 if info.bind_state == BindState::Unknown {
                     if let Some(bind_state) = r_bind_state.try_iter().last() {
                         info.bind_state = bind_state;
@@ -81,19 +174,108 @@ impl NetServer {
                 loop_helper.loop_start();
                 let net_streams = &mut net_streams;
                 match r_of_net_stream.try_recv() {
-                    Ok(net_stream) => {
-                        log::info!("Connection taken care of");
-                        net_streams.push(net_stream);
+                    Ok(net_stream) if lobby.started => net_streams.push(net_stream),
+                    Ok(mut net_stream) => {
+                        let name = format!("Player {}", lobby.slots.len() + 1);
+                        let address = format!("{}:{}", net_stream.addr.ip(), port);
+                        match lobby.join(name, false, address) {
+                            Some(player_id) => {
+                                log::info!("Client joined as {:?}", player_id);
+                                let token = rand::random();
+                                tokens.insert(player_id, token);
+                                net_stream.assign(player_id, token);
+                                net_streams.push(net_stream);
+                                let index = net_streams.len() - 1;
+                                share_peer_tokens(net_streams, index);
+                                lobby_changed = true;
+                            }
+                            None => {
+                                log::info!("Client turned away, lobby full or game started");
+                                net_stream.kill();
+                            }
+                        }
                     }
                     _ => {}
                 }
 
                 //Block on waiting new frames
                 match r.try_recv() {
+                    Ok(ToNetServerInner::LobbyChange(change)) => {
+                        lobby_changed |= lobby.apply(player_id, change);
+                    }
+                    Ok(ToNetServerInner::StartGame) => {
+                        if lobby.can_start() {
+                            lobby.started = true;
+                            lobby_changed = true;
+                        }
+                    }
                     Ok(ToNetServerInner::DataToComputeNextFrame(data)) => {
-                        let bytes = bincode::serialize(&data).unwrap();
-                        for net_stream in net_streams.iter_mut() {
-                            net_stream.send_data_to_compute_next_frame(bytes.clone())
+                        let start = std::time::Instant::now();
+                        let players = &data.old_frame.players;
+                        let team_of = |net_stream: &NetStream| {
+                            net_stream
+                                .player_id
+                                .and_then(|player_id| players.get(&player_id))
+                                .map(|player| player.team)
+                        };
+
+                        //Each team gets only what it sees, from its own encoder. The one of the
+                        //spectators always runs, the others start from it
+                        let teams: FnvHashSet<Option<u8>> = net_streams
+                            .iter()
+                            .filter(|net_stream| net_stream.player_id.is_some())
+                            .map(team_of)
+                            .chain(Some(None))
+                            .collect();
+                        encoders.retain(|team, _| teams.contains(team));
+                        for team in teams {
+                            if !encoders.contains_key(&team) {
+                                let encoder = encoders[&None].fork(team);
+                                encoders.insert(team, encoder);
+                            }
+                        }
+                        let mut messages = FnvHashMap::default();
+                        info.message_bytes = 0;
+                        info.compressed_bytes = 0;
+                        for (team, encoder) in encoders.iter_mut() {
+                            let message = encoder.encode(&data);
+                            let message_bytes = bincode::serialize(&message).unwrap();
+                            let bytes = compress(&message_bytes);
+                            info.message_bytes += message_bytes.len();
+                            info.compressed_bytes += bytes.len();
+                            messages.insert(*team, (message, bytes));
+                        }
+                        info.encode_time = start.elapsed();
+                        bytes_this_second += info.compressed_bytes;
+                        log::debug!(
+                            "Frame {} bytes, compressed to {} bytes in {:?}",
+                            info.message_bytes,
+                            info.compressed_bytes,
+                            info.encode_time
+                        );
+
+                        //Clients that just connected start from a keyframe
+                        let mut keyframe_bytes = FnvHashMap::default();
+                        for net_stream in net_streams
+                            .iter_mut()
+                            .filter(|net_stream| net_stream.player_id.is_some())
+                        {
+                            let team = team_of(net_stream);
+                            let (message, bytes) = &messages[&team];
+                            if net_stream.synced {
+                                net_stream.send_data_to_compute_next_frame(bytes.clone());
+                            } else {
+                                let encoder = encoders.get_mut(&team).unwrap();
+                                let keyframe_bytes =
+                                    keyframe_bytes.entry(team).or_insert_with(|| {
+                                        compress(
+                                            &bincode::serialize(&encoder.keyframe(&data, message))
+                                                .unwrap(),
+                                        )
+                                    });
+                                net_stream.send_data_to_compute_next_frame(keyframe_bytes.clone());
+                                net_stream.synced = true;
+                            }
                         }
                     }
                     _ => {}
@@ -105,33 +287,157 @@ impl NetServer {
                     player_inputs.extend(net_stream.collect_remote_player_input());
                 }
 
+                //Clients of a started game say who they are first: a Rejoin takes a slot back,
+                //anything else joins as spectator
+                for i in 0..net_streams.len() {
+                    if net_streams[i].player_id.is_some() {
+                        continue;
+                    }
+                    if let Some((player_id, token)) = net_streams[i].rejoin.take() {
+                        let taken = net_streams
+                            .iter()
+                            .any(|net_stream| net_stream.player_id == Some(player_id));
+                        let slot = lobby
+                            .slots
+                            .iter()
+                            .find(|slot| slot.player_id == player_id && !slot.host);
+                        let spectator = slot.is_some_and(|slot| slot.spectator);
+                        let trusted = slot.is_some() && tokens.get(&player_id) == Some(&token);
+                        if trusted && !taken {
+                            log::info!("Client rejoined as {:?}", player_id);
+                            away.remove(&player_id);
+                            lobby.set_connected(player_id, true);
+                            //Issued again, with tokens shared with the peers that came meanwhile
+                            let token = rand::random();
+                            tokens.insert(player_id, token);
+                            net_streams[i].spectator = spectator;
+                            net_streams[i].assign(player_id, token);
+                            share_peer_tokens(net_streams, i);
+                            lobby_changed = true;
+                        } else {
+                            let net_stream = &mut net_streams[i];
+                            log::info!("Client turned away, {:?} can't rejoin", player_id);
+                            net_stream.kill();
+                            net_stream.disconnected = true;
+                        }
+                    } else if !net_streams[i].lobby_changes.is_empty() {
+                        let net_stream = &mut net_streams[i];
+                        let name = net_stream
+                            .lobby_changes
+                            .iter()
+                            .find_map(|change| match change {
+                                LobbyChange::Name(name) => Some(name.clone()),
+                                _ => None,
+                            })
+                            .unwrap_or_else(|| format!("Spectator {}", lobby.slots.len() + 1));
+                        let address = format!("{}:{}", net_stream.addr.ip(), port);
+                        let player_id = lobby.join_spectator(name, address);
+                        log::info!("Client joined the game as spectator {:?}", player_id);
+                        let token = rand::random();
+                        tokens.insert(player_id, token);
+                        net_stream.spectator = true;
+                        net_stream.assign(player_id, token);
+                        share_peer_tokens(net_streams, i);
+                        lobby_changed = true;
+                    }
+                }
+
+                for net_stream in net_streams.iter_mut() {
+                    if let Some(player_id) = net_stream.player_id {
+                        for change in net_stream.lobby_changes.drain(..) {
+                            lobby_changed |= lobby.apply(player_id, change);
+                        }
+                        if net_stream.disconnected {
+                            //Units stay in the game, the slot is kept a while for a rejoin
+                            if lobby.started {
+                                log::info!("{:?} is away", player_id);
+                                lobby.set_connected(player_id, false);
+                                away.insert(player_id, Instant::now());
+                            } else {
+                                lobby.leave(player_id);
+                            }
+                            lobby_changed = true;
+                        }
+                    }
+                }
+
+                away.retain(|player_id, since| {
+                    if since.elapsed() < GRACE_PERIOD {
+                        return true;
+                    }
+                    log::info!("{:?} didn't come back, giving its slot up", player_id);
+                    lobby.leave(*player_id);
+                    lobby_changed = true;
+                    false
+                });
+
                 let _ = s.try_send(FromNetServerInner::PlayerInputs(player_inputs));
+                net_streams.retain(|net_stream| !net_stream.disconnected);
+
+                if lobby_changed {
+                    lobby_changed = false;
+                    for net_stream in net_streams.iter_mut() {
+                        net_stream.send(NetMessage::Lobby(lobby.clone()));
+                    }
+                    let _ = s.try_send(FromNetServerInner::Lobby(lobby.clone()));
+                }
 
                 //Info update
+                if second_start.elapsed() >= std::time::Duration::from_secs(1) {
+                    info.bytes_per_sec = bytes_this_second;
+                    bytes_this_second = 0;
+                    second_start = std::time::Instant::now();
+                }
                 info.number_of_client_connected = net_streams.len();
-                s_info.try_send(info).unwrap();
+                let _ = s_info.try_send(info);
             }
         });
         NetServer {
             s_inner: s_to,
             r_inner: r_from,
-            info: NetServerInfo {
-                bind_state: BindState::Unknown,
-                number_of_client_connected: 0,
-            },
+            info: NetServerInfo::new(),
             r_info,
+            s_kill,
+            player_id,
+            player_inputs: Vec::new(),
+            lobby: None,
         }
     }
-    pub fn kill(&mut self) {}
 
-    pub fn collect_remote_players_inputs(&mut self) -> Vec<FrameEventFromPlayer> {
-        let mut pis = Vec::new();
+    ///Says goodbye to every client and stops listening
+    pub fn kill(&mut self) {
+        let _ = self.s_kill.try_send(());
+    }
+
+    fn receive(&mut self) {
         for msg in self.r_inner.try_iter() {
             match msg {
-                FromNetServerInner::PlayerInputs(player_inputs) => pis.extend(player_inputs),
+                FromNetServerInner::PlayerInputs(player_inputs) => {
+                    self.player_inputs.extend(player_inputs)
+                }
+                FromNetServerInner::Lobby(lobby) => self.lobby = Some(lobby),
             }
         }
-        pis
+    }
+
+    pub fn collect_remote_players_inputs(&mut self) -> Vec<FrameEventFromPlayer> {
+        self.receive();
+        std::mem::take(&mut self.player_inputs)
+    }
+
+    ///The lobby, when it changed since the last call
+    pub fn collect_lobby(&mut self) -> Option<Lobby> {
+        self.receive();
+        self.lobby.take()
+    }
+
+    pub fn change_lobby(&mut self, change: LobbyChange) {
+        let _ = self.s_inner.try_send(ToNetServerInner::LobbyChange(change));
+    }
+
+    ///Only once every player is ready
+    pub fn start_game(&mut self) {
+        let _ = self.s_inner.try_send(ToNetServerInner::StartGame);
     }
 
     pub fn broadcast_data_to_compute_next_frame(&mut self, data: DataToComputeNextFrame) {
@@ -150,74 +456,188 @@ impl NetServer {
 }
 
 enum ToNetStream {
-    DataToComputeNextFrame(Vec<u8>),
+    Message(NetMessage),
 }
 
 enum FromNetStream {
     PlayerInput(Vec<FrameEventFromPlayer>),
+    LobbyChange(LobbyChange),
+    Rejoin(Id<Player>, u64),
+    Disconnected,
 }
 
 struct NetStream {
     r: Receiver<FromNetStream>,
     s: Sender<ToNetStream>,
+    s_kill: Sender<()>,
+    ///Received a keyframe, deltas can follow
+    synced: bool,
+    disconnected: bool,
+    ///Slot in the lobby, the only player this client may give orders for
+    player_id: Option<Id<Player>>,
+    lobby_changes: Vec<LobbyChange>,
+    ///Slot claimed back with its token, granted by the server thread
+    rejoin: Option<(Id<Player>, u64)>,
+    ///Orders of spectators are dropped
+    spectator: bool,
+    addr: SocketAddr,
 }
 
 impl NetStream {
-    fn new(stream: TcpStream) -> Self {
+    fn new(framed: FramedStream, addr: SocketAddr) -> Self {
         let (s_to, r_to) = unbounded::<ToNetStream>();
         let (s_from, r_from) = unbounded::<FromNetStream>();
+        let (s_kill, r_kill) = unbounded::<()>();
 
         std::thread::spawn(move || {
-            let mut stream = stream;
-            let _ = stream.set_read_timeout(Some(std::time::Duration::from_millis(2)));
-            let _ = stream.set_nodelay(true);
+            let mut framed = framed;
             let r = r_to;
             let s = s_from;
             let mut loop_helper = LoopHelper::builder().build_with_target_rate(100.0_f64);
             loop {
                 loop_helper.loop_sleep();
                 loop_helper.loop_start();
+                if let Ok(()) | Err(TryRecvError::Disconnected) = r_kill.try_recv() {
+                    framed.close();
+                    break;
+                }
+
                 log::trace!("read");
-                let result_bincode: bincode::Result<Vec<FrameEventFromPlayer>> =
-                    bincode::deserialize_from(&mut stream);
-                match result_bincode {
-                    Ok(player_inputs) => {
-                        log::trace!(
-                            "   Receive player_inputs ({}) from remote client",
-                            player_inputs.len()
-                        );
-                        let _ = s.try_send(FromNetStream::PlayerInput(player_inputs));
+                match framed.receive() {
+                    Ok(messages) => {
+                        let mut goodbye = false;
+                        for message in messages {
+                            match message {
+                                NetMessage::PlayerInputs(player_inputs) => {
+                                    log::trace!(
+                                        "   Receive player_inputs ({}) from remote client",
+                                        player_inputs.len()
+                                    );
+                                    let _ = s.try_send(FromNetStream::PlayerInput(player_inputs));
+                                }
+                                NetMessage::LobbyChange(change) => {
+                                    let _ = s.try_send(FromNetStream::LobbyChange(change));
+                                }
+                                NetMessage::Rejoin { player_id, token } => {
+                                    let _ = s.try_send(FromNetStream::Rejoin(player_id, token));
+                                }
+                                NetMessage::Goodbye => goodbye = true,
+                                _ => log::warn!("Unexpected message from remote client"),
+                            }
+                        }
+                        if goodbye {
+                            log::info!("Client left");
+                            break;
+                        }
                     }
-                    x => {
-                        log::trace!("   Error read {:?}", x);
+                    Err(e) => {
+                        log::info!("Client lost: {}", e);
+                        break;
                     }
                 }
 
-                //Send last frame to remote player
-                if !r.is_empty() {
-                    match r.try_iter().last().unwrap() {
-                        ToNetStream::DataToComputeNextFrame(data) => {
-                            log::debug!("Send frame to remote player ({} bytes)", data.len());
-                            let _ = stream.write_all(&data).unwrap();
-                            stream.flush().unwrap();
+                //Send every frame to remote player, each delta needs the previous one
+                let mut sent = Ok(());
+                for msg in r.try_iter() {
+                    match msg {
+                        ToNetStream::Message(message) => {
+                            if let NetMessage::Frame(data) = &message {
+                                log::debug!("Send frame to remote player ({} bytes)", data.len());
+                            }
+                            sent = sent.and_then(|_| framed.send(&message));
                         }
                     }
                 }
+                if let Err(e) = sent.and_then(|_| framed.keep_alive()) {
+                    log::info!("Client lost: {}", e);
+                    break;
+                }
             }
+            let _ = s.send(FromNetStream::Disconnected);
         });
-        NetStream { s: s_to, r: r_from }
+        NetStream {
+            s: s_to,
+            r: r_from,
+            s_kill,
+            synced: false,
+            disconnected: false,
+            player_id: None,
+            lobby_changes: Vec::new(),
+            rejoin: None,
+            spectator: false,
+            addr,
+        }
     }
 
+    ///Orders given for other players than the one of this client are dropped, all of them for spectators
     pub fn collect_remote_player_input(&mut self) -> Vec<FrameEventFromPlayer> {
         let mut pis = Vec::new();
         for msg in self.r.try_iter() {
             match msg {
-                FromNetStream::PlayerInput(player_inputs) => pis.extend(player_inputs),
+                FromNetStream::PlayerInput(player_inputs) => {
+                    let player_id = self.player_id;
+                    if !self.spectator {
+                        pis.extend(
+                            player_inputs.into_iter().filter(|event| {
+                                player_id.is_some() && event.player_id() == player_id
+                            }),
+                        )
+                    }
+                }
+                FromNetStream::LobbyChange(change) => self.lobby_changes.push(change),
+                FromNetStream::Rejoin(player_id, token) => self.rejoin = Some((player_id, token)),
+                FromNetStream::Disconnected => self.disconnected = true,
             }
         }
         pis
     }
+
+    fn assign(&mut self, player_id: Id<Player>, token: u64) {
+        self.player_id = Some(player_id);
+        self.send(NetMessage::Assign { player_id, token });
+    }
+
+    fn kill(&mut self) {
+        let _ = self.s_kill.try_send(());
+    }
+
     pub fn send_data_to_compute_next_frame(&mut self, data: Vec<u8>) {
-        let _ = self.s.try_send(ToNetStream::DataToComputeNextFrame(data));
+        self.send(NetMessage::Frame(data));
+    }
+
+    fn send(&mut self, message: NetMessage) {
+        let _ = self.s.try_send(ToNetStream::Message(message));
     }
 }
+
+///The client of net_streams[index] and each other client get a pair of tokens only they know,
+///to rejoin one another if one of them hosts the game
+fn share_peer_tokens(net_streams: &mut [NetStream], index: usize) {
+    let player_id = match net_streams[index].player_id {
+        Some(player_id) => player_id,
+        None => return,
+    };
+    for i in (0..net_streams.len()).filter(|&i| i != index) {
+        if let Some(peer_id) = net_streams[i].player_id {
+            let token = PeerToken {
+                present: rand::random(),
+                accept: rand::random(),
+            };
+            net_streams[index].send(NetMessage::PeerToken {
+                player_id: peer_id,
+                token,
+            });
+            net_streams[i].send(NetMessage::PeerToken {
+                player_id,
+                token: PeerToken {
+                    present: token.accept,
+                    accept: token.present,
+                },
+            });
+        }
+    }
+}
+
+fn compress(message_bytes: &[u8]) -> Vec<u8> {
+    frame_delta::compress(message_bytes).unwrap()
+}
@@ -0,0 +1,253 @@
+use crate::frame::{DataToComputeNextFrame, Frame, FrameEventFromPlayer};
+use crate::frame_server::FrameServerCache;
+use crate::heightmap_phy::HeightmapPhy;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+pub const REPLAY_DIR: &str = "replays";
+///Ticks between two full frames in a replay file
+const KEYFRAME_INTERVAL: usize = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+enum ReplayRecord {
+    ///Frame before the events of the following Tick
+    Keyframe(Box<Frame>),
+    Tick(Vec<FrameEventFromPlayer>),
+}
+
+///Writes the events of each tick, the first one holding the ReplaceFrame that starts the game
+pub struct ReplayRecorder {
+    encoder: ZlibEncoder<BufWriter<File>>,
+    tick: usize,
+}
+
+impl ReplayRecorder {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = File::create(path)?;
+        log::info!("Recording replay to {}", path);
+        Ok(ReplayRecorder {
+            encoder: ZlibEncoder::new(BufWriter::new(file), Compression::default()),
+            tick: 0,
+        })
+    }
+
+    ///Path of a new replay file named after the current time
+    pub fn new_path() -> String {
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        format!("{}/{}.replay", REPLAY_DIR, secs)
+    }
+
+    pub fn record(&mut self, data: &DataToComputeNextFrame) -> bincode::Result<()> {
+        if self.tick > 0 && self.tick.is_multiple_of(KEYFRAME_INTERVAL) {
+            bincode::serialize_into(
+                &mut self.encoder,
+                &ReplayRecord::Keyframe(Box::new(data.old_frame.clone())),
+            )?;
+        }
+        bincode::serialize_into(&mut self.encoder, &ReplayRecord::Tick(data.events.clone()))?;
+        self.tick += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> std::io::Result<()> {
+        self.encoder.finish()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayInfo {
+    pub tick: usize,
+    pub tick_count: usize,
+    pub paused: bool,
+    pub speed: f64,
+}
+
+///Gives the manager the events of a recorded game, tick after tick
+pub struct ReplayPlayer {
+    ticks: Vec<Vec<FrameEventFromPlayer>>,
+    keyframes: Vec<(usize, Frame)>,
+    ///Keyframes don't hold the heightmap, only the first ReplaceFrame does
+    heightmap: Option<HeightmapPhy>,
+    ///Next tick to play
+    pub tick: usize,
+    pub paused: bool,
+    pub speed: f64,
+}
+
+impl ReplayPlayer {
+    pub fn load(path: &str) -> bincode::Result<Self> {
+        let file = File::open(path)?;
+        let mut decoder = ZlibDecoder::new(BufReader::new(file));
+
+        let mut ticks = Vec::new();
+        let mut keyframes = Vec::new();
+        //Reads until the end of the stream, a game still being recorded may end abruptly
+        loop {
+            match bincode::deserialize_from(&mut decoder) {
+                Ok(ReplayRecord::Keyframe(frame)) => keyframes.push((ticks.len(), *frame)),
+                Ok(ReplayRecord::Tick(events)) => ticks.push(events),
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref io)
+                        if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+                    {
+                        break
+                    }
+                    //Compressed stream cut by a crash while recording
+                    bincode::ErrorKind::Io(_) if !ticks.is_empty() => {
+                        log::warn!("Replay {} cut after {} ticks: {}", path, ticks.len(), e);
+                        break;
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+        if ticks.is_empty() {
+            return Err(Box::new(bincode::ErrorKind::Custom(
+                "no tick recorded".to_owned(),
+            )));
+        }
+
+        let heightmap = ticks.iter().flatten().find_map(|event| match event {
+            FrameEventFromPlayer::ReplaceFrame(frame) => frame.heightmap_phy.clone(),
+            _ => None,
+        });
+
+        log::info!(
+            "Loaded replay {} with {} ticks and {} keyframes",
+            path,
+            ticks.len(),
+            keyframes.len()
+        );
+        Ok(ReplayPlayer {
+            ticks,
+            keyframes,
+            heightmap,
+            tick: 0,
+            paused: false,
+            speed: 1.0,
+        })
+    }
+
+    pub fn info(&self) -> ReplayInfo {
+        ReplayInfo {
+            tick: self.tick,
+            tick_count: self.ticks.len(),
+            paused: self.paused,
+            speed: self.speed,
+        }
+    }
+
+    ///Events of the next tick, None once the replay is over
+    pub fn next_events(&mut self) -> Option<Vec<FrameEventFromPlayer>> {
+        let events = self.ticks.get(self.tick).cloned();
+        if events.is_some() {
+            self.tick += 1;
+        }
+        events
+    }
+
+    ///Events that make the frame server jump to the frame after target
+    pub fn seek(&mut self, target: usize) -> Vec<FrameEventFromPlayer> {
+        if self.ticks.is_empty() {
+            return Vec::new();
+        }
+        let target = target.min(self.ticks.len() - 1);
+
+        let keyframe = self
+            .keyframes
+            .iter()
+            .rev()
+            .find(|(tick, _)| *tick <= target);
+        let (mut tick, mut events) = match keyframe {
+            Some((tick, frame)) => (*tick, self.replace_events(frame, *tick)),
+            None => (0, self.ticks[0].clone()),
+        };
+
+        //Catch up from the keyframe with a frame server of our own
+        let mut cache = FrameServerCache::new();
+        let mut frame = Frame::new();
+        let caught_up = tick < target;
+        while tick < target {
+            frame = cache.next_frame(frame, events);
+            tick += 1;
+            events = self.ticks[tick].clone();
+        }
+        if caught_up {
+            events = self.replace_events(&frame, target);
+        }
+
+        self.tick = target + 1;
+        events
+    }
+
+    fn replace_events(&self, frame: &Frame, tick: usize) -> Vec<FrameEventFromPlayer> {
+        let mut frame = frame.clone();
+        frame.heightmap_phy = self.heightmap.clone();
+        std::iter::once(FrameEventFromPlayer::ReplaceFrame(frame))
+            .chain(
+                self.ticks[tick]
+                    .iter()
+                    .filter(|event| !matches!(event, FrameEventFromPlayer::ReplaceFrame(_)))
+                    .cloned(),
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str, ticks: usize) {
+        let mut recorder = ReplayRecorder::create(path).unwrap();
+        for _ in 0..ticks {
+            let data = DataToComputeNextFrame {
+                old_frame: Frame::new(),
+                events: Vec::new(),
+            };
+            recorder.record(&data).unwrap();
+        }
+        recorder.finish().unwrap();
+    }
+
+    #[test]
+    fn only_the_end_of_the_file_ends_a_replay() {
+        let path =
+            std::env::temp_dir().join(format!("oxidator_test_{}.replay", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        record(path, 250);
+        let player = ReplayPlayer::load(path).unwrap();
+        assert_eq!((player.ticks.len(), player.keyframes.len()), (250, 2));
+
+        //Cut while recording
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() * 3 / 4]).unwrap();
+        let player = ReplayPlayer::load(path).unwrap();
+        assert!(player.ticks.len() > 0 && player.ticks.len() < 250);
+
+        std::fs::write(path, b"not a replay").unwrap();
+        assert!(ReplayPlayer::load(path).is_err());
+
+        //A record that isn't one in the middle of the stream
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        bincode::serialize_into(&mut encoder, &ReplayRecord::Tick(Vec::new())).unwrap();
+        std::io::Write::write_all(&mut encoder, &[7, 0, 0, 0]).unwrap();
+        bincode::serialize_into(&mut encoder, &ReplayRecord::Tick(Vec::new())).unwrap();
+        std::fs::write(path, encoder.finish().unwrap()).unwrap();
+        assert!(ReplayPlayer::load(path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -25,12 +25,12 @@ layout(set = 0, binding = 0) uniform Locals {
     float pen_radius;
     float pen_strength;
     vec2 hmap_size;
+    float water_level;
 };
 
 int max_step = 40;
 
 void main() {
-    float water_level = 40;
     vec3 world_pos = vec3(v_TexCoord*hmap_size,water_level);
     vec4 view_pos4 = u_View* vec4(world_pos,1.0);
     vec3 view_pos  = view_pos4.xyz/ view_pos4.w;
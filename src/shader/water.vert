@@ -15,6 +15,7 @@ layout(set = 0, binding = 0) uniform Locals {
     float pen_radius;
     float pen_strength;
     vec2 hmap_size;
+    float water_level;
 };
 
 void main() {
@@ -30,7 +31,6 @@ void main() {
     v_TexCoord = tc;
 
     v_floor_lwall_fwall_rwall = gl_InstanceIndex;
-    float water_level = 40; 
     vec3 pos = vec3(0); 
  
     switch(v_floor_lwall_fwall_rwall){
@@ -109,6 +109,33 @@ pub fn rand_id<T>() -> Id<T> {
     Id::new(rand::prelude::random())
 }
 
+///Gives consecutive ids from a random start, so that the frame server is deterministic
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct IdGenerator {
+    next: IdValue,
+}
+
+impl IdGenerator {
+    pub fn new() -> Self {
+        IdGenerator {
+            next: rand::prelude::random(),
+        }
+    }
+
+    pub fn next_id<T>(&mut self) -> Id<T> {
+        let id = Id::new(self.next);
+        self.next = self.next.wrapping_add(1);
+        id
+    }
+}
+
+///Keys of a map sorted by value, to iterate in the same order on every machine
+pub fn sorted_ids<T, V, S>(map: &HashMap<Id<T>, V, S>) -> Vec<Id<T>> {
+    let mut ids: Vec<Id<T>> = map.keys().copied().collect();
+    ids.sort_by_key(|id| id.value);
+    ids
+}
+
 pub fn rand_id_unsafe() -> String {
     let mut rng = thread_rng();
     let mut s = String::with_capacity(ID_SIZE);
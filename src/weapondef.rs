@@ -0,0 +1,145 @@
+use fnv::FnvHashMap;
+use serde::{Deserialize, Serialize};
+
+///How a weapon picks its target among the ennemies in range
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum TargetPriority {
+    #[default]
+    Closest,
+    LowestHealth,
+    HighestMetalCost,
+    ///Units of this BotDef category first, then the closest
+    Category(String),
+    ///Keep the current target until it dies or leaves the range, then the closest
+    KeepCurrent,
+}
+
+///Where a unit is, weapons aim at some of them only
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TargetLayer {
+    ///On the ground or the water
+    Ground,
+    ///Flying
+    Air,
+    ///Under the water
+    Underwater,
+}
+
+///Weapon mounted on the Joint::AimWeapon0 part of a unit
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct WeaponDef {
+    ///m
+    pub range: f32,
+    ///frame
+    pub reload_frame_count: i32,
+    ///life point
+    pub damage: i32,
+    ///m/frame, horizontal
+    pub projectile_speed: f32,
+    ///m/frame², downward, 0 for a straight shot
+    pub projectile_gravity: f32,
+    ///m
+    pub projectile_radius: f32,
+    ///m, damage falls off linearly to 0 at this distance from the impact
+    pub area_of_effect: f32,
+    ///Damage multiplier per BotDef::armor_class, 1 when missing
+    #[serde(default)]
+    pub armor_multipliers: FnvHashMap<String, f32>,
+    ///Damage units of the shooter's team
+    #[serde(default)]
+    pub friendly_fire: bool,
+    ///rad/frame
+    pub turret_turn_rate: f32,
+    ///rad, half angle around the unit direction where the turret can aim
+    pub firing_arc: f32,
+    #[serde(default)]
+    pub target_priority: TargetPriority,
+    ///Aims at units on the ground or the water, and at the ground
+    #[serde(default = "default_true")]
+    pub can_target_ground: bool,
+    ///Aims at flying units
+    #[serde(default)]
+    pub can_target_air: bool,
+    ///Aims at units under the water, its projectiles go through the water surface
+    #[serde(default)]
+    pub can_target_underwater: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl WeaponDef {
+    ///Weapondefs the frame server can't simulate
+    pub fn check(&self) -> Result<(), String> {
+        if self.projectile_speed > 0.0 {
+            Ok(())
+        } else {
+            Err(format!(
+                "projectile_speed must be positive, not {}",
+                self.projectile_speed
+            ))
+        }
+    }
+
+    pub fn armor_multiplier(&self, armor_class: &str) -> f32 {
+        self.armor_multipliers
+            .get(armor_class)
+            .cloned()
+            .unwrap_or(1.0)
+    }
+
+    pub fn can_target(&self, layer: TargetLayer) -> bool {
+        match layer {
+            TargetLayer::Ground => self.can_target_ground,
+            TargetLayer::Air => self.can_target_air,
+            TargetLayer::Underwater => self.can_target_underwater,
+        }
+    }
+
+    ///Launch speed to hit target_delta from the muzzle, and the number of frames to get there
+    pub fn launch_speed(&self, target_delta: na::Vector3<f32>) -> (na::Vector3<f32>, f32) {
+        let horizontal = target_delta.xy();
+        let distance = horizontal.norm();
+        let dir = if distance > 0.0 {
+            horizontal / distance
+        } else {
+            na::Vector2::new(1.0, 0.0)
+        };
+
+        //Position after n frames is n*vz + gravity*n*(n+1)/2
+        let n = if self.projectile_speed > 0.0 {
+            (distance / self.projectile_speed).max(1.0)
+        } else {
+            1.0
+        };
+        let vz = (target_delta.z + self.projectile_gravity * n * (n + 1.0) / 2.0) / n;
+
+        (
+            na::Vector3::new(
+                dir.x * self.projectile_speed,
+                dir.y * self.projectile_speed,
+                vz,
+            ),
+            n,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::botdef::BotDef;
+
+    #[test]
+    fn still_projectiles_are_rejected_and_never_nan() {
+        let path = "src/asset/botdef/unit_example.json";
+        let botdef: BotDef = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let mut weapon = botdef.weapon0.unwrap();
+        assert!(weapon.check().is_ok());
+
+        weapon.projectile_speed = 0.0;
+        assert!(weapon.check().is_err());
+        let (speed, frames) = weapon.launch_speed(na::Vector3::new(30.0, 40.0, 5.0));
+        assert!(speed.iter().all(|x| x.is_finite()) && frames.is_finite());
+    }
+}